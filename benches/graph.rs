@@ -0,0 +1,76 @@
+//! Building the directory tree sequentially and on pools of growing size.
+//!
+//! Run with `cargo bench --bench graph`. Only machines with several cores can
+//! tell how the parallel build scales, one core just shows what it costs.
+
+extern crate chrono;
+use chrono::Utc;
+
+extern crate criterion;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+extern crate num_cpus;
+
+extern crate rusty_index;
+use rusty_index::analyser;
+use rusty_index::{FileRecord, GraphStorageInterface};
+
+
+/// A volume of `top` top level directories, each holding 20 directories of
+/// 10 sub-directories of 10 files, a third of them duplicated.
+fn records(top: usize) -> Vec<FileRecord> {
+    let modified = Utc::now();
+    let mut res = Vec::new();
+    for a in 0..top {
+        for b in 0..20 {
+            for c in 0..10 {
+                for f in 0..10 {
+                    let unique = (a * 20 + b) * 100 + c * 10 + f;
+                    res.push(FileRecord {
+                        id: unique as u32,
+                        checksum: format!("{:032x}", if f % 3 == 0 { f } else { unique }),
+                        name: format!("file-{}", f),
+                        path: vec![String::from("nas"), format!("top-{}", a), format!("dir-{}", b), format!("sub-{}", c)],
+                        modified,
+                    });
+                }
+            }
+        }
+    }
+
+    res
+}
+
+
+fn build(c: &mut Criterion) {
+    let input = records(50);
+    let mut group = c.benchmark_group("build 100k records");
+    group.sample_size(10);
+
+    group.bench_function("bulk_insert", |b| b.iter_batched(
+        || input.clone(),
+        |records| {
+            let mut graph = analyser::initialise_graph();
+            graph.bulk_insert(records).unwrap();
+            graph
+        },
+        BatchSize::LargeInput,
+    ));
+
+    let cores = num_cpus::get();
+    let mut threads = 1;
+    while threads <= cores {
+        group.bench_with_input(BenchmarkId::new("parallel_bulk_insert", threads), &threads, |b, &threads| b.iter_batched(
+            || input.clone(),
+            |records| analyser::parallel_bulk_insert(records, threads).unwrap(),
+            BatchSize::LargeInput,
+        ));
+        threads *= 2;
+    }
+
+    group.finish();
+}
+
+
+criterion_group!(benches, build);
+criterion_main!(benches);
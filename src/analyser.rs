@@ -1,22 +1,39 @@
+//! Directory tree built out of file records, with a content checksum for every
+//! directory, and duplicate detection over it.
+
 use std::fmt;
 use std::collections::VecDeque;
 use std::collections::HashMap;
-use std::thread;
-use std::sync::{Arc, Mutex};
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::mpsc::channel;
 
 extern crate chrono;
 use chrono::{DateTime, Utc};
 
 extern crate petgraph;
-use petgraph::graph::{Graph, NodeIndex};
+use petgraph::graph::NodeIndex;
+use petgraph::stable_graph::StableGraph;
+
+extern crate num_cpus;
+
+extern crate threadpool;
+use threadpool::ThreadPool;
 
 extern crate crypto;
 use crypto::md5::Md5;
 use crypto::digest::Digest;
 
+use crate::error::{Error, Result};
+use crate::index_db::{IndexRecord, IndexStorage, StoredNode};
+use crate::misc;
+
 
 #[derive(Debug, Clone)]
 pub struct FileRecord {
+    /// Row of the record in the index, 0 when it has none.
+    pub id: u32,
     pub checksum: String,
     pub name: String,
     pub path: Vec<String>,
@@ -24,13 +41,6 @@ pub struct FileRecord {
 }
 
 
-#[derive(Debug)]
-struct EdgeInfo {
-    node: NodeIndex,
-    tag: String,
-}
-
-
 #[derive(Debug)]
 pub enum GNode{
     DirNode {
@@ -45,11 +55,34 @@ pub enum GNode{
 }
 
 
+impl GNode {
+    pub fn name(&self) -> &str {
+        match self {
+            GNode::DirNode {name, ..} | GNode::FileLeaf {name, ..} => name,
+        }
+    }
+
+    pub fn checksum(&self) -> &str {
+        match self {
+            GNode::DirNode {checksum, ..} | GNode::FileLeaf {checksum, ..} => checksum,
+        }
+    }
+
+    /// Row in the index of the record a file stands for, if known.
+    pub fn record_id(&self) -> Option<u32> {
+        match self {
+            GNode::FileLeaf {id, ..} if *id != 0 => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+
 impl fmt::Display for GNode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            GNode::FileLeaf {name, checksum: _, id: __} => write!(f, "File-{}", String::from(name)),
-            GNode::DirNode {name, checksum: _}  => write!(f, "Directory-{}", String::from(name)),
+            GNode::FileLeaf {name, ..} => write!(f, "File-{}", name),
+            GNode::DirNode {name, ..}  => write!(f, "Directory-{}", name),
         }
     }
 }
@@ -57,13 +90,27 @@ impl fmt::Display for GNode {
 
 #[derive(Debug)]
 pub struct GraphStorage {
-    pub graph: Graph::<GNode, ()>,
+    pub graph: StableGraph::<GNode, ()>,
     pub root: NodeIndex,
+    /// Directories of a stored tree whose entries are not loaded yet, with
+    /// their id in the index, see [`GraphStorage::expand`].
+    pub unloaded: HashMap<NodeIndex, i64>,
+    /// Entries of every directory by name, so that paths resolve in constant
+    /// time per component. Nodes are to be added through the tree's methods
+    /// only, leaving `graph` alone.
+    entries: HashMap<NodeIndex, Entries>,
+}
+
+
+#[derive(Debug, Default)]
+struct Entries {
+    dirs: HashMap<String, NodeIndex>,
+    files: HashMap<String, NodeIndex>,
 }
 
 
 pub fn initialise_graph() -> GraphStorage {
-    let mut new_graph = Graph::<GNode, ()>::new();
+    let mut new_graph = StableGraph::<GNode, ()>::new();
     let root_index = new_graph.add_node(GNode::DirNode{
         name: String::from("root"),
         checksum: String::from(""),
@@ -72,29 +119,228 @@ pub fn initialise_graph() -> GraphStorage {
     GraphStorage {
         graph: new_graph,
         root: root_index,
+        unloaded: HashMap::new(),
+        entries: HashMap::new(),
+    }
+}
+
+
+/// Opens the directory tree stored in the index, building and saving it
+/// first if there is none. Only the root is loaded, the rest of the tree
+/// comes in as directories are expanded.
+pub fn open_graph<T: IndexStorage>(storage: &T) -> Result<GraphStorage> {
+    let root = match storage.graph_root()? {
+        Some(root) => root,
+        None => {
+            let records = storage.fetch_sorted()?.iter().map(misc::to_file_record).collect();
+            let graph = parallel_bulk_insert(records, num_cpus::get())?;
+            storage.save_graph(&graph.to_stored())?;
+
+            return Ok(graph);
+        },
+    };
+
+    let mut graph = StableGraph::<GNode, ()>::new();
+    let root_index = graph.add_node(GNode::DirNode {
+        name: root.name,
+        checksum: root.checksum,
+    });
+
+    Ok(GraphStorage {
+        graph,
+        root: root_index,
+        unloaded: HashMap::from([(root_index, root.id)]),
+        entries: HashMap::new(),
+    })
+}
+
+
+/// Opens the directory tree stored in the index as a whole, see [`open_graph`].
+pub fn load_graph<T: IndexStorage>(storage: &T) -> Result<GraphStorage> {
+    let mut graph = open_graph(storage)?;
+    graph.expand_all(storage)?;
+
+    Ok(graph)
+}
+
+
+impl GraphStorage {
+    /// Loads the entries of the directory `node` from `storage`, unless they
+    /// already are.
+    pub fn expand<T: IndexStorage>(&mut self, storage: &T, node: NodeIndex) -> Result<()> {
+        let id = match self.unloaded.remove(&node) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        for child in storage.graph_children(id)? {
+            let weight = if child.is_dir {
+                GNode::DirNode { name: child.name, checksum: child.checksum }
+            } else {
+                GNode::FileLeaf { name: child.name, checksum: child.checksum, id: child.record_id.unwrap_or(0) }
+            };
+            let child_index = self.add_child(node, weight);
+            if child.is_dir {
+                self.unloaded.insert(child_index, child.id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Node at `path` from the root, the last component naming either a
+    /// directory or a file. Only loaded directories are looked into.
+    pub fn lookup<S: AsRef<str>>(&self, path: &[S]) -> Option<NodeIndex> {
+        let (last, dirs) = match path.split_last() {
+            Some(split) => split,
+            None => return Some(self.root),
+        };
+
+        let mut cursor = self.root;
+        for component in dirs {
+            cursor = self.child_dir(cursor, component.as_ref())?;
+        }
+
+        let last = last.as_ref();
+        self.child_dir(cursor, last).or_else(|| self.child_file(cursor, last))
+    }
+
+    fn child_dir(&self, parent: NodeIndex, name: &str) -> Option<NodeIndex> {
+        self.entries.get(&parent)?.dirs.get(name).copied()
+    }
+
+    fn child_file(&self, parent: NodeIndex, name: &str) -> Option<NodeIndex> {
+        self.entries.get(&parent)?.files.get(name).copied()
+    }
+
+    /// Adds `weight` to the directory `parent`, indexing it by name. An entry
+    /// of the same name and kind already there gets `weight` instead.
+    fn add_child(&mut self, parent: NodeIndex, weight: GNode) -> NodeIndex {
+        let (name, is_dir) = match &weight {
+            GNode::DirNode {name, ..} => (name.clone(), true),
+            GNode::FileLeaf {name, ..} => (name.clone(), false),
+        };
+
+        let known = if is_dir { self.child_dir(parent, &name) } else { self.child_file(parent, &name) };
+        if let Some(node) = known {
+            self.graph[node] = weight;
+            return node;
+        }
+
+        let node = self.graph.add_node(weight);
+        self.graph.add_edge(parent, node, ());
+
+        let entries = self.entries.entry(parent).or_default();
+        if is_dir {
+            entries.dirs.insert(name, node);
+        } else {
+            entries.files.insert(name, node);
+        }
+
+        node
+    }
+
+    /// Removes the entry `node` of the directory `parent`, which must be empty
+    /// if a directory.
+    fn remove_child(&mut self, parent: NodeIndex, node: NodeIndex) {
+        let entries = self.entries.entry(parent).or_default();
+        match self.graph.remove_node(node) {
+            Some(GNode::DirNode {name, ..}) => {
+                entries.dirs.remove(&name);
+                self.entries.remove(&node);
+            },
+            Some(GNode::FileLeaf {name, ..}) => {
+                entries.files.remove(&name);
+            },
+            None => {},
+        }
+    }
+
+    /// Full metadata of the file at `node`, fetched from the index it was built from.
+    pub fn record<T: IndexStorage>(&self, storage: &T, node: NodeIndex) -> Result<Option<IndexRecord>> {
+        match self.graph.node_weight(node).and_then(GNode::record_id) {
+            Some(id) => storage.record(id),
+            None => Ok(None),
+        }
+    }
+
+    /// `node`, provided its entries are loaded.
+    fn loaded(&self, node: NodeIndex) -> Result<NodeIndex> {
+        if self.unloaded.contains_key(&node) {
+            return Err(Error::Graph(format!("the entries of {} are not loaded", self.graph[node])));
+        }
+
+        Ok(node)
+    }
+
+    /// Loads every directory not loaded yet.
+    pub fn expand_all<T: IndexStorage>(&mut self, storage: &T) -> Result<()> {
+        while let Some(&node) = self.unloaded.keys().next() {
+            self.expand(storage, node)?;
+        }
+
+        Ok(())
+    }
+
+    /// The whole tree as rows of the index, parents before their entries.
+    fn to_stored(&self) -> Vec<StoredNode> {
+        let id = |node: NodeIndex| node.index() as i64 + 1;
+        let mut res = Vec::with_capacity(self.graph.node_count());
+        let mut pending = vec![(self.root, None)];
+
+        while let Some((node, parent)) = pending.pop() {
+            let (name, checksum, is_dir) = match &self.graph[node] {
+                GNode::DirNode {name, checksum} => (name, checksum, true),
+                GNode::FileLeaf {name, checksum, ..} => (name, checksum, false),
+            };
+            res.push(StoredNode {
+                id: id(node),
+                parent,
+                name: name.clone(),
+                checksum: checksum.clone(),
+                is_dir,
+                record_id: self.graph[node].record_id(),
+            });
+
+            for child in self.graph.neighbors(node) {
+                pending.push((child, Some(id(node))));
+            }
+        }
+
+        res
     }
 }
 
 
 pub trait GraphStorageInterface {
-    fn _bulk_insert(&mut self, node: &mut NodeIndex, sorted_entries: Vec<FileRecord>);
-    fn bulk_insert(&mut self, sorted_entries: Vec<FileRecord>);
+    fn _bulk_insert(&mut self, node: &mut NodeIndex, sorted_entries: Vec<FileRecord>) -> Result<()>;
+    fn bulk_insert(&mut self, sorted_entries: Vec<FileRecord>) -> Result<()>;
     fn find_duplicates(&self) -> HashMap<String, Vec<String>>;
+    /// The nodes behind [`find_duplicates`](GraphStorageInterface::find_duplicates),
+    /// each with its path components, its own name last.
+    fn duplicate_nodes(&self) -> HashMap<String, Vec<(NodeIndex, Vec<String>)>>;
+    /// Every directory below the root, as its path components and checksum.
+    fn directories(&self) -> Vec<(Vec<String>, String)>;
+    /// Adds the file of `record`, or updates it if already there. Only the
+    /// checksums of the directories above it are computed again, see [`update_files`].
+    fn insert_record(&mut self, record: FileRecord) -> Result<NodeIndex>;
+    /// Removes the file of `record`, along with the directories it leaves
+    /// empty, updating the checksums of the ones above. Returns whether it was there.
+    fn remove_record(&mut self, record: &FileRecord) -> Result<bool>;
 }
 
 
 impl GraphStorageInterface for GraphStorage {
-    fn _bulk_insert(&mut self, node: &mut NodeIndex, sorted_entries: Vec<FileRecord>) {
+    fn _bulk_insert(&mut self, node: &mut NodeIndex, sorted_entries: Vec<FileRecord>) -> Result<()> {
         let mut local_contents = HashMap::<String, Vec<FileRecord>>::new();
 
         for record in sorted_entries {
-            if record.path.len() == 0 {
-                let leaf = self.graph.add_node(GNode::FileLeaf {
-                    name: String::from(record.name),
-                    checksum: String::from(record.checksum),
-                    id: 0,
+            if record.path.is_empty() {
+                self.add_child(*node, GNode::FileLeaf {
+                    name: record.name,
+                    checksum: record.checksum,
+                    id: record.id,
                 });
-                self.graph.add_edge(*node, leaf, ());
 
                 continue;
             }
@@ -106,6 +352,7 @@ impl GraphStorageInterface for GraphStorage {
                     new_path.remove(0);
                     
                     vec.push(FileRecord {
+                        id: record.id,
                         checksum: record.checksum,
                         name: record.name,
                         path: new_path,
@@ -118,6 +365,7 @@ impl GraphStorageInterface for GraphStorage {
                     new_path.remove(0);
                     
                     new_vec.push(FileRecord {
+                        id: record.id,
                         checksum: record.checksum,
                         name: record.name,
                         path: new_path,
@@ -134,257 +382,543 @@ impl GraphStorageInterface for GraphStorage {
         //   and then recusively call parallel_execution with new node and corresponding
         //   file records
         for (key, value) in local_contents {
-            let mut cursor = match is_linked(&self.graph, node, &key) {
+            let mut cursor = match self.child_dir(*node, &key) {
                 Some(res) => res,
-                None => {
-                    let new_node = self.graph.add_node(GNode::DirNode {
-                        name: String::from(key),
-                        checksum: String::from("NA"),
-                    });
-                    self.graph.add_edge(*node, new_node, ());
-                    
-                    new_node
-                },
+                None => self.add_child(*node, GNode::DirNode {
+                    name: key,
+                    checksum: String::from("NA"),
+                }),
             };
 
-            self._bulk_insert(&mut cursor, value);
+            self._bulk_insert(&mut cursor, value)?;
         }
 
         // update current node's hash for all of its contents.
-        let checksum = calculate_hash(&self.graph, node); 
-        let node_data = self.graph.node_weight_mut(*node).unwrap();
-        let node_name = match node_data {
-            GNode::DirNode {name, checksum: _2} => name,
-            GNode::FileLeaf {name: _1, checksum: _2, id: _3} => panic!(
-                "LeafNode cannot be part of the trace. It should be impossible"
-            ),
-        };
-
-        *node_data = GNode::DirNode {
-            name: node_name.to_string(),
-            checksum
-        }
+        update_dir_checksum(&mut self.graph, node)
     }
 
-    fn bulk_insert(&mut self, sorted_entries: Vec<FileRecord>) {
+    fn bulk_insert(&mut self, sorted_entries: Vec<FileRecord>) -> Result<()> {
 	self._bulk_insert(&mut self.root.clone(), sorted_entries)
     }
     
     fn find_duplicates(&self) -> HashMap<String, Vec<String>> {
-	let mut duplicates = HashMap::<String, Vec<String>>::new();
-        let mut edges = VecDeque::<EdgeInfo>::new();
-        edges.push_back(EdgeInfo {
-            node: self.root,
-            tag: String::from(""),
-        });
+        self.duplicate_nodes().into_iter().map(|(checksum, nodes)| {
+            let paths = nodes.into_iter().map(|(node, path)| match self.graph[node] {
+                GNode::DirNode {..} => format!("{}/", path.join("/")),
+                GNode::FileLeaf {..} => path.join("/"),
+            }).collect();
+            (checksum, paths)
+        }).collect()
+    }
 
-        while !edges.is_empty() {
-            let pivot = edges.pop_front().unwrap();
-            
-            for elem in self.graph.neighbors(pivot.node) {
-                match self.graph.node_weight(elem).unwrap() {
-                    
-                    GNode::FileLeaf {name, checksum, id: _} => {
-                        let path = String::from(format!("{}{}", pivot.tag.as_str(), name.as_str()));
-
-                        match duplicates.get_mut(checksum) {
-                            Some(vec) => {vec.push(path)},
-                            None => {
-                                let mut new_vec = Vec::<String>::new();
-                                new_vec.push(path);
-                                duplicates.insert(String::from(checksum.as_str()), new_vec);
-                            },
-                        };
-                    },
-                    
-                    GNode::DirNode {name, checksum} => {
-                        let path = String::from(format!("{}{}/", pivot.tag.as_str(), name.as_str()));
-
-                        match duplicates.get_mut(checksum) {
-                            Some(vec) => {
-                                vec.push(path.clone());
-				
-                                edges.push_back(EdgeInfo {
-                                    node: elem,
-                                    tag: path,
-                                });
-                            },
-                            None => {
-                                let mut new_vec = Vec::<String>::new();
-                                new_vec.push(path.clone());
-                                duplicates.insert(String::from(checksum.as_str()), new_vec);
-
-                                edges.push_back(EdgeInfo {
-                                    node: elem,
-                                    tag: path,
-                                });
-                            },
-                        };
+    fn duplicate_nodes(&self) -> HashMap<String, Vec<(NodeIndex, Vec<String>)>> {
+        let mut duplicates = HashMap::<String, Vec<(NodeIndex, Vec<String>)>>::new();
+        let mut pending = VecDeque::from([(self.root, Vec::<String>::new())]);
+
+        while let Some((pivot, path)) = pending.pop_front() {
+            for elem in self.graph.neighbors(pivot) {
+                let node = &self.graph[elem];
+                let mut sub_path = path.clone();
+                sub_path.push(String::from(node.name()));
+
+                match duplicates.get_mut(node.checksum()) {
+                    // the contents of a duplicated directory are already
+                    // accounted for by the first copy, so don't descend.
+                    Some(vec) => vec.push((elem, sub_path)),
+                    None => {
+                        duplicates.insert(String::from(node.checksum()), vec![(elem, sub_path.clone())]);
+                        if let GNode::DirNode {..} = node {
+                            pending.push_back((elem, sub_path));
+                        }
                     },
                 };
             }
         }
 
-        return duplicates.into_iter().filter(|(_, v)| v.len() > 1).collect();
+        duplicates.into_iter().filter(|(_, v)| v.len() > 1).collect()
+    }
+
+    fn directories(&self) -> Vec<(Vec<String>, String)> {
+        let mut res = Vec::new();
+        let mut pending = vec![(self.root, Vec::<String>::new())];
+
+        while let Some((node, path)) = pending.pop() {
+            for elem in self.graph.neighbors(node) {
+                if let GNode::DirNode {name, checksum} = &self.graph[elem] {
+                    let mut sub_path = path.clone();
+                    sub_path.push(name.clone());
+
+                    res.push((sub_path.clone(), checksum.clone()));
+                    pending.push((elem, sub_path));
+                }
+            }
+        }
+
+        res
+    }
+
+    fn insert_record(&mut self, record: FileRecord) -> Result<NodeIndex> {
+        update_files(self, &[], std::slice::from_ref(&record))?;
+
+        self.lookup(&record.path)
+            .and_then(|parent| self.child_file(parent, &record.name))
+            .ok_or_else(|| Error::Graph(format!("file {} was not added", record.name)))
+    }
+
+    fn remove_record(&mut self, record: &FileRecord) -> Result<bool> {
+        Ok(update_files(self, std::slice::from_ref(record), &[])? > 0)
     }
 }
 
 
-pub fn parallel_bulk_insert(shared_graph: Arc<Mutex<GraphStorage>>, node: &mut NodeIndex, sorted_entries: Vec<FileRecord>){
-    let mut local_contents = HashMap::<String, Vec<FileRecord>>::new();
-    
-    for record in sorted_entries {
-        if record.path.len() == 0 {
-            let mut tmp_graph = shared_graph.lock().unwrap();
-
-            let leaf = tmp_graph.graph.add_node(GNode::FileLeaf {
-                name: String::from(record.name),
-                checksum: String::from(record.checksum),
-                id: 0,
-            });
-            tmp_graph.graph.add_edge(*node, leaf, ());
+/// A directory tree whose checksums are kept up to date as single files
+/// change, see [`update_files`]. The tree is either in memory or stored in the
+/// index, so both follow the very same rules.
+pub trait MerkleTree {
+    type Node: Copy + Eq + Hash;
+
+    fn root_node(&self) -> Self::Node;
+    /// The directory `name` of `parent`, created if missing when `create` is set.
+    fn directory(&mut self, parent: Self::Node, name: &str, create: bool) -> Result<Option<Self::Node>>;
+    /// Adds the file of `record` to `parent`, or updates the one of the same name.
+    fn set_file(&mut self, parent: Self::Node, record: &FileRecord) -> Result<()>;
+    /// Removes the file `name` of `parent`, telling whether it was there.
+    fn remove_file(&mut self, parent: Self::Node, name: &str) -> Result<bool>;
+    fn entry_checksums(&self, directory: Self::Node) -> Result<Vec<String>>;
+    fn set_checksum(&mut self, directory: Self::Node, checksum: String) -> Result<()>;
+    /// Removes the directory `directory` of `parent`, which has no entries left.
+    fn remove_directory(&mut self, parent: Self::Node, directory: Self::Node) -> Result<()>;
+}
 
+
+/// Removes the files of `removed` from `tree` and adds or updates the ones of
+/// `set`, then hashes the directories above them again, deepest first, and
+/// drops the ones left empty. Each of those directories is hashed out of all of
+/// its entries, so a change costs its depth times the width of the directories
+/// on the way. Returns how many of the removed files were there.
+pub fn update_files<T: MerkleTree>(tree: &mut T, removed: &[FileRecord], set: &[FileRecord]) -> Result<usize> {
+    let mut dirty = HashMap::<T::Node, (usize, Option<T::Node>)>::new();
+    let mut found = 0;
+    let changes = removed.iter().map(|record| (record, false)).chain(set.iter().map(|record| (record, true)));
+    'changes: for (record, create) in changes {
+        let mut trail = vec![(tree.root_node(), None)];
+        for component in &record.path {
+            let cursor = trail[trail.len() - 1].0;
+            match tree.directory(cursor, component, create)? {
+                Some(directory) => trail.push((directory, Some(cursor))),
+                None => continue 'changes,
+            }
+        }
+
+        let parent = trail[trail.len() - 1].0;
+        if create {
+            tree.set_file(parent, record)?;
+        } else if tree.remove_file(parent, &record.name)? {
+            found += 1;
+        } else {
             continue;
         }
-    
-        let s = &record.path[0];
-        match local_contents.get_mut(s) {
-            Some(vec) => {
-                let mut new_path = record.path.clone();
-                new_path.remove(0);
-                
-                vec.push(FileRecord {
-                    checksum: record.checksum,
-                    name: record.name,
-                    path: new_path,
-		    modified: record.modified,
-                });
-            }
-            None => {
-                let mut new_vec = Vec::<FileRecord>::new();
-                let mut new_path = record.path.clone();
-                new_path.remove(0);
-    
-                new_vec.push(FileRecord {
-                    checksum: record.checksum,
-                    name: record.name,
-                    path: new_path,
-		    modified: record.modified,
-                });
-                local_contents.insert(String::from(s), new_vec);
-            }
-        }            
+        for (depth, (node, parent)) in trail.into_iter().enumerate() {
+            dirty.insert(node, (depth, parent));
+        }
+    }
+
+    let mut dirty: Vec<_> = dirty.into_iter().collect();
+    dirty.sort_by_key(|(_, (depth, _))| std::cmp::Reverse(*depth));
+    for (node, (_, parent)) in dirty {
+        let checksums = tree.entry_checksums(node)?;
+        match parent {
+            // directories only exist through the files under them.
+            Some(parent) if checksums.is_empty() => tree.remove_directory(parent, node)?,
+            _ => tree.set_checksum(node, directory_checksum(checksums))?,
+        }
     }
 
-    // for each of the keys, check if the node already exists on the graph
-    // - if it does, get the node index and recursivelly call parallel_execution
-    // - if it doesn't, create the node and the edge between input node and new one
-    //   and then recusively call parallel_execution with new node and corresponding
-    //   file records
-    let mut handles = Vec::new();
-    for (key, value) in local_contents {
-        let mut tmp_graph = shared_graph.lock().unwrap();
+    Ok(found)
+}
+
+
+impl MerkleTree for GraphStorage {
+    type Node = NodeIndex;
+
+    fn root_node(&self) -> NodeIndex {
+        self.root
+    }
+
+    fn directory(&mut self, parent: NodeIndex, name: &str, create: bool) -> Result<Option<NodeIndex>> {
+        let parent = self.loaded(parent)?;
+        match self.child_dir(parent, name) {
+            Some(directory) => Ok(Some(directory)),
+            None if create => Ok(Some(self.add_child(parent, GNode::DirNode {
+                name: String::from(name),
+                checksum: String::from("NA"),
+            }))),
+            None => Ok(None),
+        }
+    }
 
-        let mut cursor = match is_linked(&tmp_graph.graph, node, &key) {
-            Some(res) => res,
+    fn set_file(&mut self, parent: NodeIndex, record: &FileRecord) -> Result<()> {
+        let parent = self.loaded(parent)?;
+        match self.child_file(parent, &record.name) {
+            Some(leaf) => {
+                if let Some(GNode::FileLeaf {checksum, id, ..}) = self.graph.node_weight_mut(leaf) {
+                    *checksum = record.checksum.clone();
+                    *id = record.id;
+                }
+            },
             None => {
-                let new_node = tmp_graph.graph.add_node(GNode::DirNode {
-                    name: String::from(key),
-                    checksum: String::from("NA"),
+                self.add_child(parent, GNode::FileLeaf {
+                    name: record.name.clone(),
+                    checksum: record.checksum.clone(),
+                    id: record.id,
                 });
-                tmp_graph.graph.add_edge(*node, new_node, ());
-    
-                new_node
             },
-        };
+        }
 
-        let new_ref = shared_graph.clone();
-        handles.push(thread::spawn(move || {
-            parallel_bulk_insert(new_ref, &mut cursor, value);
-        }));
+        Ok(())
     }
 
-    for item in handles {
-        item.join().unwrap();
+    fn remove_file(&mut self, parent: NodeIndex, name: &str) -> Result<bool> {
+        let parent = self.loaded(parent)?;
+        match self.child_file(parent, name) {
+            Some(leaf) => {
+                self.remove_child(parent, leaf);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
     }
 
-    let mut tmp_graph = shared_graph.lock().unwrap();
+    fn entry_checksums(&self, directory: NodeIndex) -> Result<Vec<String>> {
+        Ok(self.graph.neighbors(directory).map(|node| String::from(self.graph[node].checksum())).collect())
+    }
 
-    // update current node's hash for all of its contents.
-    let checksum = calculate_hash(&tmp_graph.graph, node); 
-    let node_data = tmp_graph.graph.node_weight_mut(*node).unwrap();
-    let node_name = match node_data {
-        GNode::DirNode {name, checksum: _2} => name,
-        GNode::FileLeaf {name: _1, checksum: _2, id: _3} => panic!(
-            "LeafNode cannot be part of the trace. It should be impossible"
-        ),
-    };
+    fn set_checksum(&mut self, directory: NodeIndex, checksum: String) -> Result<()> {
+        match self.graph.node_weight_mut(directory) {
+            Some(GNode::DirNode {checksum: dir_checksum, ..}) => {
+                *dir_checksum = checksum;
+                Ok(())
+            },
+            _ => Err(Error::Graph(format!("node {:?} is not a directory", directory))),
+        }
+    }
 
-    *node_data = GNode::DirNode {
-        name: node_name.to_string(),
-        checksum,
+    fn remove_directory(&mut self, parent: NodeIndex, directory: NodeIndex) -> Result<()> {
+        self.remove_child(parent, directory);
+        Ok(())
     }
-    
-} 
+}
 
 
-pub fn create_shared_graph() -> Arc<Mutex<GraphStorage>> {
-    let mut new_graph = Graph::<GNode, ()>::new();
-    let root_index = new_graph.add_node(GNode::DirNode{
-        name: String::from("root"),
-        checksum: String::from(""),
-    });
+/// Records per subtree below which it is not split any further, as building
+/// it on its own would cost more than it saves.
+const MIN_SHARD_SIZE: usize = 256;
 
-    Arc::new(Mutex::new(
-        GraphStorage {
-            graph: new_graph,
-            root: root_index,
-        }
-    ))
+
+/// Records of a subtree, with paths relative to its directory at `prefix`.
+struct Shard {
+    prefix: Vec<String>,
+    records: Vec<FileRecord>,
 }
 
 
-fn is_linked(graph: &Graph::<GNode, ()>, cursor: &NodeIndex, key: &str) -> Option<NodeIndex> {
-    for thing in graph.neighbors(*cursor) {
-        let i = match graph.node_weight(thing).unwrap() {
-            GNode::FileLeaf {name: _1, checksum: _2, id: _3} => None,
-            GNode::DirNode {name: dir_name, checksum: _2} => Some(dir_name),
+/// Splits the records into disjoint subtrees, the largest first, until there
+/// are `target` of them. Files directly in a split directory are returned
+/// apart, with their full path.
+fn split_shards(records: Vec<FileRecord>, target: usize, min_size: usize) -> (Vec<Shard>, Vec<FileRecord>) {
+    let mut shards = vec![Shard { prefix: Vec::new(), records }];
+    let mut upper = Vec::new();
+
+    while shards.len() < target {
+        let largest = match shards.iter().enumerate().max_by_key(|(_, shard)| shard.records.len()) {
+            Some((i, shard)) if shard.records.len() >= min_size && shard.records.iter().any(|r| !r.path.is_empty()) => i,
+            _ => break,
         };
 
-        match i {
-            Some(dir_name) => {
-                if dir_name == key {
-                    return Some(thing)
-                } else {
-                    continue
-                }
+        let shard = shards.swap_remove(largest);
+        let mut children = HashMap::<String, Vec<FileRecord>>::new();
+        for mut record in shard.records {
+            if record.path.is_empty() {
+                record.path = shard.prefix.clone();
+                upper.push(record);
+            } else {
+                let first = record.path.remove(0);
+                children.entry(first).or_default().push(record);
+            }
+        }
+
+        for (name, records) in children {
+            let mut prefix = shard.prefix.clone();
+            prefix.push(name);
+            shards.push(Shard { prefix, records });
+        }
+    }
+
+    (shards, upper)
+}
+
+
+/// A directory grouped and hashed by a worker, ready to be added to the tree.
+struct BuiltDirectory {
+    name: String,
+    checksum: String,
+    files: Vec<FileRecord>,
+    directories: Vec<BuiltDirectory>,
+    /// Nodes below the directory, at any depth.
+    descendants: usize,
+}
+
+
+/// Groups `records` into the directory `name`, their paths relative to it
+/// starting at `depth`, and hashes every directory from the bottom up. Of the
+/// records of the same file, the last one is kept, as [`GraphStorage::add_child`] does.
+fn build_directory(name: String, records: Vec<FileRecord>, depth: usize) -> BuiltDirectory {
+    let mut files = HashMap::<String, FileRecord>::new();
+    let mut children = HashMap::<String, Vec<FileRecord>>::new();
+    for record in records {
+        match record.path.get(depth) {
+            Some(component) => children.entry(component.clone()).or_default().push(record),
+            None => {
+                files.insert(record.name.clone(), record);
             },
-            None => continue
-        };
+        }
+    }
+    let files: Vec<FileRecord> = files.into_values().collect();
+
+    let directories: Vec<BuiltDirectory> = children.into_iter()
+        .map(|(name, records)| build_directory(name, records, depth + 1))
+        .collect();
+    let checksum = directory_checksum(files.iter().map(|f| f.checksum.clone())
+        .chain(directories.iter().map(|d| d.checksum.clone()))
+        .collect());
+    let descendants = files.len() + directories.iter().map(|d| d.descendants + 1).sum::<usize>();
+
+    BuiltDirectory { name, checksum, files, directories, descendants }
+}
+
+
+/// Nodes of a subtree, numbered in the order they are to be added to the tree,
+/// each with its parent, and the entries of every directory they go into.
+struct PlacedNodes {
+    base: usize,
+    nodes: Vec<(NodeIndex, GNode)>,
+    entries: Vec<(NodeIndex, Entries)>,
+}
+
+
+/// Numbers the nodes of `built` from `base` on, below `parent`. The directory
+/// itself is left out when it is `parent` already.
+fn place_nodes(built: BuiltDirectory, parent: NodeIndex, base: usize, with_directory: bool) -> PlacedNodes {
+    let mut placed = PlacedNodes { base, nodes: Vec::with_capacity(built.descendants + 1), entries: Vec::new() };
+    let mut pending = if with_directory {
+        vec![(parent, Vec::new(), vec![built])]
+    } else {
+        vec![(parent, built.files, built.directories)]
+    };
+
+    while let Some((parent, files, directories)) = pending.pop() {
+        let mut entries = Entries::default();
+        for file in files {
+            let node = NodeIndex::new(base + placed.nodes.len());
+            entries.files.insert(file.name.clone(), node);
+            placed.nodes.push((parent, GNode::FileLeaf { name: file.name, checksum: file.checksum, id: file.id }));
+        }
+        for directory in directories {
+            let node = NodeIndex::new(base + placed.nodes.len());
+            entries.dirs.insert(directory.name.clone(), node);
+            placed.nodes.push((parent, GNode::DirNode { name: directory.name, checksum: directory.checksum }));
+            pending.push((node, directory.files, directory.directories));
+        }
+        placed.entries.push((parent, entries));
+    }
+
+    placed
+}
+
+
+/// Builds the tree of `sorted_entries` on a pool of `threads` workers.
+///
+/// The records are split into disjoint subtrees, which workers group and hash
+/// on their own. Each subtree then gets a range of node indices, and workers
+/// number its nodes and index them by name, so the calling thread only appends
+/// them to the tree. The few directories above the subtrees are hashed last.
+pub fn parallel_bulk_insert(sorted_entries: Vec<FileRecord>, threads: usize) -> Result<GraphStorage> {
+    sharded_bulk_insert(sorted_entries, threads, MIN_SHARD_SIZE)
+}
+
+
+/// Runs `job` on every item on the pool, returning the results in any order.
+fn run_on_pool<I, O, F>(pool: &ThreadPool, items: Vec<I>, job: F) -> Result<Vec<O>>
+where
+    I: Send + 'static,
+    O: Send + 'static,
+    F: Fn(I) -> O + Send + Sync + 'static,
+{
+    let (tx, rx) = channel();
+    let expected = items.len();
+    let job = Arc::new(job);
+    for item in items {
+        let tx = tx.clone();
+        let job = job.clone();
+        pool.execute(move || {
+            let _ = tx.send(job(item));
+        });
+    }
+    drop(tx);
+
+    let res: Vec<O> = rx.iter().collect();
+    if res.len() != expected {
+        return Err(Error::Graph(String::from("a subtree build panicked")));
+    }
+
+    Ok(res)
+}
+
+
+fn sharded_bulk_insert(sorted_entries: Vec<FileRecord>, threads: usize, min_shard_size: usize) -> Result<GraphStorage> {
+    let threads = threads.max(1);
+    // more subtrees than workers, so uneven ones still keep everybody busy.
+    let (shards, upper) = split_shards(sorted_entries, threads * 4, min_shard_size);
+    let pool = ThreadPool::new(threads);
+
+    let built = run_on_pool(&pool, shards, |shard: Shard| {
+        let name = shard.prefix.last().cloned().unwrap_or_default();
+        (shard.prefix, build_directory(name, shard.records, 0))
+    })?;
+
+    // the directories above the subtrees come first, then a range of indices per subtree.
+    let mut graph = initialise_graph();
+    let mut above = HashMap::<NodeIndex, usize>::new();
+    let mut placements = Vec::with_capacity(built.len());
+    for (prefix, built) in built {
+        let parent = graph.make_directories(prefix.split_last().map_or(&[][..], |(_, parent)| parent), &mut above);
+        placements.push((parent, !prefix.is_empty(), built));
+    }
+    let mut base = graph.graph.node_count();
+    let mut jobs = Vec::with_capacity(placements.len());
+    for (parent, with_directory, built) in placements {
+        let count = built.descendants + usize::from(with_directory);
+        jobs.push((parent, with_directory, base, built));
+        base += count;
+    }
+
+    let mut placed = run_on_pool(&pool, jobs, |(parent, with_directory, base, built)| {
+        place_nodes(built, parent, base, with_directory)
+    })?;
+    placed.sort_by_key(|p| p.base);
+    for nodes in placed {
+        graph.append(nodes)?;
+    }
+
+    for record in upper {
+        let parent = graph.make_directories(&record.path, &mut above);
+        graph.add_child(parent, GNode::FileLeaf {
+            name: record.name,
+            checksum: record.checksum,
+            id: record.id,
+        });
+    }
+
+    // deepest first, so each directory is hashed from up to date entries.
+    let mut above: Vec<(NodeIndex, usize)> = above.into_iter().collect();
+    above.sort_by_key(|(_, depth)| std::cmp::Reverse(*depth));
+    for (node, _) in above {
+        update_dir_checksum(&mut graph.graph, &node)?;
     }
 
-    return None;
+    Ok(graph)
 }
 
 
-fn calculate_hash(graph: &Graph::<GNode, ()>, cursor: &NodeIndex) -> String {
+impl GraphStorage {
+    /// The directory at `path`, created as needed, noting every directory on
+    /// the way with its depth in `above`.
+    fn make_directories(&mut self, path: &[String], above: &mut HashMap<NodeIndex, usize>) -> NodeIndex {
+        let mut cursor = self.root;
+        above.insert(cursor, 0);
+
+        for (depth, component) in path.iter().enumerate() {
+            cursor = match self.child_dir(cursor, component) {
+                Some(dir) => dir,
+                None => self.add_child(cursor, GNode::DirNode {
+                    name: component.clone(),
+                    checksum: String::from("NA"),
+                }),
+            };
+            above.insert(cursor, depth + 1);
+        }
+
+        cursor
+    }
+
+    /// Adds nodes numbered by [`place_nodes`], which must come right after the
+    /// nodes already in the tree.
+    fn append(&mut self, placed: PlacedNodes) -> Result<()> {
+        for (parent, weight) in placed.nodes {
+            let expected = NodeIndex::new(self.graph.node_count());
+            let node = self.graph.add_node(weight);
+            if node != expected {
+                return Err(Error::Graph(format!("node {:?} was numbered {:?}", node, expected)));
+            }
+            self.graph.add_edge(parent, node, ());
+        }
+
+        for (directory, entries) in placed.entries {
+            match self.entries.entry(directory) {
+                Entry::Occupied(mut known) => {
+                    known.get_mut().dirs.extend(entries.dirs);
+                    known.get_mut().files.extend(entries.files);
+                },
+                Entry::Vacant(slot) => {
+                    slot.insert(entries);
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+fn update_dir_checksum(graph: &mut StableGraph::<GNode, ()>, node: &NodeIndex) -> Result<()> {
+    let checksum = calculate_hash(graph, node);
+    match graph.node_weight_mut(*node) {
+        Some(GNode::DirNode {checksum: dir_checksum, ..}) => {
+            *dir_checksum = checksum;
+            Ok(())
+        },
+        Some(GNode::FileLeaf {name, ..}) => Err(Error::Graph(
+            format!("file {} cannot be part of the trace", name)
+        )),
+        None => Err(Error::Graph(format!("node {:?} does not exist", node))),
+    }
+}
+
+
+fn calculate_hash(graph: &StableGraph::<GNode, ()>, cursor: &NodeIndex) -> String {
     let mut buff = Vec::<String>::new();
     for thing in graph.neighbors(*cursor) {
-        let elem_checksum = match graph.node_weight(thing).unwrap() {
-            GNode::FileLeaf {name: _1, checksum, id: _3} => checksum,
-            GNode::DirNode {name: _2, checksum} => checksum,
+        let elem_checksum = match &graph[thing] {
+            GNode::FileLeaf {checksum, ..} => checksum,
+            GNode::DirNode {checksum, ..} => checksum,
         };
 
         buff.push(elem_checksum.clone());
     }
 
+    directory_checksum(buff)
+}
+
+
+/// Checksum of a directory out of the checksums of its entries, in any order.
+pub fn directory_checksum(mut checksums: Vec<String>) -> String {
     let mut hasher = Md5::new();
-    buff.sort();
-    for checksum in buff {
+    checksums.sort();
+    for checksum in checksums {
         hasher.input_str(checksum.as_str());
     }
-    
-    String::from(hasher.result_str())
+
+    hasher.result_str()
 }
 
 
@@ -394,6 +928,11 @@ mod test {
 
     use chrono::{DateTime, NaiveDate, NaiveTime, NaiveDateTime, Utc};
 
+    use std::env;
+    use std::fs;
+
+    use crate::file_handler::{scan, ScanOptions, ScanSource};
+    use crate::index_db;
     use crate::misc;
     use misc::path_to_components;
 
@@ -406,46 +945,52 @@ mod test {
 	let d = NaiveDate::from_ymd(2015, 6, 3);
 	let t = NaiveTime::from_hms_milli(12, 34, 56, 789);
 
-	return DateTime::<Utc>::from_utc(NaiveDateTime::new(d, t), Utc);
+	DateTime::<Utc>::from_utc(NaiveDateTime::new(d, t), Utc)
     }
     
     #[test]
     fn test_bulk_insert() {
-        let mut records = Vec::<FileRecord>::new();
-        records.push(FileRecord {
+        let records = vec![
+        FileRecord {
+            id: 0,
             checksum: String::from("aaaaa"),
             name: String::from("aaaaa.txt"),
             path: elem_from_path(String::from("/some/")),
 	    modified: mock_date_time()
-        });
-        records.push(FileRecord {
+        },
+        FileRecord {
+            id: 0,
             checksum: String::from("aaaaa"),
             name: String::from("aaaaa.txt"),
             path: elem_from_path(String::from("/some/location/")),
 	    modified: mock_date_time()
-        });
-        records.push(FileRecord {
+        },
+        FileRecord {
+            id: 0,
             checksum: String::from("aaaaa"),
             name: String::from("aaaaa.txt"),
             path: elem_from_path(String::from("/some/other/")),
 	    modified: mock_date_time()
-        });
-        records.push(FileRecord {
+        },
+        FileRecord {
+            id: 0,
             checksum: String::from("aaaaa"),
             name: String::from("aaaaa.txt"),
             path: elem_from_path(String::from("/some/yet-another/")),
 	    modified: mock_date_time()
-        });
-        records.push(FileRecord {
+        },
+        FileRecord {
+            id: 0,
             checksum: String::from("aabbb"),
             name: String::from("aabbb.txt"),
             path: elem_from_path(String::from("/some/location/")),
 	    modified: mock_date_time()
-        });
-        
+        },
+        ];
+
         let mut graph = initialise_graph();
         let mut root = graph.root;
-        graph._bulk_insert(&mut root, records);
+        graph._bulk_insert(&mut root, records).unwrap();
         
         let res = graph.find_duplicates();
         
@@ -457,50 +1002,225 @@ mod test {
 
     #[test]
     fn test_bulk_parallel_insert() {
-        let mut records = Vec::<FileRecord>::new();
-        records.push(FileRecord {
+        let records = vec![
+        FileRecord {
+            id: 0,
             checksum: String::from("aaaaa"),
             name: String::from("aaaaa.txt"),
             path: elem_from_path(String::from("/some/")),
 	    modified: mock_date_time()
-        });
-        records.push(FileRecord {
+        },
+        FileRecord {
+            id: 0,
             checksum: String::from("aaaaa"),
             name: String::from("aaaaa.txt"),
             path: elem_from_path(String::from("/some/location/")),
 	    modified: mock_date_time()
-        });
-        records.push(FileRecord {
+        },
+        FileRecord {
+            id: 0,
             checksum: String::from("aaaaa"),
             name: String::from("aaaaa.txt"),
             path: elem_from_path(String::from("/some/other/")),
 	    modified: mock_date_time()
-        });
-        records.push(FileRecord {
+        },
+        FileRecord {
+            id: 0,
             checksum: String::from("aaaaa"),
             name: String::from("aaaaa.txt"),
             path: elem_from_path(String::from("/some/yet-another/")),
 	    modified: mock_date_time()
-        });
-        records.push(FileRecord {
+        },
+        FileRecord {
+            id: 0,
             checksum: String::from("aabbb"),
             name: String::from("aabbb.txt"),
             path: elem_from_path(String::from("/some/location/")),
 	    modified: mock_date_time()
-        });
-        
-        let graph_ref = create_shared_graph();
-        let local_ref = graph_ref.clone();
-        let mut root = local_ref.lock().unwrap().root;
+        },
+        ];
+
+        // small enough subtrees that every directory is built apart.
+        let graph = sharded_bulk_insert(records.clone(), 4, 1).unwrap();
+        let mut sequential = initialise_graph();
+        sequential.bulk_insert(records).unwrap();
+        assert_eq!(format!("{:?}", graph.graph[graph.root]), format!("{:?}", sequential.graph[sequential.root]));
+        let sorted = |graph: &GraphStorage| {
+            let mut directories = graph.directories();
+            directories.sort();
+            directories
+        };
+        assert_eq!(sorted(&graph), sorted(&sequential));
+        // nodes numbered by the workers are found by name like any other.
+        assert!(graph.lookup(&["some", "location", "aabbb.txt"]).is_some());
+        assert!(graph.lookup(&["some", "yet-another"]).is_some());
 
-        let first_ref = graph_ref.clone();
-        parallel_bulk_insert(first_ref, &mut root, records);
-        
-        let res = local_ref.lock().unwrap().find_duplicates();
+        let res = graph.find_duplicates();
         
         println!("dupes : {:#?}", res);
 
         assert_eq!(res.len(), 2);
         assert_eq!(res.get("aaaaa").unwrap().len(), 3);
     }
+
+    #[test]
+    fn test_duplicate_names_share_a_node() {
+        let record = |name: &str, checksum: &str| FileRecord {
+            id: 0,
+            checksum: String::from(checksum),
+            name: String::from(name),
+            path: elem_from_path(String::from("/nas/a/")),
+            modified: mock_date_time(),
+        };
+        let records = vec![record("one", "stale"), record("two", "2"), record("one", "1")];
+
+        let mut graph = initialise_graph();
+        graph.bulk_insert(records.clone()).unwrap();
+        let parallel = sharded_bulk_insert(records, 2, 1).unwrap();
+        let mut expected = initialise_graph();
+        expected.bulk_insert(vec![record("two", "2"), record("one", "1")]).unwrap();
+
+        // the last record wins, and the stale one neither lingers nor counts in the checksums.
+        for graph in [&graph, &parallel] {
+            assert_eq!(graph.graph.node_count(), expected.graph.node_count());
+            assert_eq!(graph.directories(), expected.directories());
+            let leaf = graph.lookup(&["nas", "a", "one"]).unwrap();
+            assert_eq!(format!("{:?}", graph.graph[leaf]), format!("{:?}", expected.graph[expected.lookup(&["nas", "a", "one"]).unwrap()]));
+        }
+
+        assert!(graph.remove_record(&record("one", "1")).unwrap());
+        assert_eq!(graph.lookup(&["nas", "a", "one"]), None);
+        assert_eq!(graph.graph.node_count(), expected.graph.node_count() - 1);
+    }
+
+    #[test]
+    fn test_stored_graph_follows_scans() {
+        let dir = env::temp_dir().join(format!("rusty-index-graph-{}", std::process::id()));
+        fs::create_dir_all(dir.join("a/deep")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("a/deep/one"), "one").unwrap();
+        fs::write(dir.join("a/two"), "two").unwrap();
+        fs::write(dir.join("b/two"), "two").unwrap();
+
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        let rescan = || {
+            let options = ScanOptions { volume: Some(String::from("disk")), ..ScanOptions::default() };
+            scan(ScanSource::Directory(String::from(dir.to_str().unwrap())), &storage, options, |_| {}).unwrap();
+        };
+        let fresh = || {
+            let mut graph = initialise_graph();
+            graph.bulk_insert(storage.fetch_sorted().unwrap().iter().map(misc::to_file_record).collect()).unwrap();
+            graph
+        };
+        let sorted = |graph: &GraphStorage| {
+            let mut dirs = graph.directories();
+            dirs.sort();
+            dirs
+        };
+
+        rescan();
+        assert!(storage.graph_root().unwrap().is_none());
+        load_graph(&storage).unwrap();
+
+        // lazily loaded, a directory at a time.
+        let mut graph = open_graph(&storage).unwrap();
+        assert_eq!(graph.graph.node_count(), 1);
+        graph.expand(&storage, graph.root).unwrap();
+        assert_eq!(graph.graph.node_count(), 2);
+
+        // the stored tree is updated in place by the next scans.
+        fs::remove_dir_all(dir.join("a/deep")).unwrap();
+        fs::write(dir.join("b/two"), "changed").unwrap();
+        fs::write(dir.join("b/three"), "three").unwrap();
+        rescan();
+
+        let stored = load_graph(&storage).unwrap();
+        let root_checksum = |graph: &GraphStorage| match &graph.graph[graph.root] {
+            GNode::DirNode {checksum, ..} => checksum.clone(),
+            GNode::FileLeaf {..} => String::new(),
+        };
+        assert_eq!(root_checksum(&stored), root_checksum(&fresh()));
+        assert_eq!(sorted(&stored), sorted(&fresh()));
+        assert_eq!(stored.graph.node_count(), fresh().graph.node_count());
+        assert!(stored.directories().iter().all(|(path, _)| path.last().unwrap() != "deep"));
+
+        // every file still leads to its row, unchanged ones included.
+        let leaves: Vec<NodeIndex> = stored.graph.node_indices().filter(|n| matches!(stored.graph[*n], GNode::FileLeaf {..})).collect();
+        assert_eq!(leaves.len(), 3);
+        for leaf in leaves {
+            let record = stored.record(&storage, leaf).unwrap().unwrap();
+            assert_eq!(stored.graph[leaf].to_string(), format!("File-{}", record.name));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_record_updates() {
+        let record = |path: &str, name: &str, checksum: &str| FileRecord {
+            id: 0,
+            checksum: String::from(checksum),
+            name: String::from(name),
+            path: elem_from_path(String::from(path)),
+            modified: mock_date_time(),
+        };
+        let mut records = vec![
+            record("/nas/a/", "one", "1"),
+            record("/nas/a/deep/", "two", "2"),
+            record("/nas/b/", "one", "1"),
+        ];
+
+        let mut graph = initialise_graph();
+        graph.bulk_insert(records.clone()).unwrap();
+
+        graph.insert_record(record("/nas/b/deep/", "two", "2")).unwrap();
+        graph.insert_record(record("/nas/a/", "one", "changed")).unwrap();
+        assert!(graph.remove_record(&record("/nas/a/deep/", "two", "2")).unwrap());
+        assert!(!graph.remove_record(&record("/nas/nowhere/", "two", "2")).unwrap());
+        records = vec![record("/nas/a/", "one", "changed"), record("/nas/b/", "one", "1"), record("/nas/b/deep/", "two", "2")];
+
+        // the same tree as built from scratch, checksums included.
+        let mut fresh = initialise_graph();
+        fresh.bulk_insert(records).unwrap();
+        let sorted = |graph: &GraphStorage| {
+            let mut dirs = graph.directories();
+            dirs.sort();
+            dirs
+        };
+        assert_eq!(sorted(&graph), sorted(&fresh));
+        assert_eq!(graph.graph.node_count(), fresh.graph.node_count());
+        assert_eq!(format!("{:?}", graph.graph[graph.root]), format!("{:?}", fresh.graph[fresh.root]));
+    }
+
+    #[test]
+    fn test_lookup() {
+        // a single wide directory, which used to take quadratic time.
+        let records: Vec<FileRecord> = (0..20000).map(|i| FileRecord {
+            id: 0,
+            checksum: format!("{}", i % 7),
+            name: format!("file-{}", i),
+            path: vec![String::from("nas"), String::from("wide")],
+            modified: mock_date_time(),
+        }).collect();
+
+        let mut graph = initialise_graph();
+        graph.bulk_insert(records).unwrap();
+
+        let leaf = graph.lookup(&["nas", "wide", "file-19999"]).unwrap();
+        assert_eq!(graph.graph[leaf].to_string(), "File-file-19999");
+        let dir = graph.lookup(&["nas", "wide"]).unwrap();
+        assert_eq!(graph.graph[dir].to_string(), "Directory-wide");
+        assert_eq!(graph.lookup::<&str>(&[]), Some(graph.root));
+        assert_eq!(graph.lookup(&["nas", "file-1"]), None);
+
+        graph.remove_record(&FileRecord {
+            id: 0,
+            checksum: String::from("0"),
+            name: String::from("file-0"),
+            path: vec![String::from("nas"), String::from("wide")],
+            modified: mock_date_time(),
+        }).unwrap();
+        assert_eq!(graph.lookup(&["nas", "wide", "file-0"]), None);
+    }
 }
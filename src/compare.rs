@@ -0,0 +1,218 @@
+//! Comparison of two indexes by content, such as a primary and its backup.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::error::{Error, Result};
+use crate::file_handler::HashAlgorithm;
+use crate::index_db::{IndexRecord, IndexStorage};
+
+
+/// Content present on both sides, but not at the same place.
+#[derive(Debug)]
+pub struct Relocated {
+    pub left: Vec<IndexRecord>,
+    pub right: Vec<IndexRecord>,
+}
+
+
+#[derive(Debug, Default)]
+pub struct Comparison {
+    /// Files whose content is nowhere on the right, such as data not backed up.
+    pub left_only: Vec<IndexRecord>,
+    /// Files whose content is nowhere on the left.
+    pub right_only: Vec<IndexRecord>,
+    /// Content on both sides under different paths, by checksum.
+    pub relocated: Vec<Relocated>,
+}
+
+
+impl Comparison {
+    /// Bytes that exist only on the left, counting every copy.
+    pub fn left_only_bytes(&self) -> u64 {
+        self.left_only.iter().map(|r| r.size).sum()
+    }
+}
+
+
+fn by_checksum(records: Vec<IndexRecord>, volume: Option<&str>) -> HashMap<String, Vec<IndexRecord>> {
+    let mut res = HashMap::<String, Vec<IndexRecord>>::new();
+    for record in records {
+        if volume.is_some_and(|label| label != record.volume) {
+            continue;
+        }
+        res.entry(record.checksum.clone()).or_default().push(record);
+    }
+
+    res
+}
+
+
+/// Path of a record within its volume, so copies on differently labelled
+/// volumes can be at the same place.
+fn relative_location(record: &IndexRecord) -> (&str, &str) {
+    (record.path.as_str(), record.name.as_str())
+}
+
+
+/// Hash algorithm of the volumes compared on one side, which must all have the
+/// same, or none when there is no such volume.
+fn side_algorithm<T: IndexStorage>(storage: &T, volume: Option<&str>, side: &str) -> Result<Option<HashAlgorithm>> {
+    let mut algorithms: Vec<HashAlgorithm> = storage.volumes()?.iter()
+        .filter(|v| volume.is_none_or(|label| label == v.label))
+        .map(|v| v.algorithm)
+        .collect();
+    algorithms.sort_by_key(|a| a.as_str());
+    algorithms.dedup();
+
+    match algorithms.as_slice() {
+        [] => Ok(None),
+        [algorithm] => Ok(Some(*algorithm)),
+        _ => {
+            let names: Vec<&str> = algorithms.iter().map(|a| a.as_str()).collect();
+            Err(Error::Algorithm(format!("the {} index mixes {}, compare a single volume of it", side, names.join(" and "))))
+        },
+    }
+}
+
+
+/// Compares the contents of two indexes by checksum, ignoring where they are.
+/// Either side can be narrowed down to a single volume. Both sides must be
+/// hashed the same way, or no content would ever match.
+pub fn compare<L: IndexStorage, R: IndexStorage>(
+    left: &L,
+    left_volume: Option<&str>,
+    right: &R,
+    right_volume: Option<&str>,
+) -> Result<Comparison> {
+    let left_algorithm = side_algorithm(left, left_volume, "left")?;
+    let right_algorithm = side_algorithm(right, right_volume, "right")?;
+    if let (Some(l), Some(r)) = (left_algorithm, right_algorithm) {
+        if l != r {
+            return Err(Error::Algorithm(format!(
+                "the left side is hashed with {} and the right side with {}", l.as_str(), r.as_str(),
+            )));
+        }
+    }
+
+    let left = by_checksum(left.fetch_sorted()?, left_volume);
+    let mut right = by_checksum(right.fetch_sorted()?, right_volume);
+
+    let mut comparison = Comparison::default();
+    for (checksum, left_copies) in left {
+        let right_copies = match right.remove(&checksum) {
+            Some(copies) => copies,
+            None => {
+                comparison.left_only.extend(left_copies);
+                continue;
+            },
+        };
+
+        let left_places: HashSet<(&str, &str)> = left_copies.iter().map(relative_location).collect();
+        if right_copies.iter().all(|r| !left_places.contains(&relative_location(r))) {
+            comparison.relocated.push(Relocated { left: left_copies, right: right_copies });
+        }
+    }
+    comparison.right_only = right.into_values().flatten().collect();
+
+    comparison.left_only.sort_by_key(|r| r.location());
+    comparison.right_only.sort_by_key(|r| r.location());
+    comparison.relocated.sort_by_key(|r| r.left[0].location());
+
+    Ok(comparison)
+}
+
+
+/// Paths of `records` relative to their volume root, one per entry, as taken
+/// by `rsync --files-from` (with `--from0` when NUL separated).
+pub fn copy_list(records: &[IndexRecord], separator: u8) -> Vec<u8> {
+    let mut res = Vec::new();
+    for record in records {
+        if !record.path.is_empty() {
+            res.extend_from_slice(record.path.as_bytes());
+            res.push(b'/');
+        }
+        res.extend_from_slice(record.name.as_bytes());
+        res.push(separator);
+    }
+
+    res
+}
+
+
+/// Copy list of the files only on the left, see [`copy_list`]. Their paths are
+/// relative to their volume, so they must all be on the same one.
+pub fn left_only_copy_list(comparison: &Comparison, separator: u8) -> Result<Vec<u8>> {
+    let mut volumes: Vec<&str> = comparison.left_only.iter().map(|r| r.volume.as_str()).collect();
+    volumes.sort();
+    volumes.dedup();
+    if volumes.len() > 1 {
+        return Err(Error::Parse(format!(
+            "the files only on the left span volumes {}, compare a single one", volumes.join(", "),
+        )));
+    }
+
+    Ok(copy_list(&comparison.left_only, separator))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Utc;
+
+    use crate::index_db::{self, SQLite3};
+
+    fn index(volume: &str, files: &[(&str, &str, &str)]) -> SQLite3 {
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        storage.register_volume(volume, None, "/", None).unwrap();
+
+        let records: Vec<IndexRecord> = files.iter().map(|(path, name, checksum)| IndexRecord {
+            id: 0,
+            checksum: String::from(*checksum),
+            name: String::from(*name),
+            volume: String::from(volume),
+            path: String::from(*path),
+            modified: Utc::now(),
+            size: 10,
+            inode: None,
+        }).collect();
+        storage.insert(&records).unwrap();
+
+        storage
+    }
+
+    #[test]
+    fn test_compare() {
+        let nas = index("nas", &[("photos", "a.jpg", "1"), ("photos", "b.jpg", "2"), ("docs", "c.txt", "3"), ("", "d", "4")]);
+        let backup = index("backup-2", &[("photos", "a.jpg", "1"), ("old", "b.jpg", "2"), ("", "e", "5")]);
+
+        let res = compare(&nas, None, &backup, None).unwrap();
+
+        let names = |records: &[IndexRecord]| records.iter().map(|r| r.location()).collect::<Vec<String>>();
+        assert_eq!(names(&res.left_only), vec!["nas:d", "nas:docs/c.txt"]);
+        assert_eq!(names(&res.right_only), vec!["backup-2:e"]);
+        assert_eq!(res.relocated.len(), 1);
+        assert_eq!(names(&res.relocated[0].right), vec!["backup-2:old/b.jpg"]);
+        assert_eq!(res.left_only_bytes(), 20);
+
+        assert_eq!(left_only_copy_list(&res, b'\n').unwrap(), b"d\ndocs/c.txt\n".to_vec());
+
+        let both = index("nas", &[("", "d", "4")]);
+        both.register_volume("usb", None, "/", None).unwrap();
+        let mut usb = both.fetch_sorted().unwrap();
+        usb[0].volume = String::from("usb");
+        both.insert(&usb).unwrap();
+        assert!(left_only_copy_list(&compare(&both, None, &backup, None).unwrap(), 0).is_err());
+        assert!(index_db::open_existing("no-such-index.db").is_err());
+
+        // md5 and sha256 checksums never match, the comparison is refused.
+        let sha = index("nas", &[]);
+        sha.register_volume("sha", None, "/sha", Some(HashAlgorithm::Sha256)).unwrap();
+        let err = compare(&nas, None, &sha, Some("sha")).unwrap_err();
+        assert_eq!(err.exit_code(), 8);
+        assert!(compare(&nas, None, &sha, None).is_err());
+        assert!(compare(&nas, None, &sha, Some("nas")).is_ok());
+    }
+}
@@ -0,0 +1,216 @@
+//! User configuration: named profiles mapping to an index database and the
+//! roots scanned into it.
+//!
+//! ```toml
+//! default_profile = "nas"
+//!
+//! [profiles.nas]
+//! db = "~/indexes/nas.db"
+//! roots = ["/mnt/nas/photos", "/mnt/nas/documents"]
+//!
+//! [[profiles.nas.volumes]]
+//! label = "backup-2"
+//! root = "/media/backup"
+//! uuid = "5e1c0a8f-0d3b-4c5e-9a0e-3f4f1b2c7d11"
+//! ```
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+extern crate serde;
+use serde::Deserialize;
+
+extern crate toml;
+
+use crate::error::{Error, Result};
+
+pub const DB_ENV_VAR: &str = "RUSTY_INDEX_DB";
+pub const DEFAULT_DB: &str = "index.db";
+
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Profile used when none is given on the command line.
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Profile {
+    pub db: Option<String>,
+    /// Roots scanned as volumes labelled by their own path.
+    #[serde(default)]
+    pub roots: Vec<String>,
+    #[serde(default)]
+    pub volumes: Vec<ProfileVolume>,
+}
+
+
+/// A root scanned as a named volume.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProfileVolume {
+    pub label: String,
+    pub root: String,
+    pub uuid: Option<String>,
+}
+
+
+impl Profile {
+    /// Everything scanning the profile covers: the root of each volume, with
+    /// its label and UUID when named, then the roots labelled by their path.
+    pub fn sources(&self) -> Vec<(String, Option<String>, Option<String>)> {
+        self.volumes.iter()
+            .map(|v| (expand_home(&v.root), Some(v.label.clone()), v.uuid.clone()))
+            .chain(self.roots.iter().map(|r| (expand_home(r), None, None)))
+            .collect()
+    }
+}
+
+
+impl Config {
+    /// Looks up the profile called `name`, or the default profile when no name
+    /// is given. Naming a profile that doesn't exist is an error.
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<&Profile>> {
+        match name {
+            Some(name) => match self.profiles.get(name) {
+                Some(profile) => Ok(Some(profile)),
+                None => Err(Error::Parse(format!("unknown profile '{}'", name))),
+            },
+            None => match &self.default_profile {
+                Some(name) => self.profile(Some(name)),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Error {
+        Error::Parse(format!("invalid configuration: {}", e))
+    }
+}
+
+
+/// `$XDG_CONFIG_HOME/rusty-index/config.toml`, falling back to `~/.config`.
+pub fn default_config_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(base.join("rusty-index").join("config.toml"))
+}
+
+
+/// Reads the configuration at `path`. A missing file is an empty configuration.
+pub fn load_config(path: &Path) -> Result<Config> {
+    match fs::read_to_string(path) {
+        Ok(contents) => parse_config(&contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+
+pub fn parse_config(contents: &str) -> Result<Config> {
+    Ok(toml::from_str(contents)?)
+}
+
+
+/// Expands a leading `~/` to the home directory.
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home.trim_end_matches('/'), rest),
+        _ => String::from(path),
+    }
+}
+
+
+/// Picks the index database, by order of precedence: the `--db` flag, the
+/// profile named with `--profile`, the `RUSTY_INDEX_DB` variable, the default
+/// profile and finally `index.db`.
+pub fn resolve_db_path(flag: Option<&str>, config: &Config, profile: Option<&str>, env_db: Option<String>) -> Result<String> {
+    if let Some(db) = flag {
+        return Ok(String::from(db));
+    }
+
+    let named = match profile {
+        Some(name) => config.profile(Some(name))?,
+        None => None,
+    };
+    if let Some(db) = named.and_then(|p| p.db.as_ref()) {
+        return Ok(expand_home(db));
+    }
+    if let Some(db) = env_db.filter(|db| !db.is_empty()) {
+        return Ok(db);
+    }
+
+    match config.profile(None)?.and_then(|p| p.db.as_ref()) {
+        Some(db) => Ok(expand_home(db)),
+        None => Ok(String::from(DEFAULT_DB)),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+default_profile = "nas"
+
+[profiles.nas]
+db = "/srv/nas.db"
+roots = ["/mnt/nas/photos", "/mnt/nas/documents"]
+
+[[profiles.nas.volumes]]
+label = "backup-2"
+root = "/media/backup"
+
+[profiles.backup]
+roots = ["/mnt/backup"]
+"#;
+
+    #[test]
+    fn test_profiles() {
+        let config = parse_config(EXAMPLE).unwrap();
+
+        let default = config.profile(None).unwrap().unwrap();
+        assert_eq!(default.db, Some(String::from("/srv/nas.db")));
+        assert_eq!(default.roots.len(), 2);
+        assert_eq!(default.volumes[0].label, "backup-2");
+        assert_eq!(default.volumes[0].uuid, None);
+
+        let backup = config.profile(Some("backup")).unwrap().unwrap();
+        assert_eq!(backup.db, None);
+
+        let sources = default.sources();
+        assert_eq!(sources.len(), 3);
+        assert_eq!(sources[0], (String::from("/media/backup"), Some(String::from("backup-2")), None));
+        assert_eq!(sources[2].0, "/mnt/nas/documents");
+
+        assert!(config.profile(Some("nope")).is_err());
+        assert!(Config::default().profile(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_resolve_db_path() {
+        let config = parse_config(EXAMPLE).unwrap();
+        let env_db = Some(String::from("/env.db"));
+
+        assert_eq!(resolve_db_path(Some("/flag.db"), &config, Some("nas"), env_db.clone()).unwrap(), "/flag.db");
+        assert_eq!(resolve_db_path(None, &config, Some("nas"), env_db.clone()).unwrap(), "/srv/nas.db");
+        assert_eq!(resolve_db_path(None, &config, Some("backup"), env_db.clone()).unwrap(), "/env.db");
+        // the variable, set for a single run, wins over the default profile.
+        assert_eq!(resolve_db_path(None, &config, None, env_db).unwrap(), "/env.db");
+        assert_eq!(resolve_db_path(None, &config, None, Some(String::new())).unwrap(), "/srv/nas.db");
+        assert_eq!(resolve_db_path(None, &Config::default(), None, None).unwrap(), DEFAULT_DB);
+        assert!(resolve_db_path(None, &config, Some("nope"), None).is_err());
+    }
+}
@@ -0,0 +1,534 @@
+//! Reports of the duplicates found in the directory tree, in a versioned JSON
+//! schema.
+//!
+//! A report is a `header` followed by the `groups` of copies, the most space
+//! to reclaim first:
+//!
+//! ```json
+//! {
+//!   "header": {
+//!     "schema": "rusty-index/duplicates", "version": 1, "tool_version": "0.1.0",
+//!     "hash_algorithms": ["md5"],
+//!     "roots": [{"volume": "nas", "root": "/mnt/nas", "algorithm": "md5"}],
+//!     "generated": "2021-03-01T10:00:00Z"
+//!   },
+//!   "groups": [{
+//!     "checksum": "078b0dfa7c61da6f98894fa286d506de", "kind": "directory",
+//!     "size": 2048, "files": 2, "reclaimable": 2048,
+//!     "paths": [
+//!       {"volume": "nas", "path": "photos/", "modified": "2020-12-24T18:00:00Z", "record": null},
+//!       {"volume": "nas", "path": "backup/photos/", "modified": "2020-12-24T18:00:00Z", "record": null}
+//!     ]
+//!   }]
+//! }
+//! ```
+//!
+//! `size` and `files` are those of a single copy, and `reclaimable` what
+//! removing all copies but one would free. Paths are relative to the root of
+//! their volume, directories ending with a slash; `modified` is the latest
+//! change of a file below a directory, and `record` the row of a file in the
+//! index. `root` is null for volumes scanned without being stored.
+//!
+//! The NDJSON variant has the header and every group on a line of its own,
+//! tagged with `"type": "header"` or `"type": "group"`, so huge reports can be
+//! filtered with `jq -c 'select(.type == "group")'` without loading them whole.
+//! Fields are only added within a version, and the version is bumped for any
+//! other change.
+//!
+//! For people to review, reports can also be written as CSV, one row per copy
+//! with the number of its group, or as a self-contained HTML page. Copies
+//! ticked on the page are exported as a selection file: a `volume:path`
+//! location per line, lines starting with `#` being comments, read back with
+//! [`read_selection`].
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+extern crate chrono;
+use chrono::{DateTime, Utc};
+
+extern crate petgraph;
+use petgraph::graph::NodeIndex;
+
+extern crate serde;
+use serde::{Deserialize, Serialize};
+
+extern crate serde_json;
+
+use crate::analyser::{self, GNode, GraphStorage, GraphStorageInterface};
+use crate::error::{Error, Result};
+use crate::file_handler::HashAlgorithm;
+use crate::index_db::{location, IndexRecord, IndexStorage, Volume};
+
+
+pub const SCHEMA: &str = "rusty-index/duplicates";
+pub const SCHEMA_VERSION: u32 = 1;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    /// A single JSON document.
+    Json,
+    /// The header and every group on a line of their own.
+    Ndjson,
+    /// A row per copy, with the number of its group.
+    Csv,
+    /// A page to review the groups and select copies in a browser.
+    Html,
+}
+
+
+impl ReportFormat {
+    pub fn parse(name: &str) -> Result<ReportFormat> {
+        match name {
+            "json" => Ok(ReportFormat::Json),
+            "ndjson" => Ok(ReportFormat::Ndjson),
+            "csv" => Ok(ReportFormat::Csv),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(Error::Parse(format!("unknown report format '{}'", name))),
+        }
+    }
+}
+
+
+/// A volume the records of a report come from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScanRoot {
+    pub volume: String,
+    pub root: Option<String>,
+    pub algorithm: String,
+}
+
+
+impl ScanRoot {
+    pub fn of(volume: &Volume) -> ScanRoot {
+        ScanRoot {
+            volume: volume.label.clone(),
+            root: Some(volume.root.clone()),
+            algorithm: String::from(volume.algorithm.as_str()),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReportHeader {
+    pub schema: String,
+    pub version: u32,
+    pub tool_version: String,
+    pub hash_algorithms: Vec<String>,
+    pub roots: Vec<ScanRoot>,
+    pub generated: DateTime<Utc>,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupKind {
+    File,
+    Directory,
+}
+
+
+/// One of the copies of a group.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CopyPath {
+    pub volume: String,
+    pub path: String,
+    pub modified: Option<DateTime<Utc>>,
+    pub record: Option<u32>,
+}
+
+
+impl CopyPath {
+    /// Where the copy lives, as `volume:path`.
+    pub fn location(&self) -> String {
+        format!("{}:{}", self.volume, self.path)
+    }
+}
+
+
+/// Files or directories sharing a checksum.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DuplicateGroup {
+    pub checksum: String,
+    pub kind: GroupKind,
+    pub size: u64,
+    pub files: usize,
+    pub reclaimable: u64,
+    pub paths: Vec<CopyPath>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DuplicateReport {
+    pub header: ReportHeader,
+    pub groups: Vec<DuplicateGroup>,
+}
+
+
+/// A line of an NDJSON report.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ReportLine<'a> {
+    Header(&'a ReportHeader),
+    Group(&'a DuplicateGroup),
+}
+
+
+/// The whole index, as every report of its duplicates needs it.
+pub struct IndexContents {
+    pub graph: GraphStorage,
+    pub records: Vec<IndexRecord>,
+    pub roots: Vec<ScanRoot>,
+}
+
+
+/// Loads the directory tree of the index with the records and volumes behind it.
+pub fn load_index<T: IndexStorage>(storage: &T) -> Result<IndexContents> {
+    let graph = analyser::load_graph(storage)?;
+    let records = storage.fetch_sorted()?;
+    let roots = scan_roots(storage, &records)?;
+
+    Ok(IndexContents { graph, records, roots })
+}
+
+
+/// The volumes `records` come from. Volumes missing from `storage`, scanned
+/// without being stored, get their algorithm from the length of their checksums.
+pub fn scan_roots<T: IndexStorage>(storage: &T, records: &[IndexRecord]) -> Result<Vec<ScanRoot>> {
+    let mut res = Vec::<ScanRoot>::new();
+    for record in records {
+        if res.iter().any(|root| root.volume == record.volume) {
+            continue;
+        }
+
+        res.push(match storage.volume(&record.volume)? {
+            Some(volume) => ScanRoot::of(&volume),
+            None => ScanRoot {
+                volume: record.volume.clone(),
+                root: None,
+                algorithm: String::from(HashAlgorithm::of_digest(&record.checksum).unwrap_or_default().as_str()),
+            },
+        });
+    }
+    res.sort_by(|a, b| a.volume.cmp(&b.volume));
+
+    Ok(res)
+}
+
+
+/// Size, number of files and latest change of everything below a node.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Totals {
+    pub(crate) size: u64,
+    pub(crate) files: usize,
+    pub(crate) modified: Option<DateTime<Utc>>,
+}
+
+
+pub(crate) fn node_totals(graph: &GraphStorage, records: &HashMap<String, &IndexRecord>) -> HashMap<NodeIndex, Totals> {
+    let mut order = Vec::new();
+    let mut pending = vec![(graph.root, Vec::<String>::new())];
+    while let Some((node, path)) = pending.pop() {
+        for child in graph.graph.neighbors(node) {
+            let mut sub_path = path.clone();
+            sub_path.push(String::from(graph.graph[child].name()));
+            pending.push((child, sub_path));
+        }
+        order.push((node, path));
+    }
+
+    let mut res = HashMap::<NodeIndex, Totals>::new();
+    // children come after their parents, so backwards they are done first.
+    for (node, path) in order.into_iter().rev() {
+        let totals = match &graph.graph[node] {
+            GNode::FileLeaf {..} => {
+                let dir = path[1..path.len() - 1].join("/");
+                let record = records.get(&location(&path[0], &dir, &path[path.len() - 1]));
+                Totals {
+                    size: record.map(|r| r.size).unwrap_or_default(),
+                    files: 1,
+                    modified: record.map(|r| r.modified),
+                }
+            },
+            GNode::DirNode {..} => graph.graph.neighbors(node).fold(Totals::default(), |acc, child| {
+                let child = &res[&child];
+                Totals {
+                    size: acc.size + child.size,
+                    files: acc.files + child.files,
+                    modified: acc.modified.max(child.modified),
+                }
+            }),
+        };
+        res.insert(node, totals);
+    }
+
+    res
+}
+
+
+/// Builds the report of the duplicates in `graph`, whose files are described
+/// by `records` and come from `roots`.
+pub fn build_report(graph: &GraphStorage, records: &[IndexRecord], roots: Vec<ScanRoot>) -> DuplicateReport {
+    let by_location: HashMap<String, &IndexRecord> = records.iter().map(|r| (r.location(), r)).collect();
+    let totals = node_totals(graph, &by_location);
+
+    let mut groups: Vec<DuplicateGroup> = graph.duplicate_nodes().into_iter().map(|(checksum, nodes)| {
+        let (kind, first) = match graph.graph[nodes[0].0] {
+            GNode::FileLeaf {..} => (GroupKind::File, &totals[&nodes[0].0]),
+            GNode::DirNode {..} => (GroupKind::Directory, &totals[&nodes[0].0]),
+        };
+
+        let mut paths: Vec<CopyPath> = nodes.iter().map(|(node, path)| {
+            let relative = path[1..].join("/");
+            CopyPath {
+                volume: path[0].clone(),
+                path: if kind == GroupKind::Directory { format!("{}/", relative) } else { relative },
+                modified: totals[node].modified,
+                record: graph.graph[*node].record_id(),
+            }
+        }).collect();
+        paths.sort_by(|a, b| (&a.volume, &a.path).cmp(&(&b.volume, &b.path)));
+
+        DuplicateGroup {
+            checksum,
+            kind,
+            size: first.size,
+            files: first.files,
+            reclaimable: first.size * (paths.len() as u64 - 1),
+            paths,
+        }
+    }).collect();
+    groups.sort_by(|a, b| b.reclaimable.cmp(&a.reclaimable).then_with(|| a.checksum.cmp(&b.checksum)));
+
+    let mut hash_algorithms: Vec<String> = roots.iter().map(|root| root.algorithm.clone()).collect();
+    hash_algorithms.sort();
+    hash_algorithms.dedup();
+
+    DuplicateReport {
+        header: ReportHeader {
+            schema: String::from(SCHEMA),
+            version: SCHEMA_VERSION,
+            tool_version: String::from(env!("CARGO_PKG_VERSION")),
+            hash_algorithms,
+            roots,
+            generated: Utc::now(),
+        },
+        groups,
+    }
+}
+
+
+const CSV_HEADER: &str = "group,checksum,kind,size,files,reclaimable,volume,path,absolute_path,modified,record";
+
+const HTML_TEMPLATE: &str = include_str!("report.html");
+
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        String::from(value)
+    }
+}
+
+
+fn write_csv<W: Write>(report: &DuplicateReport, out: &mut W) -> Result<()> {
+    let roots: HashMap<&str, &str> = report.header.roots.iter()
+        .filter_map(|root| root.root.as_deref().map(|path| (root.volume.as_str(), path)))
+        .collect();
+
+    writeln!(out, "{}", CSV_HEADER)?;
+    for (i, group) in report.groups.iter().enumerate() {
+        let kind = match group.kind {
+            GroupKind::File => "file",
+            GroupKind::Directory => "directory",
+        };
+        for copy in &group.paths {
+            let absolute = match roots.get(copy.volume.as_str()) {
+                Some(root) => format!("{}/{}", root.trim_end_matches('/'), copy.path),
+                None => String::new(),
+            };
+            let row = [
+                (i + 1).to_string(),
+                group.checksum.clone(),
+                String::from(kind),
+                group.size.to_string(),
+                group.files.to_string(),
+                group.reclaimable.to_string(),
+                copy.volume.clone(),
+                copy.path.clone(),
+                absolute,
+                copy.modified.map(|m| m.to_rfc3339()).unwrap_or_default(),
+                copy.record.map(|r| r.to_string()).unwrap_or_default(),
+            ];
+            let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+            writeln!(out, "{}", row.join(","))?;
+        }
+    }
+
+    Ok(())
+}
+
+
+fn write_html<W: Write>(report: &DuplicateReport, out: &mut W) -> Result<()> {
+    // nothing in the data may close the script element holding it.
+    let data = serde_json::to_string(report)?.replace('<', "\\u003c");
+    out.write_all(HTML_TEMPLATE.replace("{{REPORT}}", &data).as_bytes())?;
+
+    Ok(())
+}
+
+
+/// Reads the locations of a selection file, as exported from the HTML report.
+pub fn read_selection<R: BufRead>(input: R) -> Result<Vec<String>> {
+    let mut res = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !line.contains(':') {
+            return Err(Error::Parse(format!("'{}' is not a volume:path location", line)));
+        }
+        res.push(String::from(line));
+    }
+
+    Ok(res)
+}
+
+
+/// Writes `report` in `format`, returning the number of groups written.
+pub fn write_report<W: Write>(report: &DuplicateReport, format: ReportFormat, mut out: W) -> Result<usize> {
+    match format {
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, report)?;
+            writeln!(out)?;
+        },
+        ReportFormat::Ndjson => {
+            serde_json::to_writer(&mut out, &ReportLine::Header(&report.header))?;
+            writeln!(out)?;
+            for group in &report.groups {
+                serde_json::to_writer(&mut out, &ReportLine::Group(group))?;
+                writeln!(out)?;
+            }
+        },
+        ReportFormat::Csv => write_csv(report, &mut out)?,
+        ReportFormat::Html => write_html(report, &mut out)?,
+    }
+    out.flush()?;
+
+    Ok(report.groups.len())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::analyser::initialise_graph;
+    use crate::index_db;
+    use crate::misc;
+
+    fn record(id: u32, path: &str, name: &str, checksum: &str, size: u64) -> IndexRecord {
+        IndexRecord {
+            id,
+            checksum: String::from(checksum),
+            name: String::from(name),
+            volume: String::from("nas"),
+            path: String::from(path),
+            modified: Utc::now(),
+            size,
+            inode: None,
+        }
+    }
+
+    #[test]
+    fn test_build_and_write_report() {
+        let records = vec![
+            record(1, "backup/photos", "a.jpg", "1", 100),
+            record(2, "backup/photos", "b.jpg", "2", 50),
+            record(3, "docs", "a.txt", "3", 10),
+            record(4, "docs", "copy.txt", "3", 10),
+            record(5, "photos", "a.jpg", "1", 100),
+            record(6, "photos", "b.jpg", "2", 50),
+        ];
+        let mut graph = initialise_graph();
+        graph.bulk_insert(records.iter().map(misc::to_file_record).collect()).unwrap();
+
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        let roots = scan_roots(&storage, &records).unwrap();
+        assert_eq!(roots, vec![ScanRoot { volume: String::from("nas"), root: None, algorithm: String::from("md5") }]);
+
+        let report = build_report(&graph, &records, roots);
+        assert_eq!(report.header.version, SCHEMA_VERSION);
+        assert_eq!(report.groups.len(), 2);
+
+        let photos = &report.groups[0];
+        assert_eq!((photos.kind, photos.size, photos.files, photos.reclaimable), (GroupKind::Directory, 150, 2, 150));
+        assert_eq!(photos.paths.iter().map(|p| p.location()).collect::<Vec<String>>(), vec!["nas:backup/photos/", "nas:photos/"]);
+
+        let text = &report.groups[1];
+        assert_eq!((text.kind, text.reclaimable), (GroupKind::File, 10));
+        assert_eq!(text.paths[1].record, Some(4));
+
+        let mut out = Vec::new();
+        write_report(&report, ReportFormat::Json, &mut out).unwrap();
+        assert_eq!(serde_json::from_slice::<DuplicateReport>(&out).unwrap(), report);
+
+        let mut out = Vec::new();
+        write_report(&report, ReportFormat::Ndjson, &mut out).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!((lines[0]["type"].as_str(), lines[0]["schema"].as_str()), (Some("header"), Some(SCHEMA)));
+        assert_eq!((lines[1]["type"].as_str(), lines[1]["kind"].as_str()), (Some("group"), Some("directory")));
+    }
+
+    #[test]
+    fn test_review_formats() {
+        let records = vec![
+            record(1, "a", "x, \"quoted\".txt", "1", 10),
+            record(2, "a", "<script>.txt", "1", 10),
+        ];
+        let mut graph = initialise_graph();
+        graph.bulk_insert(records.iter().map(misc::to_file_record).collect()).unwrap();
+        let roots = vec![ScanRoot { volume: String::from("nas"), root: Some(String::from("/mnt/nas/")), algorithm: String::from("md5") }];
+        let report = build_report(&graph, &records, roots);
+
+        let mut out = Vec::new();
+        write_report(&report, ReportFormat::Csv, &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows.len(), 3);
+        assert!(rows[1].contains(",nas,a/<script>.txt,/mnt/nas/a/<script>.txt,"));
+        assert!(rows[2].starts_with("1,1,file,10,1,10,nas,\"a/x, \"\"quoted\"\".txt\",\"/mnt/nas/a/x, \"\"quoted\"\".txt\","));
+
+        let mut out = Vec::new();
+        write_report(&report, ReportFormat::Html, &mut out).unwrap();
+        let html = String::from_utf8(out).unwrap();
+        assert!(!html.contains("{{REPORT}}") && !html.contains("<script>.txt"));
+        assert!(html.contains("\\u003cscript>.txt"));
+
+        let selection = "# rusty-index selection\nnas:a/<script>.txt\n\nnas:a/\n";
+        assert_eq!(read_selection(selection.as_bytes()).unwrap(), vec!["nas:a/<script>.txt", "nas:a/"]);
+        assert!(read_selection("no location\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_load_index() {
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        storage.register_volume("nas", None, "/mnt/nas", None).unwrap();
+        storage.insert(&[record(0, "a", "x", "1", 10), record(0, "b", "x", "1", 10)]).unwrap();
+
+        let index = load_index(&storage).unwrap();
+        assert_eq!(index.records.len(), 2);
+        assert_eq!(index.roots[0].root, Some(String::from("/mnt/nas")));
+        assert_eq!(build_report(&index.graph, &index.records, index.roots).groups.len(), 1);
+    }
+}
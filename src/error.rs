@@ -0,0 +1,106 @@
+//! Errors shared by the whole crate.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+extern crate chrono;
+
+extern crate rusqlite;
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing files, directories or streams failed.
+    Io(io::Error),
+    /// The index database could not be opened, queried or updated.
+    Database(rusqlite::Error),
+    /// Stored or provided data is not in the expected format.
+    Parse(String),
+    /// A path can't be represented as UTF-8, which the index requires.
+    PathEncoding(PathBuf),
+    /// The directory tree is not in a consistent state.
+    Graph(String),
+    /// Checksums made with different hash algorithms can't be compared.
+    Algorithm(String),
+    /// Verification found this many damaged, missing or unreadable files.
+    Damaged(usize),
+    /// The user stopped the run before it finished.
+    Interrupted,
+}
+
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+
+impl Error {
+    /// Process exit code for the class of the error, so scripts can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 3,
+            Error::Database(_) => 4,
+            Error::Parse(_) => 5,
+            Error::PathEncoding(_) => 6,
+            Error::Graph(_) => 7,
+            Error::Algorithm(_) => 8,
+            Error::Damaged(_) => 1,
+            Error::Interrupted => 130,
+        }
+    }
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Database(e) => write!(f, "Database error: {}", e),
+            Error::Parse(msg) => write!(f, "Parse error: {}", msg),
+            Error::PathEncoding(path) => write!(f, "Path is not valid UTF-8: {:?}", path),
+            Error::Graph(msg) => write!(f, "Inconsistent directory tree: {}", msg),
+            Error::Algorithm(msg) => write!(f, "Hash algorithm mismatch: {}", msg),
+            Error::Damaged(count) => write!(f, "{} files failed verification", count),
+            Error::Interrupted => write!(f, "Interrupted"),
+        }
+    }
+}
+
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Database(e)
+    }
+}
+
+
+impl From<chrono::ParseError> for Error {
+    fn from(e: chrono::ParseError) -> Error {
+        Error::Parse(format!("invalid date: {}", e))
+    }
+}
+
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Parse(format!("invalid JSON: {}", e))
+    }
+}
@@ -1,10 +1,18 @@
+//! Scanning of directories and file lists, and hashing of their contents.
+
+use std::collections::{HashMap, HashSet};
 use std::fs::{metadata, read_dir, File};
 use std::io::prelude::*;
-use std::io::{stdin, Result};
-use std::path::Component::{Normal, RootDir};
+use std::io::BufRead;
 use std::path::{Path, PathBuf};
 use std::env;
-use std::sync::mpsc::channel;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::SystemTime;
 
 extern crate chrono;
@@ -18,29 +26,96 @@ use threadpool::ThreadPool;
 extern crate crypto;
 use crypto::digest::Digest;
 use crypto::md5::Md5;
+use crypto::sha2::Sha256;
 
-use crate::analyser;
+use crate::error::{Error, Result};
 use crate::index_db;
+use index_db::IndexStorage;
+
+use crate::progress::{ProgressEvent, ProgressSender};
 
 use crate::misc;
-use misc::get_name_and_split_path;
-use misc::process_file_paths;
+use misc::path_to_str;
+
+use crate::volumes;
 
 const BUFFER_SIZE: usize = 1024;
+const QUEUE_SIZE: usize = 1024;
+const BATCH_SIZE: usize = 1000;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static ABORTED: AtomicBool = AtomicBool::new(false);
+
+
+pub fn get_current_dir() -> Result<String> {
+    let path = env::current_dir()?;
+    Ok(String::from(path_to_str(&path)?))
+}
+
+
+/// Hash function the checksums of a volume are computed with. Their digests
+/// differ in length, so checksums of different algorithms never match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Md5,
+    Sha256,
+}
 
 
-pub fn get_current_dir() -> String {
-    let path = env::current_dir().expect("Couldn't get current dir ...");
-    String::from(path.to_str().expect("Couldn't transform current dir into string ..."))
+impl HashAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<HashAlgorithm> {
+        match name.to_lowercase().as_str() {
+            "md5" => Ok(HashAlgorithm::Md5),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => Err(Error::Parse(format!("unknown hash algorithm '{}'", name))),
+        }
+    }
+
+    /// The algorithm giving hexadecimal digests such as `digest`, if any.
+    pub fn of_digest(digest: &str) -> Option<HashAlgorithm> {
+        if !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        match digest.len() {
+            32 => Some(HashAlgorithm::Md5),
+            64 => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    fn hasher(self) -> Box<dyn Digest> {
+        match self {
+            HashAlgorithm::Md5 => Box::new(Md5::new()),
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+        }
+    }
 }
 
 
 pub fn hash_file<T: AsRef<Path>>(file_path: &T) -> Result<String> {
-    let mut file = File::open(&file_path)?;
+    hash_file_with(file_path, HashAlgorithm::Md5)
+}
+
+
+pub fn hash_file_with<T: AsRef<Path>>(file_path: &T, algorithm: HashAlgorithm) -> Result<String> {
+    let mut file = File::open(file_path)?;
 
-    let mut hasher = Md5::new();
+    let mut hasher = algorithm.hasher();
     let mut buffer = [0u8; BUFFER_SIZE];
     loop {
+        if is_aborted() {
+            return Err(Error::Interrupted);
+        }
+
         let n = file.read(&mut buffer)?;
         hasher.input(&buffer[..n]);
 
@@ -49,262 +124,771 @@ pub fn hash_file<T: AsRef<Path>>(file_path: &T) -> Result<String> {
         }
     }
 
-    Ok(String::from(hasher.result_str()))
+    Ok(hasher.result_str())
 }
 
 
-fn load_files_from_stdin() -> Vec<String> {
-    let mut files = Vec::<String>::new();
-    loop {
-        let mut input = String::new();
+/// Builds the storage record for a single file of the volume mounted at `root`,
+/// hashing its contents.
+fn path_to_index_record(file: &Path, root: &Path, volume: &str, algorithm: HashAlgorithm) -> Result<index_db::IndexRecord> {
+    let file_name = match file.file_name() {
+        Some(name) => name.to_str().ok_or_else(|| Error::PathEncoding(file.to_path_buf()))?,
+        None => return Err(Error::Parse(format!("{:?} does not name a file", file))),
+    };
+    let path = match file.parent().unwrap_or_else(|| Path::new("")).strip_prefix(root) {
+        Ok(relative) => path_to_str(relative)?,
+        Err(_) => return Err(Error::Parse(format!("{:?} is not under the volume root {:?}", file, root))),
+    };
+    let file_hash = hash_file_with(&file, algorithm)?;
+    let m_data = metadata(file)?;
+    let timestamp = match m_data.modified() {
+        Ok(time) => time,
+        Err(_e) => SystemTime::now(),
+    };
+
+    let modified: DateTime<Utc> = timestamp.into();
+    Ok(index_db::IndexRecord {
+        id: 0,
+        checksum: file_hash,
+        name: String::from(file_name),
+        volume: String::from(volume),
+        path: String::from(path),
+        modified,
+        size: m_data.len(),
+        inode: Some(m_data.ino()),
+    })
+}
+
+
+/// Where the files of a scan come from.
+pub enum ScanSource {
+    /// Every file under the directory, walked recursively.
+    Directory(String),
+    /// A list of file paths separated by the given delimiter, relative to the
+    /// current directory unless absolute.
+    List(Box<dyn BufRead + Send>, Delimiter),
+}
 
-        stdin()
-            .read_line(&mut input)
-            .expect("failed to read from pipe");
-        input = input.trim().to_string();
-        if input == "" {
-            break;
-        }
 
-        files.push(input);
+/// What separates the paths of a file list.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delimiter {
+    /// One path per line, as printed by `find`.
+    Newline,
+    /// NUL terminated paths, as printed by `find -print0`, the only way to
+    /// pass names containing newlines.
+    Nul,
+}
+
+
+impl Delimiter {
+    fn byte(self) -> u8 {
+        match self {
+            Delimiter::Newline => b'\n',
+            Delimiter::Nul => 0,
+        }
     }
+}
+
 
-    return files;
+/// How a scan treats the records it produces.
+pub struct ScanOptions {
+    /// Save the records into the index.
+    pub store: bool,
+    /// Continue the last interrupted scan of the same directory.
+    pub resume: bool,
+    /// Label of the volume scanned, defaults to the absolute path of its root.
+    /// The root is the scanned directory. File lists are rooted at the current
+    /// directory when labelled, and at `/` otherwise.
+    pub volume: Option<String>,
+    /// Filesystem UUID of the volume, if known.
+    pub uuid: Option<String>,
+    /// Hash function of the volume, defaults to the one it was first scanned
+    /// with, or MD5 for a new volume.
+    pub algorithm: Option<HashAlgorithm>,
+    pub progress: ProgressSender,
 }
 
 
-fn process_into_file_records(file_list: Vec<String>) -> Vec<analyser::FileRecord> {
-    let n_cpus = num_cpus::get();
-    let pool = ThreadPool::new(n_cpus);
-    println!("Running with {} threads ...", n_cpus);
+impl Default for ScanOptions {
+    fn default() -> ScanOptions {
+        ScanOptions {
+            store: true,
+            resume: false,
+            volume: None,
+            uuid: None,
+            algorithm: None,
+            progress: ProgressSender::none(),
+        }
+    }
+}
 
-    let (tx, rx) = channel();
 
-    for file in file_list {
-        let tx = tx.clone();
+/// What the feeder and the hashing workers report back to the thread storing records.
+enum ScanEvent {
+    /// A file under the given directory went through.
+    Record(PathBuf, index_db::IndexRecord),
+    /// A file under the given directory that could not be processed.
+    Skipped(PathBuf),
+    /// The directory has been listed and this many files were queued from it.
+    Enumerated(PathBuf, usize),
+    /// A worker had to stop, failing the scan.
+    Failed(Error),
+}
 
-        pool.execute(move || {
-            let file_hash = hash_file(&file).unwrap();
-            // println!("processing {} ...", file);
 
-            let (path, file_name) = get_name_and_split_path(&file);
-            let metadata = match metadata(&file) {
-                Ok(m_tada) => m_tada,
-                Err(e) => panic!("Can't get metadata for file {:?}; {:?}", &file, e),
-            };
-            let timestamp = match metadata.modified() {
-                Ok(time) => time,
-                Err(_e) => SystemTime::now(),
-            };
+/// Files of a single directory that are still going through the pipeline.
+#[derive(Default)]
+struct DirectoryProgress {
+    expected: Option<usize>,
+    processed: usize,
+}
 
-            let modified: DateTime<Utc> = timestamp.into();
-            let new_record = analyser::FileRecord {
-                checksum: file_hash,
-                name: String::from(file_name),
-                path,
-                modified,
-            };
 
-            tx.send(new_record).expect("Could not send data!");
-        })
+impl DirectoryProgress {
+    fn is_complete(&self) -> bool {
+        self.expected == Some(self.processed)
     }
+}
 
-    println!("Finished spanning. Dropping connection ...");
-    drop(tx);
 
-    let mut records = Vec::<analyser::FileRecord>::new();
-    for r in rx.iter() {
-        records.push(r);
+/// Counts the files of each directory going through the pipeline, to tell
+/// when a directory is done and its files no longer need to be listed.
+#[derive(Default)]
+struct DirectoryTracker {
+    progress: HashMap<PathBuf, DirectoryProgress>,
+}
+
+
+impl DirectoryTracker {
+    /// Accounts for `event`, returning the directory it completes.
+    fn update(&mut self, event: &ScanEvent) -> Option<PathBuf> {
+        let dir = match event {
+            ScanEvent::Record(dir, _) | ScanEvent::Skipped(dir) => {
+                self.progress.entry(dir.clone()).or_default().processed += 1;
+                dir
+            },
+            ScanEvent::Enumerated(dir, count) => {
+                self.progress.entry(dir.clone()).or_default().expected = Some(*count);
+                dir
+            },
+            ScanEvent::Failed(_) => return None,
+        };
+
+        if !self.progress[dir].is_complete() {
+            return None;
+        }
+
+        self.progress.remove(dir)?;
+        Some(dir.clone())
     }
+}
 
-    return records;
+
+/// Asks any running scan to stop, keeping everything processed so far.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
 }
 
 
-pub fn load_and_process_files() -> Vec<analyser::FileRecord> {
-    let raw_files = load_files_from_stdin();
-    let files = process_file_paths(raw_files);
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
 
-    println!("Processing {} files ...", files.len());
-    return process_into_file_records(files);
+/// Stops any running scan right away, dropping the files still being hashed.
+pub fn abort() {
+    interrupt();
+    ABORTED.store(true, Ordering::SeqCst);
 }
 
 
-fn process_file(path: &Path) -> analyser::FileRecord {
-    let file_hash = hash_file(&path).unwrap();
-    let file_name = path
-        .file_name()
-        .expect("Could not get file name from path.")
-        .to_str()
-        .expect("Cloud not convert file name to string");
-    let str_path = path
-        .parent()
-        .expect("Cloud not extract the lain path from the file path.")
-        .components()
-        .map(|x| match x {
-            Normal(val) => String::from(
-                val.to_str()
-                    .expect("Could not convert path component into string"),
-            ),
-            RootDir => String::from(""),
-            _ => panic!("Could not process path component."),
-        })
-        .collect();
-
-    let metadata = match path.metadata() {
-        Ok(m_tada) => m_tada,
-        Err(e) => panic!("Can't get metadata for file {:?}; {:?}", &file_name, e),
-    };
-    let timestamp = match metadata.modified() {
-        Ok(time) => time,
-        Err(_e) => SystemTime::now(),
-    };
-    let modified: DateTime<Utc> = timestamp.into();
-    
-    analyser::FileRecord {
-        checksum: file_hash,
-        name: String::from(file_name),
-        path: str_path,
-        modified,
-    }
+pub fn is_aborted() -> bool {
+    ABORTED.load(Ordering::SeqCst)
 }
 
 
-fn process_directory(path:& Path) -> Vec<analyser::FileRecord> {
-    let str_path = match path.to_str() {
-	Some(s) => String::from(s),
-	None => panic!("Emptt path."),
-    };
-    let entries = match read_dir(path) {
-	Ok(x) => x,
-	Err(e) => panic!("Could not read directory {}: {}", str_path, e),
-    };
+/// Queues `item` on `tx`, waiting while the queue is full. Gives up and returns
+/// false once the scan is interrupted or nobody is left to receive it: interrupted
+/// workers stop taking files and drop the queue, which wakes a waiting feeder.
+fn feed<T>(tx: &SyncSender<T>, item: T) -> bool {
+    !is_interrupted() && tx.send(item).is_ok()
+}
 
-    let mut results = Vec::new();
-    for entry in entries {
-	let sub_path = match entry {
-	    Ok(dir_entry) => dir_entry.path(),
-	    Err(e) => panic!("Cannot get path for entries under {}: {}", str_path, e),
-	};
-
-	if sub_path.is_dir() {
-	    results.push(process_directory(&sub_path));
-	    
-	} else {
-	    results.push(vec![process_file(&sub_path)]);
-	}
-    }
 
-    results.concat()
+/// Absolute, normalised form of a path given on the command line, so the same
+/// directory always gives the same records whatever the current directory.
+fn normalise_path(path: &str, current_dir: &str) -> PathBuf {
+    Path::new(&misc::absolute_path(path, current_dir)).components().collect()
 }
 
 
-pub fn scan_directory(base_path: String) -> Vec<analyser::FileRecord> {
-    let path = Path::new(&base_path);
-    let mut results = Vec::new();
-    
-    if path.is_dir() {
-	results.push(process_directory(&path));
-    } else {
-        results.push(vec![process_file(&path)]);
+/// Walks the tree depth first and feeds every file found into `path_tx`,
+/// reporting each directory on `event_tx` once it has been fully listed.
+///
+/// Files of directories in `completed`, and the ones in `stored`, are not
+/// queued again, but sub-directories are still visited.
+fn walk_directory(
+    base_path: PathBuf,
+    completed: HashSet<PathBuf>,
+    stored: HashSet<PathBuf>,
+    path_tx: SyncSender<(PathBuf, u64)>,
+    event_tx: SyncSender<ScanEvent>,
+    progress: ProgressSender,
+) -> Result<()> {
+    if !base_path.is_dir() {
+        let parent = base_path.parent().map(PathBuf::from).unwrap_or_default();
+        let bytes = metadata(&base_path)?.len();
+        if !completed.contains(&parent) && !stored.contains(&base_path) && feed(&path_tx, (base_path, bytes)) {
+            progress.send(ProgressEvent::Queued { bytes });
+            let _ = event_tx.send(ScanEvent::Enumerated(parent, 1));
+        }
+        progress.send(ProgressEvent::EnumerationDone);
+        return Ok(());
     }
 
-    results.concat()
-}
+    let mut pending = vec![base_path];
+    while let Some(dir) = pending.pop() {
+        let entries = match read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Could not read directory {:?}: {}", dir, e);
+                continue;
+            }
+        };
+
+        let skip_files = completed.contains(&dir);
+        let mut count = 0;
+        for entry in entries {
+            if is_interrupted() {
+                return Ok(());
+            }
+
+            let sub_path = match entry {
+                Ok(dir_entry) => dir_entry.path(),
+                Err(e) => {
+                    eprintln!("Cannot get path for entries under {:?}: {}", dir, e);
+                    continue;
+                }
+            };
 
+            let bytes = match metadata(&sub_path) {
+                Ok(m_data) if m_data.is_dir() => {
+                    pending.push(sub_path);
+                    continue;
+                },
+                Ok(m_data) => m_data.len(),
+                Err(_) => 0,
+            };
 
-fn simple_process_directory(path:& Path) -> Vec<PathBuf> {
-    let str_path = match path.to_str() {
-	Some(s) => String::from(s),
-	None => panic!("Emptt path."),
-    };
-    let entries = match read_dir(path) {
-	Ok(x) => x,
-	Err(e) => panic!("Could not read directory {}: {}", str_path, e),
-    };
+            if !skip_files && !stored.contains(&sub_path) {
+                if !feed(&path_tx, (sub_path, bytes)) {
+                    // interrupted, or the consumers are gone.
+                    return Ok(());
+                }
+                progress.send(ProgressEvent::Queued { bytes });
+                count += 1;
+            }
+        }
 
-    let mut results = Vec::new();
-    for entry in entries {
-	let sub_path = match entry {
-	    Ok(dir_entry) => dir_entry.path(),
-	    Err(e) => panic!("Cannot get path for entries under {}: {}", str_path, e),
-	};
-
-	if sub_path.is_dir() {
-	    results.push(simple_process_directory(&sub_path));
-	    
-	} else {
-            results.push(vec![PathBuf::from(sub_path)]);
-	}
+        if !skip_files && event_tx.send(ScanEvent::Enumerated(dir, count)).is_err() {
+            return Ok(());
+        }
     }
 
-    results.concat()
+    progress.send(ProgressEvent::EnumerationDone);
+    Ok(())
 }
 
 
-pub fn simple_scan_directory(base_path: String) -> Vec<PathBuf> {
-    let path = Path::new(&base_path);
-    let mut results = Vec::new();
-    
-    if path.is_dir() {
-	results.push(simple_process_directory(&path));
-    } else {
-        results.push(vec![PathBuf::from(path)]);
+/// Path of a file list entry, taken byte for byte: only the delimiter is
+/// removed, so names with surrounding spaces or odd characters stay intact.
+fn list_entry_path(entry: &[u8], current_dir: &Path) -> PathBuf {
+    let path = Path::new(OsStr::from_bytes(entry));
+    current_dir.join(path).components().collect()
+}
+
+
+/// Feeds every path listed in `input` into `path_tx`, resolving relative paths
+/// against `current_dir`. Empty entries are ignored.
+fn read_file_list(
+    mut input: Box<dyn BufRead + Send>,
+    delimiter: Delimiter,
+    current_dir: String,
+    path_tx: SyncSender<(PathBuf, u64)>,
+    progress: ProgressSender,
+) -> Result<()> {
+    let current_dir = PathBuf::from(current_dir);
+    let mut entry = Vec::new();
+    loop {
+        if is_interrupted() {
+            return Ok(());
+        }
+
+        entry.clear();
+        if input.read_until(delimiter.byte(), &mut entry)? == 0 {
+            break;
+        }
+        if entry.last() == Some(&delimiter.byte()) {
+            entry.pop();
+        }
+        if entry.is_empty() {
+            continue;
+        }
+
+        let file = list_entry_path(&entry, &current_dir);
+        let bytes = metadata(&file).map(|m| m.len()).unwrap_or(0);
+        if !feed(&path_tx, (file, bytes)) {
+            return Ok(());
+        }
+        progress.send(ProgressEvent::Queued { bytes });
     }
 
-    results.concat()
+    progress.send(ProgressEvent::EnumerationDone);
+    Ok(())
 }
 
 
+/// Scans the files of `source`, passing every record produced to `on_record`
+/// and, unless told otherwise by `options`, storing them in `storage` as it goes.
+///
+/// Feeding, hashing and inserting run as a pipeline connected by bounded queues,
+/// so neither the list of paths nor the list of records is ever held in full.
+/// Records of a directory scan are committed in batches together with a
+/// checkpoint of the directories they complete, so an interrupted scan can
+/// later be resumed without hashing any of the files stored so far. Every file going through the hashing
+/// pool is reported on the progress sender. Returns the number of records produced.
+pub fn scan<T, F>(source: ScanSource, storage: &T, options: ScanOptions, mut on_record: F) -> Result<usize>
+where
+    T: IndexStorage,
+    F: FnMut(&index_db::IndexRecord),
+{
+    let current_dir = get_current_dir()?;
+    let ScanOptions { store, resume, volume, uuid, algorithm, progress } = options;
+
+    let (path_tx, path_rx) = sync_channel::<(PathBuf, u64)>(QUEUE_SIZE);
+    let (event_tx, event_rx) = sync_channel(QUEUE_SIZE);
+
+    let volume_root = match &source {
+        ScanSource::Directory(path) => {
+            let base_path = normalise_path(path, &current_dir);
+            // a missing root is an error of the whole scan, not a file to skip.
+            if metadata(&base_path)?.is_dir() {
+                base_path
+            } else {
+                base_path.parent().map(PathBuf::from).unwrap_or_default()
+            }
+        },
+        ScanSource::List(..) if volume.is_some() => PathBuf::from(&current_dir),
+        ScanSource::List(..) => PathBuf::from("/"),
+    };
+    let label = match volume {
+        Some(label) => label,
+        None => String::from(path_to_str(&volume_root)?),
+    };
+    let registered = if store {
+        // a list only indexes the files listed, whatever else is under its root.
+        if let ScanSource::Directory(_) = &source {
+            volumes::check_overlap(storage, &label, uuid.as_deref(), &volume_root)?;
+        }
+        Some(storage.register_volume(&label, uuid.as_deref(), path_to_str(&volume_root)?, algorithm)?)
+    } else {
+        None
+    };
+    let algorithm = match &registered {
+        Some(volume) => volume.algorithm,
+        None => algorithm.unwrap_or_default(),
+    };
+
+    let mut scan_id = None;
+    let feeder_progress = progress.clone();
+    let feeder = match source {
+        ScanSource::Directory(path) => {
+            let base_path = normalise_path(&path, &current_dir);
+
+            let mut completed = HashSet::new();
+            let mut stored = HashSet::new();
+            // only a scan of the whole volume is checkpointed, and can tell deleted files.
+            if let (Some(volume), true) = (&registered, base_path == volume_root) {
+                let root = path_to_str(&base_path)?;
+                scan_id = match storage.unfinished_scan(root)? {
+                    Some(id) if resume => {
+                        let done = storage.completed_directories(id)?;
+                        eprintln!("Resuming scan of {}, {} directories already done.", root, done.len());
+                        completed = done.into_iter().map(PathBuf::from).collect();
+                        stored = storage.partially_scanned_files(id)?.into_iter().map(PathBuf::from).collect();
+                        Some(id)
+                    },
+                    _ => Some(storage.start_scan(volume.id, root)?),
+                };
+            }
+
+            let walker_tx = event_tx.clone();
+            thread::spawn(move || {
+                walk_directory(base_path, completed, stored, path_tx, walker_tx, feeder_progress)
+            })
+        },
+        ScanSource::List(input, delimiter) => {
+            thread::spawn(move || read_file_list(input, delimiter, current_dir, path_tx, feeder_progress))
+        },
+    };
 
-pub fn path_to_file_record(file_list: Vec<PathBuf>) -> Vec<index_db::IndexRecord> {
     let n_cpus = num_cpus::get();
     let pool = ThreadPool::new(n_cpus);
-    println!("Running with {} threads ...", n_cpus);
-
-    let (tx, rx) = channel();
-
-    for file in file_list {
-        let tx = tx.clone();
-	
-        pool.execute(move || {
-            // println!("processing {:#?} ...", file);
-	    
-            let file_hash = hash_file(&file).unwrap();
-	    let file_name = file.file_name().expect("Could not get file name")
-		.to_str().expect("Could not transform file name into string");
-	    let path = file.parent().expect("Could not get path for")
-		.as_os_str().to_str().expect("Could not transform path into string");
-	    let metadata = match metadata(&file) {
-                Ok(m_tada) => m_tada,
-                Err(e) => panic!("Can't get metadata for file {:?}; {:?}", &file, e),
+    eprintln!("Running with {} threads ...", n_cpus);
+
+    let path_rx = Arc::new(Mutex::new(path_rx));
+    let volume = Arc::new((volume_root, label, algorithm));
+    for _ in 0..n_cpus {
+        let path_rx = path_rx.clone();
+        let volume = volume.clone();
+        let event_tx = event_tx.clone();
+        let progress = progress.clone();
+
+        pool.execute(move || loop {
+            if is_interrupted() {
+                break;
+            }
+
+            let next = match path_rx.lock() {
+                Ok(rx) => rx.recv(),
+                Err(_) => {
+                    let _ = event_tx.send(ScanEvent::Failed(Error::Io(std::io::Error::other("a scan worker panicked"))));
+                    break;
+                },
             };
-            let timestamp = match metadata.modified() {
-                Ok(time) => time,
-                Err(_e) => SystemTime::now(),
+            let (file, bytes) = match next {
+                Ok(entry) => entry,
+                Err(_) => break,
             };
 
-            let modified: DateTime<Utc> = timestamp.into();
-            let new_record = index_db::IndexRecord {
-		id: 0,
-                checksum: file_hash,
-                name: String::from(file_name),
-                path: String::from(path),
-                modified,
+            let dir = file.parent().map(PathBuf::from).unwrap_or_default();
+            let (root, label, algorithm) = &*volume;
+            let event = match path_to_index_record(&file, root, label, *algorithm) {
+                Ok(record) => ScanEvent::Record(dir.clone(), record),
+                Err(_) if is_aborted() => break,
+                Err(e) => {
+                    eprintln!("Skipping {:?}: {}", file, e);
+                    ScanEvent::Skipped(dir.clone())
+                },
             };
-	    // println!("Processed new entry {:#?}", new_record);
 
-            tx.send(new_record).expect("Could not send data!");
-        })
+            progress.send(ProgressEvent::Processed { bytes, dir });
+
+            if event_tx.send(event).is_err() {
+                break;
+            }
+        });
+    }
+    // only the workers may keep the queue open, or the feeder could wait on it forever.
+    drop(path_rx);
+    drop(event_tx);
+    drop(progress);
+
+    let mut total = 0;
+    let mut tracker = DirectoryTracker::default();
+    let mut directories = Vec::<String>::new();
+    let mut batch = Vec::<index_db::IndexRecord>::with_capacity(BATCH_SIZE);
+    let mut failure = None;
+    for event in event_rx.iter() {
+        if is_aborted() {
+            return Err(Error::Interrupted);
+        }
+
+        if let ScanEvent::Failed(e) = event {
+            // the other workers see the same poisoned queue and stop too.
+            failure.get_or_insert(e);
+            continue;
+        }
+
+        if let ScanEvent::Record(_, record) = &event {
+            on_record(record);
+            total += 1;
+        }
+
+        if !store {
+            continue;
+        }
+
+        // lists have no directories to checkpoint.
+        let done = match scan_id {
+            Some(_) => tracker.update(&event),
+            None => None,
+        };
+        if let ScanEvent::Record(_, record) = event {
+            batch.push(record);
+        }
+        if let Some(dir) = done {
+            directories.push(dir.to_string_lossy().into_owned());
+        }
+
+        if batch.len() >= BATCH_SIZE || directories.len() >= BATCH_SIZE {
+            flush(storage, scan_id, &mut directories, &mut batch)?;
+        }
     }
 
-    println!("Finished spanning. Dropping connection ...");
-    drop(tx);
+    if is_aborted() {
+        return Err(Error::Interrupted);
+    }
+    if store {
+        flush(storage, scan_id, &mut directories, &mut batch)?;
+    }
 
-    let mut records = Vec::<index_db::IndexRecord>::new();
-    for r in rx.iter() {
-        records.push(r);
+    match feeder.join() {
+        Ok(res) => res?,
+        Err(_) => return Err(Error::Io(std::io::Error::other("the scan feeder panicked"))),
+    }
+    if let Some(e) = failure {
+        return Err(e);
     }
 
-    return records;
+    if let Some(id) = scan_id {
+        if !is_interrupted() {
+            storage.finish_scan(id)?;
+        }
+    }
+
+    Ok(total)
+}
+
+
+fn flush<T: IndexStorage>(
+    storage: &T,
+    scan_id: Option<i64>,
+    directories: &mut Vec<String>,
+    batch: &mut Vec<index_db::IndexRecord>,
+) -> Result<()> {
+    match scan_id {
+        Some(id) => storage.checkpoint(id, directories, batch)?,
+        None => storage.insert(batch)?,
+    }
+
+    directories.clear();
+    batch.clear();
+    Ok(())
+}
+
+
+/// Scans `base_path` and stores every file found in `storage` as it goes,
+/// see [`scan`]. Returns the number of records stored.
+pub fn stream_scan_directory<T: IndexStorage>(
+    base_path: String,
+    storage: &T,
+    resume: bool,
+    progress: ProgressSender,
+) -> Result<usize> {
+    let options = ScanOptions { resume, progress, ..ScanOptions::default() };
+    scan(ScanSource::Directory(base_path), storage, options, |_| {})
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn absolute_test_data(path: &str) -> String {
+        normalise_path(path, &get_current_dir().unwrap()).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_stream_scan_directory() {
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+
+        let total = stream_scan_directory(String::from("test_data"), &storage, false, ProgressSender::none()).unwrap();
+        let records = storage.fetch_sorted().unwrap();
+
+        assert_eq!(total, 6);
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[0].volume, absolute_test_data("test_data"));
+        assert_eq!(records[0].path, "");
+        assert_eq!(records[0].name, "fb");
+        assert_eq!(storage.unfinished_scan(&absolute_test_data("test_data")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_resume_skips_stored_files() {
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+
+        let volume = storage.register_volume(&absolute_test_data("test_data"), None, &absolute_test_data("test_data"), None).unwrap();
+        let scan_id = storage.start_scan(volume.id, &absolute_test_data("test_data")).unwrap();
+        storage.checkpoint(scan_id, &[absolute_test_data("test_data/b")], &[]).unwrap();
+        // a file of a directory left half done is not hashed again either.
+        let root = PathBuf::from(absolute_test_data("test_data"));
+        let done = path_to_index_record(&root.join("c/fa"), &root, &volume.label, HashAlgorithm::Md5).unwrap();
+        storage.checkpoint(scan_id, &[], &[done]).unwrap();
+        assert_eq!(storage.partially_scanned_files(scan_id).unwrap(), vec![absolute_test_data("test_data/c/fa")]);
+
+        let total = stream_scan_directory(String::from("./test_data/"), &storage, true, ProgressSender::none()).unwrap();
+        let records = storage.fetch_sorted().unwrap();
+
+        assert_eq!(total, 3);
+        assert!(records.iter().all(|r| r.path != "b"));
+        assert_eq!(records.iter().filter(|r| r.path == "c").count(), 2);
+        assert_eq!(storage.unfinished_scan(&absolute_test_data("test_data")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_unfinished_rescans_stay_hidden() {
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        let root = absolute_test_data("test_data");
+
+        // an interrupted first scan is all there is to show.
+        let volume = storage.register_volume(&root, None, &root, None).unwrap();
+        let first = storage.start_scan(volume.id, &root).unwrap();
+        let done = path_to_index_record(Path::new(&format!("{}/fb", root)), Path::new(&root), &root, HashAlgorithm::Md5).unwrap();
+        storage.checkpoint(first, &[], std::slice::from_ref(&done)).unwrap();
+        assert_eq!(storage.fetch_sorted().unwrap().len(), 1);
+
+        stream_scan_directory(String::from("test_data"), &storage, false, ProgressSender::none()).unwrap();
+        let copies = storage.copies(&done.checksum).unwrap().len();
+        let rescan = storage.start_scan(volume.id, &root).unwrap();
+        storage.checkpoint(rescan, &[], std::slice::from_ref(&done)).unwrap();
+
+        assert_eq!(storage.fetch_sorted().unwrap().len(), 6);
+        assert_eq!(storage.copies(&done.checksum).unwrap().len(), copies);
+        assert_eq!(storage.count_records(&root).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_list_and_directory_give_the_same_records() {
+        let from_dir = index_db::initalise_db(":memory:").unwrap();
+        from_dir.create().unwrap();
+        stream_scan_directory(String::from("test_data/b"), &from_dir, false, ProgressSender::none()).unwrap();
+
+        let from_list = index_db::initalise_db(":memory:").unwrap();
+        from_list.create().unwrap();
+        let list = Box::new(Cursor::new("./test_data/b/fa\ntest_data/b/fb\n"));
+        scan(ScanSource::List(list, Delimiter::Newline), &from_list, ScanOptions::default(), |_| {}).unwrap();
+
+        // the directory is a volume of its own, while the list is rooted at /.
+        let dir_records = from_dir.fetch_sorted().unwrap();
+        let list_records = from_list.fetch_sorted().unwrap();
+        assert_eq!(dir_records.len(), 2);
+        for (d, l) in dir_records.iter().zip(list_records.iter()) {
+            assert_eq!((&d.name, &d.checksum), (&l.name, &l.checksum));
+            assert_eq!((d.path.as_str(), l.volume.as_str()), ("", "/"));
+            assert_eq!(format!("/{}", l.path), absolute_test_data("test_data/b"));
+        }
+    }
+
+    #[test]
+    fn test_rescanning_a_list_replaces_its_records() {
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        for _ in 0..2 {
+            let list = Box::new(Cursor::new("test_data/b/fa
+test_data/b/fb
+"));
+            scan(ScanSource::List(list, Delimiter::Newline), &storage, ScanOptions::default(), |_| {}).unwrap();
+        }
+        stream_scan_directory(String::from("test_data/c/fa"), &storage, false, ProgressSender::none()).unwrap();
+        stream_scan_directory(String::from("test_data/c/fa"), &storage, false, ProgressSender::none()).unwrap();
+
+        assert_eq!(storage.count_records("/").unwrap(), 2);
+        assert_eq!(storage.fetch_sorted().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_scan_without_storing() {
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+
+        let options = ScanOptions { store: false, ..ScanOptions::default() };
+        let mut seen = Vec::new();
+        let total = scan(ScanSource::Directory(String::from("test_data")), &storage, options, |r| {
+            seen.push(r.checksum.clone())
+        }).unwrap();
+
+        assert_eq!(total, 6);
+        assert_eq!(seen.len(), 6);
+        assert!(storage.fetch_sorted().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_file_lists_keep_every_byte() {
+        let dir = env::temp_dir().join(format!("rusty-index-list-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let odd_name = " odd\nname ";
+        std::fs::write(dir.join(odd_name), "content").unwrap();
+        std::fs::write(dir.join("plain"), "content").unwrap();
+
+        let mut nul_list = Vec::new();
+        for name in &[odd_name, "plain"] {
+            nul_list.extend_from_slice(dir.join(name).to_str().unwrap().as_bytes());
+            nul_list.push(0);
+        }
+        let mut names = Vec::new();
+        scan(ScanSource::List(Box::new(Cursor::new(nul_list)), Delimiter::Nul), &index_db::initalise_db(":memory:").unwrap(),
+             ScanOptions { store: false, ..ScanOptions::default() }, |r| names.push(r.name.clone())).unwrap();
+        names.sort();
+        assert_eq!(names, vec![String::from(odd_name), String::from("plain")]);
+
+        // a blank line in the middle of a list is skipped, not the end of it.
+        let line_list = format!("\n{}\n\n{}\n", dir.join(" spaced").display(), dir.join("plain").display());
+        std::fs::write(dir.join(" spaced"), "other").unwrap();
+        let mut names = Vec::new();
+        scan(ScanSource::List(Box::new(Cursor::new(line_list)), Delimiter::Newline), &index_db::initalise_db(":memory:").unwrap(),
+             ScanOptions { store: false, ..ScanOptions::default() }, |r| names.push(r.name.clone())).unwrap();
+        names.sort();
+        assert_eq!(names, vec![String::from(" spaced"), String::from("plain")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_volumes_keep_relative_paths() {
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+
+        let options = ScanOptions { volume: Some(String::from("nas")), uuid: Some(String::from("1234")), ..ScanOptions::default() };
+        scan(ScanSource::Directory(String::from("test_data")), &storage, options, |_| {}).unwrap();
+        // a part of a known volume is not indexed a second time.
+        let options = ScanOptions { volume: Some(String::from("backup")), ..ScanOptions::default() };
+        assert!(scan(ScanSource::Directory(String::from("test_data/b")), &storage, options, |_| {}).is_err());
+
+        let records = storage.fetch_sorted().unwrap();
+        assert_eq!(records.len(), 6);
+        assert!(records.iter().any(|r| r.location() == "nas:b/fa"));
+
+        let volumes = storage.volumes().unwrap();
+        assert_eq!(volumes.len(), 1);
+        assert_eq!(volumes[0].root, absolute_test_data("test_data"));
+
+        // the same label on another drive is refused.
+        assert!(storage.register_volume("nas", Some("5678"), "/elsewhere", None).is_err());
+        // so is another hash function.
+        assert!(storage.register_volume("nas", None, "/elsewhere", Some(HashAlgorithm::Sha256)).is_err());
+    }
+
+    #[test]
+    fn test_rescan_only_drops_files_of_its_volume() {
+        let dir = env::temp_dir().join(format!("rusty-index-rescan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("kept"), "kept").unwrap();
+        std::fs::write(dir.join("deleted"), "deleted").unwrap();
+
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        let disk = || ScanOptions { volume: Some(String::from("disk")), ..ScanOptions::default() };
+        let cold = ScanOptions { volume: Some(String::from("cold")), ..ScanOptions::default() };
+        scan(ScanSource::Directory(String::from(dir.to_str().unwrap())), &storage, disk(), |_| {}).unwrap();
+        scan(ScanSource::Directory(String::from("test_data/b")), &storage, cold, |_| {}).unwrap();
+
+        std::fs::remove_file(dir.join("deleted")).unwrap();
+        scan(ScanSource::Directory(String::from(dir.to_str().unwrap())), &storage, disk(), |_| {}).unwrap();
+
+        let locations: Vec<String> = storage.fetch_sorted().unwrap().iter().map(|r| r.location()).collect();
+        assert_eq!(locations, vec!["cold:fa", "cold:fb", "disk:kept"]);
+        assert_eq!(storage.copies(&hash_file(&"test_data/b/fa").unwrap()).unwrap().len(), 1);
+
+        let kinds: Vec<index_db::ChangeKind> = storage.history("disk", "", "deleted").unwrap().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, vec![index_db::ChangeKind::Added, index_db::ChangeKind::Removed]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,337 @@
+//! Export of the directory tree for graph tools: Graphviz through DOT, and
+//! Gephi or Cytoscape through GraphML and Cytoscape JSON.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+extern crate petgraph;
+use petgraph::graph::NodeIndex;
+
+extern crate serde_json;
+use serde_json::json;
+
+use crate::analyser::{GNode, GraphStorage};
+use crate::error::{Error, Result};
+use crate::misc::path_to_components;
+
+
+/// Colours given to shared checksums in turn, the ColorBrewer Set3 palette.
+const PALETTE: [&str; 12] = [
+    "#8dd3c7", "#ffffb3", "#bebada", "#fb8072", "#80b1d3", "#fdb462",
+    "#b3de69", "#fccde5", "#d9d9d9", "#bc80bd", "#ccebc5", "#ffed6f",
+];
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum GraphFormat {
+    #[default]
+    Dot,
+    GraphMl,
+    Cytoscape,
+}
+
+
+impl GraphFormat {
+    pub fn parse(name: &str) -> Result<GraphFormat> {
+        match name {
+            "dot" => Ok(GraphFormat::Dot),
+            "graphml" => Ok(GraphFormat::GraphMl),
+            "cytoscape" => Ok(GraphFormat::Cytoscape),
+            _ => Err(Error::Parse(format!("unknown graph format '{}'", name))),
+        }
+    }
+}
+
+
+#[derive(Debug, Default)]
+pub struct GraphExportOptions {
+    pub format: GraphFormat,
+    /// Levels exported below the root of the export, all of them when `None`.
+    pub max_depth: Option<usize>,
+    /// Leave out the branches holding no duplicated file or directory.
+    pub duplicates_only: bool,
+    /// Path of the directory to export, starting with the volume label. The
+    /// whole tree when empty.
+    pub root: Vec<String>,
+}
+
+
+/// Path in the tree of a directory given as `volume` or `volume:path/to/dir`.
+pub fn parse_root(location: &str) -> Vec<String> {
+    let (volume, relative) = location.split_once(':').unwrap_or((location, ""));
+    let mut res = vec![String::from(volume)];
+    res.extend(path_to_components(relative));
+    res
+}
+
+
+/// A node picked for the export.
+struct ExportNode {
+    index: NodeIndex,
+    parent: Option<NodeIndex>,
+    label: String,
+    kind: &'static str,
+    checksum: String,
+    /// Shared by every node of the same checksum, when it is duplicated.
+    colour: Option<&'static str>,
+    record: Option<u32>,
+}
+
+
+/// Whether `node` or anything below it is duplicated, memoised in `bearing`.
+fn bears_duplicates(
+    graph: &GraphStorage,
+    node: NodeIndex,
+    counts: &HashMap<&str, usize>,
+    bearing: &mut HashMap<NodeIndex, bool>,
+) -> bool {
+    if let Some(res) = bearing.get(&node) {
+        return *res;
+    }
+
+    let mut res = counts.get(graph.graph[node].checksum()).is_some_and(|count| *count > 1);
+    for child in graph.graph.neighbors(node) {
+        // every child is visited, so all of them are memoised.
+        res |= bears_duplicates(graph, child, counts, bearing);
+    }
+
+    bearing.insert(node, res);
+    res
+}
+
+
+/// Picks the nodes to export, parents before their entries.
+fn select(graph: &GraphStorage, options: &GraphExportOptions) -> Result<Vec<ExportNode>> {
+    let root = graph.lookup(&options.root)
+        .ok_or_else(|| Error::Graph(format!("{} is not in the tree", options.root.join("/"))))?;
+
+    let mut counts = HashMap::<&str, usize>::new();
+    for node in graph.graph.node_indices() {
+        *counts.entry(graph.graph[node].checksum()).or_default() += 1;
+    }
+    let mut bearing = HashMap::new();
+    let mut colours = HashMap::<&str, &'static str>::new();
+
+    let mut res = Vec::new();
+    let mut pending = vec![(root, None, 0)];
+    while let Some((index, parent, depth)) = pending.pop() {
+        let node = &graph.graph[index];
+        let colour = match counts.get(node.checksum()) {
+            Some(count) if *count > 1 => {
+                let next = PALETTE[colours.len() % PALETTE.len()];
+                Some(*colours.entry(node.checksum()).or_insert(next))
+            },
+            _ => None,
+        };
+        res.push(ExportNode {
+            index,
+            parent,
+            label: node.to_string(),
+            kind: if matches!(node, GNode::DirNode {..}) { "directory" } else { "file" },
+            checksum: String::from(node.checksum()),
+            colour,
+            record: node.record_id(),
+        });
+
+        if options.max_depth.is_some_and(|max| depth >= max) {
+            continue;
+        }
+
+        let mut children: Vec<NodeIndex> = graph.graph.neighbors(index)
+            .filter(|child| !options.duplicates_only || bears_duplicates(graph, *child, &counts, &mut bearing))
+            .collect();
+        // directories first, by name, and popped in that order.
+        children.sort_by(|a, b| {
+            let key = |n: &NodeIndex| (matches!(graph.graph[*n], GNode::FileLeaf {..}), graph.graph[*n].name());
+            key(b).cmp(&key(a))
+        });
+        pending.extend(children.into_iter().map(|child| (child, Some(index), depth + 1)));
+    }
+
+    Ok(res)
+}
+
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+
+fn write_dot<W: Write>(nodes: &[ExportNode], out: &mut W) -> Result<()> {
+    writeln!(out, "digraph {{")?;
+    for node in nodes {
+        write!(out, "    {} [label=\"{}\", tooltip=\"{}\"", node.index.index(), escape_dot(&node.label), node.checksum)?;
+        if node.kind == "directory" {
+            write!(out, ", shape=folder")?;
+        }
+        if let Some(colour) = node.colour {
+            write!(out, ", style=filled, fillcolor=\"{}\"", colour)?;
+        }
+        if let Some(record) = node.record {
+            write!(out, ", id=\"record-{}\"", record)?;
+        }
+        writeln!(out, "]")?;
+    }
+    for node in nodes {
+        if let Some(parent) = node.parent {
+            writeln!(out, "    {} -> {}", parent.index(), node.index.index())?;
+        }
+    }
+    writeln!(out, "}}")?;
+
+    Ok(())
+}
+
+
+fn write_graphml<W: Write>(nodes: &[ExportNode], out: &mut W) -> Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#)?;
+    for key in &["label", "kind", "checksum", "color", "record"] {
+        writeln!(out, r#"  <key id="{}" for="node" attr.name="{}" attr.type="string"/>"#, key, key)?;
+    }
+    writeln!(out, r#"  <graph id="index" edgedefault="directed">"#)?;
+
+    for node in nodes {
+        writeln!(out, r#"    <node id="n{}">"#, node.index.index())?;
+        writeln!(out, r#"      <data key="label">{}</data>"#, escape_xml(&node.label))?;
+        writeln!(out, r#"      <data key="kind">{}</data>"#, node.kind)?;
+        writeln!(out, r#"      <data key="checksum">{}</data>"#, node.checksum)?;
+        if let Some(colour) = node.colour {
+            writeln!(out, r#"      <data key="color">{}</data>"#, colour)?;
+        }
+        if let Some(record) = node.record {
+            writeln!(out, r#"      <data key="record">{}</data>"#, record)?;
+        }
+        writeln!(out, "    </node>")?;
+    }
+    for node in nodes {
+        if let Some(parent) = node.parent {
+            writeln!(out, r#"    <edge source="n{}" target="n{}"/>"#, parent.index(), node.index.index())?;
+        }
+    }
+
+    writeln!(out, "  </graph>")?;
+    writeln!(out, "</graphml>")?;
+
+    Ok(())
+}
+
+
+fn write_cytoscape<W: Write>(nodes: &[ExportNode], out: &mut W) -> Result<()> {
+    let elements: Vec<serde_json::Value> = nodes.iter().map(|node| json!({
+        "data": {
+            "id": format!("n{}", node.index.index()),
+            "label": node.label,
+            "kind": node.kind,
+            "checksum": node.checksum,
+            "color": node.colour,
+            "record": node.record,
+        },
+    })).collect();
+    let edges: Vec<serde_json::Value> = nodes.iter().filter_map(|node| node.parent.map(|parent| json!({
+        "data": {
+            "id": format!("e{}", node.index.index()),
+            "source": format!("n{}", parent.index()),
+            "target": format!("n{}", node.index.index()),
+        },
+    }))).collect();
+
+    serde_json::to_writer(&mut *out, &json!({ "elements": { "nodes": elements, "edges": edges } }))?;
+    writeln!(out)?;
+
+    Ok(())
+}
+
+
+/// Writes the part of the tree picked by `options`. Labels come from the
+/// `Display` of the nodes, and every duplicated checksum gets a colour of its
+/// own, so copies of a subtree stand out. Returns the number of nodes written.
+pub fn write_graph<W: Write>(graph: &GraphStorage, options: &GraphExportOptions, mut out: W) -> Result<usize> {
+    let nodes = select(graph, options)?;
+    match options.format {
+        GraphFormat::Dot => write_dot(&nodes, &mut out)?,
+        GraphFormat::GraphMl => write_graphml(&nodes, &mut out)?,
+        GraphFormat::Cytoscape => write_cytoscape(&nodes, &mut out)?,
+    }
+    out.flush()?;
+
+    Ok(nodes.len())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Utc;
+
+    use crate::analyser::{initialise_graph, FileRecord, GraphStorageInterface};
+
+    fn graph() -> GraphStorage {
+        let record = |path: &[&str], name: &str, checksum: &str| FileRecord {
+            id: 1,
+            checksum: String::from(checksum),
+            name: String::from(name),
+            path: path.iter().map(|c| String::from(*c)).collect(),
+            modified: Utc::now(),
+        };
+
+        let mut graph = initialise_graph();
+        graph.bulk_insert(vec![
+            record(&["nas", "photos"], "a.jpg", "1"),
+            record(&["nas", "photos", "2020"], "b.jpg", "2"),
+            record(&["nas", "backup"], "a.jpg", "1"),
+            record(&["nas", "backup", "2020"], "b.jpg", "2"),
+            record(&["nas", "docs"], "unique.txt", "3"),
+        ]).unwrap();
+        graph
+    }
+
+    fn export(options: GraphExportOptions) -> String {
+        let mut out = Vec::new();
+        write_graph(&graph(), &options, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_dot_export() {
+        let dot = export(GraphExportOptions::default());
+        assert!(dot.contains("label=\"Directory-photos\""));
+        assert!(dot.contains("id=\"record-1\""));
+        // both copies of a directory share a colour.
+        let colour = |label: &str| dot.lines().find(|l| l.contains(label)).unwrap().split("fillcolor=").nth(1).map(String::from);
+        assert!(colour("Directory-photos").is_some());
+        assert_eq!(colour("Directory-photos"), colour("Directory-backup"));
+        assert_eq!(colour("File-unique.txt"), None);
+
+        let pruned = export(GraphExportOptions { duplicates_only: true, ..GraphExportOptions::default() });
+        assert!(!pruned.contains("docs") && pruned.contains("backup"));
+
+        let shallow = export(GraphExportOptions { max_depth: Some(1), root: parse_root("nas"), ..GraphExportOptions::default() });
+        assert!(shallow.contains("Directory-photos") && !shallow.contains("File-a.jpg") && !shallow.contains("Directory-root"));
+
+        let nested = export(GraphExportOptions { root: parse_root("nas:photos/2020/"), ..GraphExportOptions::default() });
+        assert!(nested.contains("File-b.jpg") && !nested.contains("File-a.jpg"));
+    }
+
+    #[test]
+    fn test_other_formats() {
+        let graphml = export(GraphExportOptions { format: GraphFormat::GraphMl, ..GraphExportOptions::default() });
+        assert_eq!(graphml.matches("<node ").count(), 12);
+        assert_eq!(graphml.matches("<edge ").count(), 11);
+
+        let cytoscape = export(GraphExportOptions { format: GraphFormat::Cytoscape, ..GraphExportOptions::default() });
+        let value: serde_json::Value = serde_json::from_str(&cytoscape).unwrap();
+        assert_eq!(value["elements"]["nodes"].as_array().unwrap().len(), 12);
+        assert_eq!(value["elements"]["edges"][0]["data"]["source"], "n0");
+
+        let mut out = Vec::new();
+        let missing = GraphExportOptions { root: vec![String::from("elsewhere")], ..GraphExportOptions::default() };
+        assert!(write_graph(&graph(), &missing, &mut out).is_err());
+    }
+}
@@ -0,0 +1,616 @@
+//! Changes of files across the snapshots of a volume.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::analyser::{self, GraphStorageInterface};
+use crate::error::{Error, Result};
+use crate::index_db::{ChangeKind, DirectoryMove, FileChange, IndexRecord, IndexStorage, Volume};
+use crate::misc;
+
+
+/// What a finished scan changed on its volume.
+#[derive(Debug, Default)]
+pub struct ScanChanges {
+    pub files: Vec<FileChange>,
+    /// Directories moved as a whole, their files are also in `files`.
+    pub directories: Vec<DirectoryMove>,
+}
+
+
+/// The files of a volume that differ before and after a scan, by location.
+#[derive(Debug, Default)]
+pub struct ScanDiff {
+    /// Files found with other contents, along with their checksum before.
+    pub modified: Vec<(IndexRecord, String)>,
+    /// Files that are no longer where they were.
+    pub vanished: Vec<IndexRecord>,
+    /// Files where there was none.
+    pub appeared: Vec<IndexRecord>,
+}
+
+
+/// What differs between two snapshots of a volume.
+#[derive(Debug, Default)]
+pub struct SnapshotDiff {
+    pub added: Vec<FileChange>,
+    pub removed: Vec<FileChange>,
+    pub modified: Vec<FileChange>,
+    /// Moved files, other than the contents of moved directories.
+    pub moved: Vec<FileChange>,
+    pub moved_directories: Vec<DirectoryMove>,
+}
+
+
+fn record_change(snapshot: i64, kind: ChangeKind, record: &IndexRecord) -> FileChange {
+    let (checksum, previous_checksum) = match kind {
+        ChangeKind::Added => (Some(record.checksum.clone()), None),
+        ChangeKind::Removed => (None, Some(record.checksum.clone())),
+        _ => (Some(record.checksum.clone()), Some(record.checksum.clone())),
+    };
+
+    FileChange {
+        snapshot,
+        kind,
+        volume: record.volume.clone(),
+        path: record.path.clone(),
+        name: record.name.clone(),
+        checksum,
+        previous_checksum,
+        modified: record.modified,
+        previous_path: None,
+        previous_name: None,
+    }
+}
+
+
+fn moved_change(snapshot: i64, from: &IndexRecord, to: &IndexRecord) -> FileChange {
+    FileChange {
+        previous_path: Some(from.path.clone()),
+        previous_name: Some(from.name.clone()),
+        previous_checksum: Some(from.checksum.clone()),
+        ..record_change(snapshot, ChangeKind::Moved, to)
+    }
+}
+
+
+/// Directories of a volume by path relative to its root, with their checksum.
+fn directory_checksums(records: &[IndexRecord]) -> Result<HashMap<String, String>> {
+    let mut graph = analyser::initialise_graph();
+    graph.bulk_insert(records.iter().map(misc::to_file_record).collect())?;
+
+    // the first component is the volume itself.
+    Ok(graph.directories().into_iter()
+        .filter(|(path, _)| path.len() > 1)
+        .map(|(path, checksum)| (path[1..].join("/"), checksum))
+        .collect())
+}
+
+
+fn is_under(path: &str, dir: &str) -> bool {
+    path == dir || path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
+
+/// `path` and every directory above it, innermost first.
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(path), |path| path.rsplit_once('/').map(|(parent, _)| parent))
+}
+
+
+/// Directories that are gone since the scan and showed up in it with the very
+/// same contents, outermost only. Only directories made of vanished or appeared
+/// files can match, `had_directory` and `has_directory` tell whether the others
+/// held files before and after the scan.
+fn match_directories<F, G>(diff: &ScanDiff, had_directory: F, has_directory: G) -> Result<Vec<(String, String)>>
+where
+    F: Fn(&str) -> Result<bool>,
+    G: Fn(&str) -> Result<bool>,
+{
+    let mut appeared = HashMap::<String, Vec<String>>::new();
+    for (path, checksum) in directory_checksums(&diff.appeared)? {
+        if !had_directory(&path)? {
+            appeared.entry(checksum).or_default().push(path);
+        }
+    }
+    for paths in appeared.values_mut() {
+        // deepest last, so the outermost candidate is popped first.
+        paths.sort_by_key(|path| std::cmp::Reverse(path.matches('/').count()));
+    }
+
+    let mut vanished = Vec::new();
+    for (path, checksum) in directory_checksums(&diff.vanished)? {
+        if !has_directory(&path)? {
+            vanished.push((path, checksum));
+        }
+    }
+    vanished.sort_by_key(|(path, _)| (path.matches('/').count(), path.clone()));
+
+    let mut res = Vec::<(String, String)>::new();
+    let mut moved = HashSet::<String>::new();
+    let mut taken = HashSet::<String>::new();
+    for (path, checksum) in vanished {
+        if ancestors(&path).any(|dir| moved.contains(dir)) {
+            continue;
+        }
+
+        let candidates = match appeared.get_mut(&checksum) {
+            Some(candidates) => candidates,
+            None => continue,
+        };
+        // candidates inside a directory already taken stay so, they are dropped for good.
+        while let Some(to) = candidates.pop() {
+            if !ancestors(&to).any(|dir| taken.contains(dir)) {
+                moved.insert(path.clone());
+                taken.insert(to.clone());
+                res.push((path, to));
+                break;
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+
+/// Pairs every vanished file with an appeared one of the same checksum and
+/// size, and the same inode when both sides have one, preferring inode matches.
+fn match_files(vanished: &[&IndexRecord], appeared: &[&IndexRecord]) -> Vec<(usize, usize)> {
+    // every list is in reverse, so popping gives the first candidate left.
+    let mut by_contents = HashMap::<(&str, u64), Vec<usize>>::new();
+    let mut by_inode = HashMap::<(&str, u64, u64), Vec<usize>>::new();
+    let mut without_inode = HashMap::<(&str, u64), Vec<usize>>::new();
+    for (j, record) in appeared.iter().enumerate().rev() {
+        let contents = (record.checksum.as_str(), record.size);
+        by_contents.entry(contents).or_default().push(j);
+        match record.inode {
+            Some(inode) => by_inode.entry((contents.0, contents.1, inode)).or_default().push(j),
+            None => without_inode.entry(contents).or_default().push(j),
+        }
+    }
+
+    let mut taken = HashSet::new();
+    let mut first_left = |options: Option<&mut Vec<usize>>| {
+        let options = options?;
+        while let Some(j) = options.pop() {
+            if taken.insert(j) {
+                return Some(j);
+            }
+        }
+        None
+    };
+
+    let mut res = Vec::new();
+    for (i, record) in vanished.iter().enumerate() {
+        let contents = (record.checksum.as_str(), record.size);
+        let found = match record.inode {
+            Some(inode) => first_left(by_inode.get_mut(&(contents.0, contents.1, inode)))
+                .or_else(|| first_left(without_inode.get_mut(&contents))),
+            None => first_left(by_contents.get_mut(&contents)),
+        };
+        if let Some(j) = found {
+            res.push((i, j));
+        }
+    }
+
+    res
+}
+
+
+/// Sorts out what a scan changed on its volume from the files that differ, see
+/// [`ScanDiff`]. Files that are gone from one place and showed up in another
+/// are moves: whole directories when their checksum is unchanged, otherwise
+/// single files, see [`match_files`].
+pub fn detect_changes<F, G>(snapshot: i64, diff: &ScanDiff, had_directory: F, has_directory: G) -> Result<ScanChanges>
+where
+    F: Fn(&str) -> Result<bool>,
+    G: Fn(&str) -> Result<bool>,
+{
+    let mut changes = ScanChanges::default();
+    for (record, previous) in &diff.modified {
+        changes.files.push(FileChange {
+            previous_checksum: Some(previous.clone()),
+            ..record_change(snapshot, ChangeKind::Modified, record)
+        });
+    }
+
+    let key = |r: &IndexRecord| (r.path.clone(), r.name.clone());
+    let mut appeared: HashMap<(String, String), &IndexRecord> = diff.appeared.iter().map(|r| (key(r), r)).collect();
+
+    let moves = match_directories(diff, had_directory, has_directory)?;
+    let targets: HashMap<&str, &str> = moves.iter().map(|(from, to)| (from.as_str(), to.as_str())).collect();
+    let mut vanished = Vec::new();
+    for record in &diff.vanished {
+        // moved directories never nest, so at most one of them holds the file.
+        let target = ancestors(&record.path)
+            .find_map(|from| targets.get(from).map(|to| (from, *to)))
+            .and_then(|(from, to)| appeared.remove(&(format!("{}{}", to, &record.path[from.len()..]), record.name.clone())));
+        match target {
+            Some(target) => changes.files.push(moved_change(snapshot, record, target)),
+            None => vanished.push(record),
+        }
+    }
+
+    let volume = diff.appeared.first().map(|r| r.volume.clone()).unwrap_or_default();
+    for (from, to) in moves {
+        changes.directories.push(DirectoryMove { snapshot, volume: volume.clone(), from, to });
+    }
+
+    let mut appeared: Vec<&IndexRecord> = appeared.into_values().collect();
+    appeared.sort_by_key(|r| r.location());
+    vanished.sort_by_key(|r| r.location());
+
+    let pairs = match_files(&vanished, &appeared);
+    for &(i, j) in &pairs {
+        changes.files.push(moved_change(snapshot, vanished[i], appeared[j]));
+    }
+    let moved_from: HashSet<usize> = pairs.iter().map(|&(i, _)| i).collect();
+    let moved_to: HashSet<usize> = pairs.iter().map(|&(_, j)| j).collect();
+    for (i, record) in vanished.iter().enumerate() {
+        if !moved_from.contains(&i) {
+            changes.files.push(record_change(snapshot, ChangeKind::Removed, record));
+        }
+    }
+    for (j, record) in appeared.iter().enumerate() {
+        if !moved_to.contains(&j) {
+            changes.files.push(record_change(snapshot, ChangeKind::Added, record));
+        }
+    }
+
+    Ok(changes)
+}
+
+
+/// Collapses a run of changes, oldest first, into a single change per file,
+/// following files as they move. Files that end up as they started, or added
+/// and removed again, are left out.
+pub fn net_changes(changes: Vec<FileChange>) -> Vec<FileChange> {
+    // current location -> (location and contents before the run, latest change)
+    let mut files = BTreeMap::<String, (String, Option<String>, FileChange)>::new();
+    for change in changes {
+        let origin = match change.previous_location() {
+            Some(previous) if change.kind == ChangeKind::Moved => files.remove(&previous)
+                .map(|(origin, before, _)| (origin, before))
+                .unwrap_or((previous, change.previous_checksum.clone())),
+            _ => match files.remove(&change.location()) {
+                Some((origin, before, _)) => (origin, before),
+                None => (change.location(), change.previous_checksum.clone()),
+            },
+        };
+        files.insert(change.location(), (origin.0, origin.1, change));
+    }
+
+    let mut res = Vec::new();
+    for (location, (origin, before, mut last)) in files {
+        last.kind = match (&before, &last.checksum) {
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(_), None) => ChangeKind::Removed,
+            (Some(_), Some(_)) if origin != location => ChangeKind::Moved,
+            (Some(before), Some(after)) if before != after => ChangeKind::Modified,
+            _ => continue,
+        };
+
+        // a removed file is reported where it was, a moved one keeps where it came from.
+        let (volume, path, name) = split_location(&origin);
+        match last.kind {
+            ChangeKind::Removed => {
+                last.path = path;
+                last.name = name;
+            },
+            ChangeKind::Moved => {
+                last.previous_path = Some(path);
+                last.previous_name = Some(name);
+            },
+            _ => {},
+        }
+        last.volume = volume;
+        last.previous_checksum = before;
+        res.push(last);
+    }
+
+    res
+}
+
+
+/// Inverse of [`crate::index_db::location`].
+fn split_location(location: &str) -> (String, String, String) {
+    let (volume, relative) = location.split_once(':').unwrap_or(("", location));
+    let (path, name) = relative.rsplit_once('/').unwrap_or(("", relative));
+
+    (String::from(volume), String::from(path), String::from(name))
+}
+
+
+/// Sorts net changes by kind. Only the moves recorded when the snapshots were
+/// taken are moves, see [`match_files`], so the diff tells the same as the
+/// history of each file. Moves within `directories` are only reported as the
+/// directory move.
+pub fn to_diff(changes: Vec<FileChange>, directories: Vec<DirectoryMove>) -> SnapshotDiff {
+    let mut diff = SnapshotDiff::default();
+    for change in changes {
+        match change.kind {
+            ChangeKind::Modified => diff.modified.push(change),
+            ChangeKind::Moved => diff.moved.push(change),
+            ChangeKind::Removed => diff.removed.push(change),
+            ChangeKind::Added => diff.added.push(change),
+        }
+    }
+    diff.added.sort_by_key(|change| change.location());
+    diff.removed.sort_by_key(|change| change.location());
+
+    let targets: HashMap<&str, Vec<&str>> = directories.iter().fold(HashMap::new(), |mut targets, dir| {
+        targets.entry(dir.from.as_str()).or_default().push(dir.to.as_str());
+        targets
+    });
+    diff.moved.retain(|change| {
+        let from = change.previous_path.as_deref().unwrap_or_default();
+        !ancestors(from)
+            .filter_map(|dir| targets.get(dir))
+            .any(|to| to.iter().any(|to| is_under(&change.path, to)))
+    });
+    diff.moved.sort_by_key(|change| change.location());
+    diff.moved_directories = directories;
+
+    diff
+}
+
+
+/// Lists what was added, removed, modified and moved on a volume from snapshot
+/// `from` to snapshot `to`.
+pub fn diff_snapshots<T: IndexStorage>(storage: &T, from: i64, to: i64) -> Result<SnapshotDiff> {
+    let older = storage.snapshot(from)?.ok_or_else(|| Error::Parse(format!("no snapshot {}", from)))?;
+    let newer = storage.snapshot(to)?.ok_or_else(|| Error::Parse(format!("no snapshot {}", to)))?;
+
+    if older.volume != newer.volume {
+        return Err(Error::Parse(format!(
+            "snapshot {} is of volume '{}' and snapshot {} of volume '{}'", from, older.volume, to, newer.volume,
+        )));
+    }
+    if from > to {
+        return Err(Error::Parse(format!("snapshot {} is newer than snapshot {}", from, to)));
+    }
+
+    let changes = storage.changes_between(&newer.volume, from, to)?;
+    let directories = storage.directory_moves_between(&newer.volume, from, to)?;
+    Ok(to_diff(net_changes(changes), directories))
+}
+
+
+/// Finds the volume, directory and name of a file given either as
+/// `volume:path/name` or as a path on disk, under the root of a known volume.
+pub fn resolve_location(volumes: &[Volume], file: &str, current_dir: &str) -> Option<(String, String, String)> {
+    let (volume, relative) = match file.split_once(':') {
+        Some((label, relative)) if volumes.iter().any(|v| v.label == label) => {
+            (String::from(label), String::from(relative.trim_matches('/')))
+        },
+        _ => {
+            let absolute = crate::misc::absolute_path(file, current_dir);
+            // the deepest root holding the file, as volumes may be nested.
+            let volume = volumes.iter()
+                .filter(|v| in_root(&absolute, &v.root))
+                .max_by_key(|v| v.root.len())?;
+            let relative = absolute[volume.root.trim_end_matches('/').len()..].trim_matches('/');
+            (volume.label.clone(), String::from(relative))
+        },
+    };
+
+    let (path, name) = match relative.rsplit_once('/') {
+        Some((path, name)) => (String::from(path), String::from(name)),
+        None => (String::new(), relative),
+    };
+    if name.is_empty() {
+        return None;
+    }
+
+    Some((volume, path, name))
+}
+
+
+fn in_root(path: &str, root: &str) -> bool {
+    let root = root.trim_end_matches('/');
+    match path.strip_prefix(root) {
+        Some(rest) => rest.starts_with('/'),
+        None => false,
+    }
+}
+
+
+/// Every change of a file, oldest first, see [`resolve_location`].
+pub fn file_history<T: IndexStorage>(storage: &T, file: &str, current_dir: &str) -> Result<Vec<FileChange>> {
+    let volumes = storage.volumes()?;
+    match resolve_location(&volumes, file, current_dir) {
+        Some((volume, path, name)) => storage.history(&volume, &path, &name),
+        None => Err(Error::Parse(format!("{} is not on any indexed volume", file))),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Utc;
+
+    use crate::file_handler::HashAlgorithm;
+
+    fn change(snapshot: i64, kind: ChangeKind, name: &str, checksum: Option<&str>, previous: Option<&str>) -> FileChange {
+        FileChange {
+            snapshot,
+            kind,
+            volume: String::from("nas"),
+            path: String::from("photos"),
+            name: String::from(name),
+            checksum: checksum.map(String::from),
+            previous_checksum: previous.map(String::from),
+            modified: Utc::now(),
+            previous_path: None,
+            previous_name: None,
+        }
+    }
+
+    fn record(path: &str, name: &str, checksum: &str, inode: Option<u64>) -> IndexRecord {
+        IndexRecord {
+            id: 0,
+            checksum: String::from(checksum),
+            name: String::from(name),
+            volume: String::from("nas"),
+            path: String::from(path),
+            modified: Utc::now(),
+            size: checksum.len() as u64,
+            inode,
+        }
+    }
+
+    #[test]
+    fn test_net_changes_and_moves() {
+        let moved = |snapshot, from: &str, to: &str| {
+            let mut moved = change(snapshot, ChangeKind::Moved, to, Some("7"), Some("7"));
+            moved.previous_path = Some(String::from("photos"));
+            moved.previous_name = Some(String::from(from));
+            moved
+        };
+
+        let changes = vec![
+            change(2, ChangeKind::Added, "a", Some("1"), None),
+            change(2, ChangeKind::Removed, "b", None, Some("2")),
+            change(2, ChangeKind::Added, "c", Some("7"), None),
+            change(2, ChangeKind::Modified, "d", Some("4"), Some("3")),
+            change(3, ChangeKind::Modified, "d", Some("3"), Some("4")),
+            change(3, ChangeKind::Modified, "e", Some("6"), Some("5")),
+            change(3, ChangeKind::Removed, "a", None, Some("1")),
+            change(3, ChangeKind::Added, "g", Some("2"), None),
+            moved(3, "c", "f"),
+            moved(3, "h", "i"),
+        ];
+
+        let diff = to_diff(net_changes(changes), Vec::new());
+
+        // a came and went, d got back to where it was, c was added then moved.
+        assert_eq!(diff.added.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>(), vec!["f", "g"]);
+        assert_eq!(diff.modified.len(), 1);
+        assert_eq!(diff.modified[0].name, "e");
+        assert_eq!(diff.moved.len(), 1);
+        assert_eq!(diff.moved[0].previous_location(), Some(String::from("nas:photos/h")));
+        assert_eq!(diff.moved[0].location(), "nas:photos/i");
+        // b's contents came back as g, but the scans did not record a move, nor does the diff.
+        assert_eq!(diff.removed.iter().map(|c| c.location()).collect::<Vec<String>>(), vec!["nas:photos/b"]);
+    }
+
+    #[test]
+    fn test_detect_changes() {
+        let old = vec![
+            record("2019/trip", "a.jpg", "aaa", Some(1)),
+            record("2019/trip/raw", "a.raw", "rrr", Some(2)),
+            record("2019", "notes", "nnn", Some(3)),
+            record("2019", "copy", "ccc", Some(4)),
+            record("", "gone", "ggg", Some(5)),
+        ];
+        let new = vec![
+            record("archive/trip", "a.jpg", "aaa", Some(1)),
+            record("archive/trip/raw", "a.raw", "rrr", Some(2)),
+            record("2019", "notes", "NNN", Some(3)),
+            record("docs", "notes-renamed", "ccc", Some(4)),
+            record("", "gone-copy", "ggg", Some(6)),
+        ];
+
+        let at = |records: &[IndexRecord], r: &IndexRecord| records.iter().find(|o| o.path == r.path && o.name == r.name).cloned();
+        let diff = ScanDiff {
+            modified: new.iter()
+                .filter_map(|r| at(&old, r).filter(|o| o.checksum != r.checksum).map(|o| (r.clone(), o.checksum)))
+                .collect(),
+            vanished: old.iter().filter(|r| at(&new, r).is_none()).cloned().collect(),
+            appeared: new.iter().filter(|r| at(&old, r).is_none()).cloned().collect(),
+        };
+        let holds = |records: &[IndexRecord], dir: &str| records.iter().any(|r| is_under(&r.path, dir));
+
+        let changes = detect_changes(7, &diff, |dir| Ok(holds(&old, dir)), |dir| Ok(holds(&new, dir))).unwrap();
+        assert_eq!(changes.directories, vec![DirectoryMove {
+            snapshot: 7, volume: String::from("nas"), from: String::from("2019/trip"), to: String::from("archive/trip"),
+        }]);
+
+        let kinds = |kind| changes.files.iter().filter(|c| c.kind == kind).map(|c| c.location()).collect::<Vec<String>>();
+        assert_eq!(kinds(ChangeKind::Moved), vec!["nas:archive/trip/a.jpg", "nas:archive/trip/raw/a.raw", "nas:docs/notes-renamed"]);
+        assert_eq!(kinds(ChangeKind::Modified), vec!["nas:2019/notes"]);
+        // same contents, but a different inode: copied and deleted rather than moved.
+        assert_eq!(kinds(ChangeKind::Removed), vec!["nas:gone"]);
+        assert_eq!(kinds(ChangeKind::Added), vec!["nas:gone-copy"]);
+    }
+
+    #[test]
+    fn test_copy_then_delete_is_no_move() {
+        let dir = std::env::temp_dir().join(format!("rusty-index-copy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("gone"), "contents").unwrap();
+        let storage = crate::index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        let root = String::from(dir.to_str().unwrap());
+        let scan = || crate::file_handler::stream_scan_directory(root.clone(), &storage, false, Default::default()).unwrap();
+
+        scan();
+        std::fs::copy(dir.join("gone"), dir.join("gone-copy")).unwrap();
+        std::fs::remove_file(dir.join("gone")).unwrap();
+        scan();
+
+        let kinds = |name: &str| storage.history(&root, "", name).unwrap().into_iter().map(|c| c.kind).collect::<Vec<ChangeKind>>();
+        assert_eq!(kinds("gone"), vec![ChangeKind::Added, ChangeKind::Removed]);
+        assert_eq!(kinds("gone-copy"), vec![ChangeKind::Added]);
+
+        // the diff tells the same as the history.
+        let snapshots = storage.snapshots().unwrap();
+        let diff = diff_snapshots(&storage, snapshots[0].id, snapshots[1].id).unwrap();
+        assert!(diff.moved.is_empty());
+        assert_eq!(diff.removed.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>(), vec!["gone"]);
+        assert_eq!(diff.added.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>(), vec!["gone-copy"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_diff_shows_the_recorded_moves() {
+        let dir = std::env::temp_dir().join(format!("rusty-index-moves-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("trip/raw")).unwrap();
+        std::fs::write(dir.join("trip/a.jpg"), "a").unwrap();
+        std::fs::write(dir.join("trip/raw/a.raw"), "raw").unwrap();
+        std::fs::write(dir.join("notes"), "notes").unwrap();
+        let storage = crate::index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        let root = String::from(dir.to_str().unwrap());
+        let scan = || crate::file_handler::stream_scan_directory(root.clone(), &storage, false, Default::default()).unwrap();
+
+        scan();
+        std::fs::create_dir(dir.join("archive")).unwrap();
+        std::fs::rename(dir.join("trip"), dir.join("archive/trip")).unwrap();
+        std::fs::rename(dir.join("notes"), dir.join("notes-renamed")).unwrap();
+        scan();
+
+        let kinds = |path: &str, name: &str| storage.history(&root, path, name).unwrap().into_iter().map(|c| c.kind).collect::<Vec<ChangeKind>>();
+        assert_eq!(kinds("", "notes-renamed"), vec![ChangeKind::Moved]);
+        assert_eq!(kinds("archive/trip/raw", "a.raw"), vec![ChangeKind::Moved]);
+
+        let snapshots = storage.snapshots().unwrap();
+        let diff = diff_snapshots(&storage, snapshots[0].id, snapshots[1].id).unwrap();
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.moved.iter().map(|c| c.location()).collect::<Vec<String>>(), vec![format!("{}:notes-renamed", root)]);
+        assert_eq!(diff.moved_directories.iter().map(|d| (d.from.as_str(), d.to.as_str())).collect::<Vec<(&str, &str)>>(), vec![("trip", "archive/trip")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_location() {
+        let volume = |label: &str, root: &str| Volume {
+            id: 0,
+            label: String::from(label),
+            uuid: None,
+            root: String::from(root),
+            last_seen: Utc::now(),
+            algorithm: HashAlgorithm::Md5,
+        };
+        let volumes = vec![volume("nas", "/mnt/nas"), volume("photos", "/mnt/nas/photos/")];
+
+        let resolved = |file: &str| resolve_location(&volumes, file, "/mnt");
+        assert_eq!(resolved("nas:docs/a.txt"), Some((String::from("nas"), String::from("docs"), String::from("a.txt"))));
+        assert_eq!(resolved("nas/docs/a.txt"), Some((String::from("nas"), String::from("docs"), String::from("a.txt"))));
+        assert_eq!(resolved("/mnt/nas/photos/b.jpg"), Some((String::from("photos"), String::new(), String::from("b.jpg"))));
+        assert_eq!(resolved("/mnt/nasty/c"), None);
+    }
+}
@@ -1,141 +1,1172 @@
-use std::collections::HashMap;
+//! Persistent index of file records.
+
+use std::fs;
+use std::path::Path;
 
 extern crate rusqlite;
 use rusqlite::{params, NO_PARAMS};
-use rusqlite::{Connection, Result};
+use rusqlite::{Connection, OptionalExtension, Row, ToSql};
 
 extern crate chrono;
 use chrono::{DateTime, SecondsFormat, Utc};
 
-#[derive(Debug)]
+use crate::analyser::{self, FileRecord};
+use crate::error::{Error, Result};
+use crate::file_handler::HashAlgorithm;
+use crate::history::{self, ScanChanges, ScanDiff};
+use crate::misc;
+
+#[derive(Debug, Clone)]
 pub struct IndexRecord {
     pub id: u32,
     pub checksum: String,
     pub name: String,
+    /// Label of the volume holding the file.
+    pub volume: String,
+    /// Directory of the file, relative to the root of its volume.
     pub path: String,
     pub modified: DateTime<Utc>,
+    /// Size in bytes.
+    pub size: u64,
+    /// Inode number, where the filesystem has them.
+    pub inode: Option<u64>,
+}
+
+
+impl IndexRecord {
+    /// Where the file lives, as `volume:path/name`.
+    pub fn location(&self) -> String {
+        location(&self.volume, &self.path, &self.name)
+    }
+}
+
+
+pub fn location(volume: &str, path: &str, name: &str) -> String {
+    match path {
+        "" => format!("{}:{}", volume, name),
+        path => format!("{}:{}/{}", volume, path, name),
+    }
+}
+
+
+/// A finished scan of a whole volume, numbered by its scan id.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub id: i64,
+    pub volume: String,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Removed,
+    Moved,
+}
+
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Moved => "moved",
+        }
+    }
+
+    fn parse(kind: &str) -> Result<ChangeKind> {
+        match kind {
+            "added" => Ok(ChangeKind::Added),
+            "modified" => Ok(ChangeKind::Modified),
+            "removed" => Ok(ChangeKind::Removed),
+            "moved" => Ok(ChangeKind::Moved),
+            other => Err(Error::Parse(format!("unknown change '{}'", other))),
+        }
+    }
+}
+
+
+/// How a file differs in a snapshot from the one before it.
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    pub snapshot: i64,
+    pub kind: ChangeKind,
+    pub volume: String,
+    pub path: String,
+    pub name: String,
+    /// Content after the change, none once removed.
+    pub checksum: Option<String>,
+    /// Content before the change, none when added.
+    pub previous_checksum: Option<String>,
+    /// Modification time after the change, or last known one when removed.
+    pub modified: DateTime<Utc>,
+    /// Where a moved file was before.
+    pub previous_path: Option<String>,
+    pub previous_name: Option<String>,
+}
+
+
+impl FileChange {
+    pub fn location(&self) -> String {
+        location(&self.volume, &self.path, &self.name)
+    }
+
+    pub fn previous_location(&self) -> Option<String> {
+        match (&self.previous_path, &self.previous_name) {
+            (Some(path), Some(name)) => Some(location(&self.volume, path, name)),
+            _ => None,
+        }
+    }
+}
+
+
+/// A whole directory moved within a volume between two snapshots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectoryMove {
+    pub snapshot: i64,
+    pub volume: String,
+    pub from: String,
+    pub to: String,
+}
+
+
+/// A disk, share or any other tree scanned as a whole. Records refer to their
+/// volume by label, so they stay valid when it is mounted somewhere else.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    pub id: i64,
+    pub label: String,
+    /// Filesystem UUID, used to tell apart drives given the same label.
+    pub uuid: Option<String>,
+    /// Where the volume was mounted when last scanned.
+    pub root: String,
+    /// When the volume was last scanned.
+    pub last_seen: DateTime<Utc>,
+    /// Hash function of the checksums of its records.
+    pub algorithm: HashAlgorithm,
+}
+
+
+impl Volume {
+    /// Whether the volume is mounted where it was last seen.
+    pub fn is_online(&self) -> bool {
+        is_mounted(&self.root)
+    }
+
+    /// Whether the volume was imported from a checksum manifest rather than
+    /// scanned, leaving it without any place on disk.
+    pub fn is_virtual(&self) -> bool {
+        self.root.is_empty()
+    }
+}
+
+
+/// A node of the directory tree kept in the index, linked to the directory
+/// holding it. The root has no parent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredNode {
+    pub id: i64,
+    pub parent: Option<i64>,
+    pub name: String,
+    pub checksum: String,
+    pub is_dir: bool,
+    /// Row of the record a file stands for.
+    pub record_id: Option<u32>,
+}
+
+
+/// Whether there is anything at `root`. An empty mount point is taken as a
+/// drive that is not plugged in.
+pub fn is_mounted(root: &str) -> bool {
+    match fs::read_dir(root) {
+        Ok(mut entries) => entries.next().is_some(),
+        Err(_) => false,
+    }
 }
 
 pub trait IndexStorage {
     fn create(&self) -> Result<()>;
-    fn insert(&self, arr: &Vec<IndexRecord>) -> Result<()>;
-    fn select(&self, name: String) -> Result<Vec<IndexRecord>>;
+    /// Stores records outside of any scan, replacing the ones already at the
+    /// same place of their volume, so that scanning a file again doesn't list it twice.
+    fn insert(&self, arr: &[IndexRecord]) -> Result<()>;
     fn fetch_sorted(&self) -> Result<Vec<IndexRecord>>;
-    fn fetch_indexed(&self) -> Result<HashMap<String, IndexRecord>>;
+
+    /// Registers the volume `label` as mounted at `root`, creating it if needed.
+    /// A UUID different from the one already known for the label is an error,
+    /// and so is another hash function. New volumes are hashed with MD5 by default.
+    fn register_volume(&self, label: &str, uuid: Option<&str>, root: &str, algorithm: Option<HashAlgorithm>) -> Result<Volume>;
+    fn volume(&self, label: &str) -> Result<Option<Volume>>;
+    fn volumes(&self) -> Result<Vec<Volume>>;
+    /// Every copy of the content with the given checksum, on any volume,
+    /// including the ones currently offline.
+    fn copies(&self, checksum: &str) -> Result<Vec<IndexRecord>>;
+
+    /// Registers a new scan of `root`, the root of the given volume, and returns its id.
+    fn start_scan(&self, volume_id: i64, root: &str) -> Result<i64>;
+    /// Latest scan of `root` that was interrupted before finishing, if any.
+    fn unfinished_scan(&self, root: &str) -> Result<Option<i64>>;
+    /// Directories whose files have all been stored by the given scan.
+    fn completed_directories(&self, scan_id: i64) -> Result<Vec<String>>;
+    /// Absolute paths of the files stored by the given scan in directories it
+    /// has not completed yet.
+    fn partially_scanned_files(&self, scan_id: i64) -> Result<Vec<String>>;
+    /// Stores a batch of records and marks the directories whose files are now
+    /// all stored as done, atomically.
+    fn checkpoint(&self, scan_id: i64, directories: &[String], arr: &[IndexRecord]) -> Result<()>;
+    /// Marks the scan as done. Records of its volume that it did not find again
+    /// were deleted from the volume and are dropped from the index. Volumes that
+    /// are not scanned keep their records, whether they are mounted or not.
+    fn finish_scan(&self, scan_id: i64) -> Result<()>;
+
+    fn snapshots(&self) -> Result<Vec<Snapshot>>;
+    fn snapshot(&self, id: i64) -> Result<Option<Snapshot>>;
+    /// Every change of the file `name` under `path` on `volume`, oldest first,
+    /// including it being moved away.
+    fn history(&self, volume: &str, path: &str, name: &str) -> Result<Vec<FileChange>>;
+    /// Changes on `volume` recorded after snapshot `from`, up to and including
+    /// snapshot `to`, oldest first.
+    fn changes_between(&self, volume: &str, from: i64, to: i64) -> Result<Vec<FileChange>>;
+    fn directory_moves_between(&self, volume: &str, from: i64, to: i64) -> Result<Vec<DirectoryMove>>;
+
+    /// The record stored in row `id`.
+    fn record(&self, id: u32) -> Result<Option<IndexRecord>>;
+    fn count_records(&self, volume: &str) -> Result<usize>;
+    /// Records of `volume` in the directory `path` or below it, the whole
+    /// volume when `path` is empty.
+    fn count_records_under(&self, volume: &str, path: &str) -> Result<usize>;
+    /// Up to `limit` records of `volume`, the ones never verified first and
+    /// then the ones verified the longest ago.
+    fn least_recently_verified(&self, volume: &str, limit: usize) -> Result<Vec<IndexRecord>>;
+    fn mark_verified(&self, ids: &[u32]) -> Result<()>;
+
+    /// Root of the stored directory tree, if one was saved and is still current.
+    fn graph_root(&self) -> Result<Option<StoredNode>>;
+    /// Nodes directly under the stored directory `parent`.
+    fn graph_children(&self, parent: i64) -> Result<Vec<StoredNode>>;
+    /// Replaces the stored directory tree. Completed scans then keep it up to
+    /// date, while records inserted outside of a scan drop it.
+    fn save_graph(&self, nodes: &[StoredNode]) -> Result<()>;
 }
 
 pub struct SQLite3 {
     pub conn: Connection,
 }
 
-pub fn initalise_db(file_name: &String) -> Result<SQLite3> {
+pub fn initalise_db(file_name: &str) -> Result<SQLite3> {
     Ok(SQLite3 {
-        conn: Connection::open(file_name.as_str())?,
+        conn: Connection::open(file_name)?,
+    })
+}
+
+
+/// Opens an index that must already exist, as opening creates missing databases.
+pub fn open_existing(file_name: &str) -> Result<SQLite3> {
+    if !Path::new(file_name).exists() {
+        return Err(Error::Io(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} does not exist", file_name))));
+    }
+
+    let storage = initalise_db(file_name)?;
+    storage.create()?;
+    Ok(storage)
+}
+
+
+/// Columns selected for every record, `i` being `index_records` and `v` its volume.
+/// Reads go through `current_records` instead, leaving out the rows of scans that
+/// were interrupted and then superseded, so a file is never listed twice while a
+/// rescan is not finished.
+const RECORD_COLUMNS: &str = "i.id, i.checksum, i.name, v.label, i.path, i.modified, i.size, i.inode";
+
+
+/// Raw columns of an `index_records` row, before the timestamp is parsed.
+type RecordRow = (u32, String, String, String, String, String, i64, Option<i64>);
+
+
+fn read_record_row(row: &Row) -> rusqlite::Result<RecordRow> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?))
+}
+
+
+fn to_index_record(raw: RecordRow) -> Result<IndexRecord> {
+    let (id, checksum, name, volume, path, str_modified, size, inode) = raw;
+
+    Ok(IndexRecord {
+        id,
+        checksum,
+        name,
+        volume,
+        path,
+        modified: DateTime::parse_from_rfc3339(str_modified.as_str())?.into(),
+        size: size as u64,
+        inode: inode.map(|inode| inode as u64),
+    })
+}
+
+
+fn query_records(conn: &Connection, sql: &str, params: &[&dyn ToSql]) -> Result<Vec<IndexRecord>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, read_record_row)?;
+
+    let mut res = Vec::new();
+    for raw in rows {
+        res.push(to_index_record(raw?)?);
+    }
+
+    Ok(res)
+}
+
+
+fn insert_records(conn: &Connection, scan_id: Option<i64>, arr: &[IndexRecord]) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO index_records (volume_id, scan_id, checksum, name, path, modified, size, inode)
+         values ((SELECT v.id FROM volumes v WHERE v.label = ?1), ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?;
+    for record in arr {
+        // sqlite integers are signed, sizes and inodes go in as their bits.
+        stmt.execute(params![
+            record.volume, scan_id, record.checksum, record.name, record.path,
+            record.modified.to_rfc3339_opts(SecondsFormat::Millis, true),
+            record.size as i64, record.inode.map(|inode| inode as i64),
+        ])?;
+    }
+
+    Ok(())
+}
+
+
+const VOLUME_COLUMNS: &str = "v.id, v.label, v.uuid, v.root, v.last_seen, v.algorithm";
+
+
+/// Raw columns of a `volumes` row, before the timestamp is parsed.
+type VolumeRow = (i64, String, Option<String>, String, String, String);
+
+
+fn to_volume(raw: VolumeRow) -> Result<Volume> {
+    let (id, label, uuid, root, str_last_seen, algorithm) = raw;
+
+    Ok(Volume {
+        id,
+        label,
+        uuid,
+        root,
+        last_seen: DateTime::parse_from_rfc3339(str_last_seen.as_str())?.into(),
+        algorithm: HashAlgorithm::parse(&algorithm)?,
     })
 }
 
 
+fn query_volumes(conn: &Connection, sql: &str, params: &[&dyn ToSql]) -> Result<Vec<Volume>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+    })?;
+
+    let mut res = Vec::new();
+    for raw in rows {
+        res.push(to_volume(raw?)?);
+    }
+
+    Ok(res)
+}
+
+
+const CHANGE_COLUMNS: &str = "h.scan_id, h.change, v.label, h.path, h.name, h.checksum, h.previous_checksum,
+                              h.modified, h.previous_path, h.previous_name";
+
+
+fn query_changes(conn: &Connection, sql: &str, params: &[&dyn ToSql]) -> Result<Vec<FileChange>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, |row| {
+        Ok((
+            row.get(0)?, row.get::<_, String>(1)?, row.get(2)?, row.get(3)?,
+            row.get(4)?, row.get(5)?, row.get(6)?, row.get::<_, String>(7)?, row.get(8)?, row.get(9)?,
+        ))
+    })?;
+
+    let mut res = Vec::new();
+    for raw in rows {
+        let (snapshot, kind, volume, path, name, checksum, previous_checksum, modified, previous_path, previous_name) = raw?;
+        res.push(FileChange {
+            snapshot,
+            kind: ChangeKind::parse(&kind)?,
+            volume,
+            path,
+            name,
+            checksum,
+            previous_checksum,
+            modified: DateTime::parse_from_rfc3339(modified.as_str())?.into(),
+            previous_path,
+            previous_name,
+        });
+    }
+
+    Ok(res)
+}
+
+
+fn insert_changes(conn: &Connection, scan_id: i64, volume_id: i64, changes: &ScanChanges) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO file_history (scan_id, volume_id, path, name, change, checksum, previous_checksum,
+                                   modified, previous_path, previous_name)
+         values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+    )?;
+    for change in &changes.files {
+        stmt.execute(params![
+            scan_id, volume_id, change.path, change.name, change.kind.as_str(), change.checksum,
+            change.previous_checksum, change.modified.to_rfc3339_opts(SecondsFormat::Millis, true),
+            change.previous_path, change.previous_name,
+        ])?;
+    }
+
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO directory_moves (scan_id, volume_id, from_path, to_path) values (?1, ?2, ?3, ?4)",
+    )?;
+    for moved in &changes.directories {
+        stmt.execute(params![scan_id, volume_id, moved.from, moved.to])?;
+    }
+
+    Ok(())
+}
+
+
+fn query_snapshots(conn: &Connection, sql: &str, params: &[&dyn ToSql]) -> Result<Vec<Snapshot>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?))
+    })?;
+
+    let mut res = Vec::new();
+    for raw in rows {
+        let (id, volume, started, finished) = raw?;
+        res.push(Snapshot {
+            id,
+            volume,
+            started: DateTime::parse_from_rfc3339(started.as_str())?.into(),
+            finished: DateTime::parse_from_rfc3339(finished.as_str())?.into(),
+        });
+    }
+
+    Ok(res)
+}
+
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+
+const NODE_COLUMNS: &str = "g.id, g.parent_id, g.name, g.checksum, g.is_dir, g.record_id";
+
+
+fn query_nodes(conn: &Connection, sql: &str, params: &[&dyn ToSql]) -> Result<Vec<StoredNode>> {
+    let mut stmt = conn.prepare_cached(sql)?;
+    let rows = stmt.query_map(params, |row| {
+        Ok(StoredNode {
+            id: row.get(0)?,
+            parent: row.get(1)?,
+            name: row.get(2)?,
+            checksum: row.get(3)?,
+            is_dir: row.get(4)?,
+            record_id: row.get(5)?,
+        })
+    })?;
+
+    Ok(rows.collect::<rusqlite::Result<Vec<StoredNode>>>()?)
+}
+
+
+/// Stored directory `name` under `parent`.
+fn child_directory(conn: &Connection, parent: i64, name: &str) -> Result<Option<i64>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT g.id FROM graph_nodes g WHERE g.parent_id = ?1 AND g.name = ?2 AND g.is_dir = 1",
+    )?;
+    Ok(stmt.query_row(params![parent, name], |row| row.get(0)).optional()?)
+}
+
+
+/// The tree stored in `graph_nodes`, updated in place, see [`analyser::update_files`].
+struct StoredTree<'a> {
+    conn: &'a Connection,
+    root: i64,
+}
+
+
+impl analyser::MerkleTree for StoredTree<'_> {
+    type Node = i64;
+
+    fn root_node(&self) -> i64 {
+        self.root
+    }
+
+    fn directory(&mut self, parent: i64, name: &str, create: bool) -> Result<Option<i64>> {
+        match child_directory(self.conn, parent, name)? {
+            Some(id) => Ok(Some(id)),
+            None if create => {
+                self.conn.execute(
+                    "INSERT INTO graph_nodes (parent_id, name, checksum, is_dir) values (?1, ?2, '', 1)",
+                    params![parent, name],
+                )?;
+                Ok(Some(self.conn.last_insert_rowid()))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn set_file(&mut self, parent: i64, record: &FileRecord) -> Result<()> {
+        let updated = self.conn.execute(
+            "UPDATE graph_nodes SET checksum = ?3, record_id = ?4 WHERE parent_id = ?1 AND name = ?2 AND is_dir = 0",
+            params![parent, record.name, record.checksum, record.id],
+        )?;
+        if updated == 0 {
+            self.conn.execute(
+                "INSERT INTO graph_nodes (parent_id, name, checksum, is_dir, record_id) values (?1, ?2, ?3, 0, ?4)",
+                params![parent, record.name, record.checksum, record.id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_file(&mut self, parent: i64, name: &str) -> Result<bool> {
+        let removed = self.conn.execute(
+            "DELETE FROM graph_nodes WHERE parent_id = ?1 AND name = ?2 AND is_dir = 0",
+            params![parent, name],
+        )?;
+
+        Ok(removed > 0)
+    }
+
+    fn entry_checksums(&self, directory: i64) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached("SELECT g.checksum FROM graph_nodes g WHERE g.parent_id = ?1")?;
+        let checksums = stmt.query_map(params![directory], |row| row.get(0))?;
+
+        Ok(checksums.collect::<rusqlite::Result<Vec<String>>>()?)
+    }
+
+    fn set_checksum(&mut self, directory: i64, checksum: String) -> Result<()> {
+        self.conn.execute("UPDATE graph_nodes SET checksum = ?1 WHERE id = ?2", params![checksum, directory])?;
+        Ok(())
+    }
+
+    fn remove_directory(&mut self, _parent: i64, directory: i64) -> Result<()> {
+        self.conn.execute("DELETE FROM graph_nodes WHERE id = ?1", params![directory])?;
+        Ok(())
+    }
+}
+
+
+/// Rows of the volume `?1` as they were before the scan `?2`, leaving out those
+/// of scans that were given up halfway.
+const PREVIOUS_RECORDS: &str = "WITH previous AS (
+    SELECT o.* FROM index_records o WHERE o.volume_id = ?1 AND (o.scan_id IS NULL OR o.scan_id IN (
+        SELECT s.id FROM scans s WHERE s.volume_id = ?1 AND s.finished IS NOT NULL AND s.id != ?2)))";
+
+
+/// Rows `i` at or under the directory `?3`, as ranges so the location index is used.
+const UNDER_DIRECTORY: &str = "(i.path = ?3 OR (i.path > ?3 || '/' AND i.path < ?3 || '0'))";
+
+
+/// The files that differ before and after the scan `scan_id` of a volume, joined
+/// by location so the files that stayed the same are never loaded.
+fn scan_diff(conn: &Connection, volume_id: i64, scan_id: i64) -> Result<ScanDiff> {
+    let mut diff = ScanDiff::default();
+
+    let select = format!(
+        "{} SELECT {}, p.checksum FROM index_records i JOIN volumes v ON v.id = i.volume_id
+         JOIN previous p ON p.path = i.path AND p.name = i.name
+         WHERE i.scan_id = ?2 AND p.checksum != i.checksum;",
+        PREVIOUS_RECORDS, RECORD_COLUMNS,
+    );
+    let mut stmt = conn.prepare(&select)?;
+    let rows = stmt.query_map(params![volume_id, scan_id], |row| Ok((read_record_row(row)?, row.get::<_, String>(8)?)))?;
+    for row in rows {
+        let (raw, previous) = row?;
+        diff.modified.push((to_index_record(raw)?, previous));
+    }
+
+    let select = format!(
+        "{} SELECT {} FROM previous i JOIN volumes v ON v.id = i.volume_id
+         WHERE NOT EXISTS (SELECT 1 FROM index_records n
+                           WHERE n.volume_id = ?1 AND n.path = i.path AND n.name = i.name AND n.scan_id = ?2);",
+        PREVIOUS_RECORDS, RECORD_COLUMNS,
+    );
+    diff.vanished = query_records(conn, &select, params![volume_id, scan_id])?;
+
+    let select = format!(
+        "{} SELECT {} FROM index_records i JOIN volumes v ON v.id = i.volume_id
+         WHERE i.scan_id = ?2 AND NOT EXISTS (SELECT 1 FROM previous p WHERE p.path = i.path AND p.name = i.name);",
+        PREVIOUS_RECORDS, RECORD_COLUMNS,
+    );
+    diff.appeared = query_records(conn, &select, params![volume_id, scan_id])?;
+
+    Ok(diff)
+}
+
+
+/// Whether the rows picked by `select`, given the volume, the scan and a
+/// directory, hold files under that directory.
+fn holds_directory(conn: &Connection, select: &str, volume_id: i64, scan_id: i64, dir: &str) -> Result<bool> {
+    let mut stmt = conn.prepare_cached(select)?;
+    Ok(stmt.query_row(params![volume_id, scan_id, dir], |row| row.get(0))?)
+}
+
+
+/// Brings the stored tree in line with the scan `scan_id` of a volume,
+/// touching only the files that changed and the directories above them.
+fn update_graph(conn: &Connection, volume_id: i64, scan_id: i64, diff: &ScanDiff) -> Result<()> {
+    let root: i64 = match conn.query_row(
+        "SELECT g.id FROM graph_nodes g WHERE g.parent_id IS NULL", NO_PARAMS, |row| row.get(0),
+    ).optional()? {
+        Some(id) => id,
+        // nothing stored yet, the tree is built when first needed.
+        None => return Ok(()),
+    };
+
+    // rescanned files are new rows, the tree follows them without rehashing.
+    conn.execute(
+        "UPDATE graph_nodes SET record_id = (
+             SELECT n.id FROM index_records o JOIN index_records n
+             ON n.volume_id = o.volume_id AND n.path = o.path AND n.name = o.name AND n.scan_id = ?2
+             WHERE o.id = graph_nodes.record_id)
+         WHERE is_dir = 0 AND record_id IN (
+             SELECT o.id FROM index_records o WHERE o.volume_id = ?1 AND (o.scan_id IS NULL OR o.scan_id != ?2))",
+        params![volume_id, scan_id],
+    )?;
+
+    let removed: Vec<FileRecord> = diff.vanished.iter().map(misc::to_file_record).collect();
+    let set: Vec<FileRecord> = diff.modified.iter().map(|(record, _)| record).chain(&diff.appeared)
+        .map(misc::to_file_record)
+        .collect();
+    analyser::update_files(&mut StoredTree { conn, root }, &removed, &set)?;
+
+    Ok(())
+}
+
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("SELECT name FROM pragma_table_info('{}');", table))?;
+    let columns = stmt.query_map(NO_PARAMS, |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    Ok(columns.iter().any(|c| c == column))
+}
+
+
+/// Brings indexes created by older versions up to date.
+///
+/// Indexes created before volumes existed hold absolute paths. They become
+/// relative to a `/` volume, so old and new records can be told apart.
+fn migrate(conn: &Connection) -> Result<()> {
+    if !has_column(conn, "volumes", "last_seen")? {
+        conn.execute_batch(
+            "ALTER TABLE volumes ADD COLUMN last_seen text not null default '1970-01-01T00:00:00.000Z';",
+        )?;
+    }
+
+    if !has_column(conn, "index_records", "volume_id")? {
+        conn.execute_batch(
+            "ALTER TABLE index_records ADD COLUMN volume_id integer references volumes(id);
+             UPDATE index_records SET path = trim(path, '/');",
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO volumes (label, root, last_seen) values ('/', '/', ?1)",
+            params![now()],
+        )?;
+        conn.execute_batch(
+            "UPDATE index_records SET volume_id = (SELECT v.id FROM volumes v WHERE v.label = '/');",
+        )?;
+    }
+
+    if !has_column(conn, "index_records", "scan_id")? {
+        conn.execute_batch("ALTER TABLE index_records ADD COLUMN scan_id integer references scans(id);")?;
+    }
+
+    if !has_column(conn, "index_records", "size")? {
+        conn.execute_batch(
+            "ALTER TABLE index_records ADD COLUMN size integer not null default 0;
+             ALTER TABLE index_records ADD COLUMN inode integer;",
+        )?;
+    }
+
+    if !has_column(conn, "file_history", "previous_path")? {
+        conn.execute_batch(
+            "ALTER TABLE file_history ADD COLUMN previous_path text;
+             ALTER TABLE file_history ADD COLUMN previous_name text;",
+        )?;
+    }
+
+    if !has_column(conn, "index_records", "verified")? {
+        conn.execute_batch("ALTER TABLE index_records ADD COLUMN verified text;")?;
+    }
+
+    if !has_column(conn, "scans", "volume_id")? {
+        conn.execute_batch("ALTER TABLE scans ADD COLUMN volume_id integer references volumes(id);")?;
+    }
+
+    if !has_column(conn, "graph_nodes", "record_id")? {
+        // trees stored without their rows are built again when next needed.
+        conn.execute_batch(
+            "ALTER TABLE graph_nodes ADD COLUMN record_id integer;
+             DELETE FROM graph_nodes;",
+        )?;
+    }
+
+    if !has_column(conn, "volumes", "algorithm")? {
+        conn.execute_batch("ALTER TABLE volumes ADD COLUMN algorithm text not null default 'md5';")?;
+    }
+
+    Ok(())
+}
+
+
 impl IndexStorage for SQLite3 {
     fn create(&self) -> Result<()> {
-        self.conn.execute(
-            "create table if not exists index_records (
+        self.conn.execute_batch(
+            "create table if not exists volumes (
+             id integer primary key autoincrement,
+             label text not null unique,
+             uuid text,
+             root text not null,
+             last_seen text not null,
+             algorithm text not null default 'md5');
+
+             create table if not exists index_records (
              id integer primary key autoincrement,
+             volume_id integer not null references volumes(id),
+             scan_id integer references scans(id),
              checksum text not null,
              name text not null,
              path text,
-             modified text)",
-            NO_PARAMS,
+             modified text,
+             size integer not null default 0,
+             inode integer,
+             verified text);
+
+             create table if not exists scans (
+             id integer primary key autoincrement,
+             volume_id integer references volumes(id),
+             root text not null,
+             started text not null,
+             finished text);
+
+             create table if not exists scan_directories (
+             scan_id integer not null references scans(id),
+             path text not null,
+             primary key (scan_id, path));
+
+             create table if not exists file_history (
+             id integer primary key autoincrement,
+             scan_id integer not null references scans(id),
+             volume_id integer not null references volumes(id),
+             path text not null,
+             name text not null,
+             change text not null,
+             checksum text,
+             previous_checksum text,
+             modified text not null,
+             previous_path text,
+             previous_name text);
+
+             create table if not exists directory_moves (
+             scan_id integer not null references scans(id),
+             volume_id integer not null references volumes(id),
+             from_path text not null,
+             to_path text not null);
+
+             create table if not exists graph_nodes (
+             id integer primary key autoincrement,
+             parent_id integer references graph_nodes(id),
+             name text not null,
+             checksum text not null,
+             is_dir integer not null,
+             record_id integer);",
+        )?;
+
+        migrate(&self.conn)?;
+        self.conn.execute_batch(
+            "create index if not exists index_records_location on index_records (volume_id, path, name);
+             create index if not exists file_history_location on file_history (volume_id, path, name);
+             create index if not exists graph_nodes_parent on graph_nodes (parent_id, name);
+             create index if not exists graph_nodes_record on graph_nodes (record_id);
+
+             create view if not exists current_records as
+             SELECT i.* FROM index_records i
+             WHERE i.scan_id IS NULL OR i.scan_id NOT IN (
+                 SELECT s.id FROM scans s
+                 WHERE s.finished IS NULL
+                   AND (EXISTS (SELECT 1 FROM scans f WHERE f.volume_id = s.volume_id AND f.finished IS NOT NULL)
+                        OR s.id < (SELECT max(l.id) FROM scans l WHERE l.volume_id = s.volume_id)));",
         )?;
 
         Ok(())
     }
 
-    fn insert(&self, arr: &Vec<IndexRecord>) -> Result<()> {
-        for record in arr {
-            self.conn.execute(
-                "INSERT INTO index_records (checksum, name, path, modified) values (?1, ?2, ?3, ?4)",
-                params![record.checksum, record.name, record.path, record.modified.to_rfc3339_opts(SecondsFormat::Millis, true)]
+    fn insert(&self, arr: &[IndexRecord]) -> Result<()> {
+        // a single transaction per batch, otherwise sqlite syncs on every row.
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "DELETE FROM index_records
+                 WHERE volume_id = (SELECT v.id FROM volumes v WHERE v.label = ?1) AND path = ?2 AND name = ?3",
             )?;
+            for record in arr {
+                stmt.execute(params![record.volume, record.path, record.name])?;
+                insert_records(&tx, None, std::slice::from_ref(record))?;
+            }
         }
+        // records from outside a scan come with no changes to follow.
+        tx.execute_batch("DELETE FROM graph_nodes;")?;
+        tx.commit()?;
 
         Ok(())
     }
 
-    fn select(&self, name: String) -> Result<Vec<IndexRecord>> {
-        let prepared_name = format!("%{}%", name);
+    fn fetch_sorted(&self) -> Result<Vec<IndexRecord>> {
+        query_records(
+            &self.conn,
+            &format!(
+                "SELECT {} FROM current_records i JOIN volumes v ON v.id = i.volume_id
+                 ORDER BY v.label, i.path, i.name;",
+                RECORD_COLUMNS,
+            ),
+            NO_PARAMS,
+        )
+    }
+
+    fn register_volume(&self, label: &str, uuid: Option<&str>, root: &str, algorithm: Option<HashAlgorithm>) -> Result<Volume> {
+        let known = self.volume(label)?;
+
+        if let (Some(Volume { uuid: Some(known_uuid), .. }), Some(uuid)) = (&known, uuid) {
+            if known_uuid != uuid {
+                return Err(Error::Parse(format!(
+                    "volume '{}' has UUID {}, not {}", label, known_uuid, uuid,
+                )));
+            }
+        }
+        if let (Some(volume), Some(algorithm)) = (&known, algorithm) {
+            if volume.algorithm != algorithm {
+                return Err(Error::Parse(format!(
+                    "volume '{}' is hashed with {}, not {}", label, volume.algorithm.as_str(), algorithm.as_str(),
+                )));
+            }
+        }
+
+        match &known {
+            Some(volume) => {
+                self.conn.execute(
+                    "UPDATE volumes SET uuid = ?1, root = ?2, last_seen = ?3 WHERE id = ?4",
+                    params![uuid.or(volume.uuid.as_deref()), root, now(), volume.id],
+                )?;
+            },
+            None => {
+                self.conn.execute(
+                    "INSERT INTO volumes (label, uuid, root, last_seen, algorithm) values (?1, ?2, ?3, ?4, ?5)",
+                    params![label, uuid, root, now(), algorithm.unwrap_or_default().as_str()],
+                )?;
+            },
+        }
+
+        match self.volume(label)? {
+            Some(volume) => Ok(volume),
+            None => Err(Error::Parse(format!("volume '{}' could not be registered", label))),
+        }
+    }
 
+    fn volume(&self, label: &str) -> Result<Option<Volume>> {
+        let mut volumes = query_volumes(
+            &self.conn,
+            &format!("SELECT {} FROM volumes v WHERE v.label = ?1;", VOLUME_COLUMNS),
+            params![label],
+        )?;
+
+        Ok(volumes.pop())
+    }
+
+    fn volumes(&self) -> Result<Vec<Volume>> {
+        query_volumes(
+            &self.conn,
+            &format!("SELECT {} FROM volumes v ORDER BY v.label;", VOLUME_COLUMNS),
+            NO_PARAMS,
+        )
+    }
+
+    fn copies(&self, checksum: &str) -> Result<Vec<IndexRecord>> {
+        query_records(
+            &self.conn,
+            &format!(
+                "SELECT {} FROM current_records i JOIN volumes v ON v.id = i.volume_id
+                 WHERE i.checksum = ?1
+                 ORDER BY v.label, i.path, i.name;",
+                RECORD_COLUMNS,
+            ),
+            params![checksum],
+        )
+    }
+
+    fn start_scan(&self, volume_id: i64, root: &str) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO scans (volume_id, root, started) values (?1, ?2, ?3)",
+            params![volume_id, root, now()],
+        )?;
+
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn unfinished_scan(&self, root: &str) -> Result<Option<i64>> {
+        let scan_id = self.conn.query_row(
+            "SELECT s.id FROM scans s
+             WHERE s.root = ?1 AND s.finished IS NULL
+             ORDER BY s.id DESC LIMIT 1;",
+            params![root],
+            |row| row.get(0),
+        ).optional()?;
+
+        Ok(scan_id)
+    }
+
+    fn completed_directories(&self, scan_id: i64) -> Result<Vec<String>> {
         let mut stmt = self.conn.prepare(
-            "SELECT i.id, i.checksum, i.name, i.path, i.modified
-             FROM index_records i
-             WHERE i.name LIKE $1 ;",
-        )?;
-
-        let records = stmt.query_map(params![prepared_name], |row| {
-	    let str_modifeid: String = row.get(4)?;
-	    Ok(IndexRecord {
-                id: row.get(0)?,
-                checksum: row.get(1)?,
-                name: row.get(2)?,
-                path: row.get(3)?,
-		modified: DateTime::parse_from_rfc3339(str_modifeid.as_str()).expect("Failed to parse date from db").into(),
-            })
-        })?;
+            "SELECT d.path FROM scan_directories d WHERE d.scan_id = ?1;",
+        )?;
 
-        let res = records.map(|r| r.unwrap()).collect::<Vec<IndexRecord>>();
-        Ok(res)
+        let paths = stmt.query_map(params![scan_id], |row| row.get(0))?;
+        Ok(paths.collect::<rusqlite::Result<Vec<String>>>()?)
     }
 
-    fn fetch_sorted(&self) -> Result<Vec<IndexRecord>> {
+    fn partially_scanned_files(&self, scan_id: i64) -> Result<Vec<String>> {
         let mut stmt = self.conn.prepare(
-            "SELECT i.id, i.checksum, i.name, i.path, i.modified
-             FROM index_records i
-             ORDER BY i.path, i.name;",
-        )?;
-
-        let records = stmt.query_map(NO_PARAMS, |row| {
-	    let str_modifeid: String = row.get(4)?;
-	    Ok(IndexRecord {
-                id: row.get(0)?,
-                checksum: row.get(1)?,
-                name: row.get(2)?,
-                path: row.get(3)?,
-		modified: DateTime::parse_from_rfc3339(str_modifeid.as_str()).expect("Failed to parse date from db").into(),
-            })
+            "SELECT CASE WHEN i.path = '' THEN s.root
+                         WHEN s.root = '/' THEN '/' || i.path
+                         ELSE s.root || '/' || i.path END AS directory, i.name
+             FROM index_records i JOIN scans s ON s.id = i.scan_id
+             WHERE i.scan_id = ?1
+               AND directory NOT IN (SELECT d.path FROM scan_directories d WHERE d.scan_id = ?1);",
+        )?;
+
+        let files = stmt.query_map(params![scan_id], |row| {
+            let directory: String = row.get(0)?;
+            let name: String = row.get(1)?;
+            Ok(Path::new(&directory).join(name).to_string_lossy().into_owned())
         })?;
+        Ok(files.collect::<rusqlite::Result<Vec<String>>>()?)
+    }
+
+    fn checkpoint(&self, scan_id: i64, directories: &[String], arr: &[IndexRecord]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        insert_records(&tx, Some(scan_id), arr)?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR IGNORE INTO scan_directories (scan_id, path) values (?1, ?2)",
+            )?;
+            for directory in directories {
+                stmt.execute(params![scan_id, directory])?;
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn finish_scan(&self, scan_id: i64) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE scans SET finished = ?1 WHERE id = ?2",
+            params![now(), scan_id],
+        )?;
+
+        let volume_id: Option<i64> = tx.query_row(
+            "SELECT s.volume_id FROM scans s WHERE s.id = ?1;",
+            params![scan_id],
+            |row| row.get(0),
+        )?;
+
+        // scans from before volumes existed have nothing to compare with.
+        if let Some(volume_id) = volume_id {
+            let diff = scan_diff(&tx, volume_id, scan_id)?;
+            let had_directory = format!("{} SELECT EXISTS (SELECT 1 FROM previous i WHERE {})", PREVIOUS_RECORDS, UNDER_DIRECTORY);
+            let has_directory = format!(
+                "SELECT EXISTS (SELECT 1 FROM index_records i WHERE i.volume_id = ?1 AND i.scan_id = ?2 AND {})",
+                UNDER_DIRECTORY,
+            );
+            let changes = history::detect_changes(
+                scan_id,
+                &diff,
+                |dir| holds_directory(&tx, &had_directory, volume_id, scan_id, dir),
+                |dir| holds_directory(&tx, &has_directory, volume_id, scan_id, dir),
+            )?;
+            insert_changes(&tx, scan_id, volume_id, &changes)?;
+
+            update_graph(&tx, volume_id, scan_id, &diff)?;
+            tx.execute(
+                "DELETE FROM index_records WHERE volume_id = ?1 AND (scan_id IS NULL OR scan_id != ?2)",
+                params![volume_id, scan_id],
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        query_snapshots(
+            &self.conn,
+            "SELECT s.id, v.label, s.started, s.finished FROM scans s JOIN volumes v ON v.id = s.volume_id
+             WHERE s.finished IS NOT NULL ORDER BY s.id;",
+            NO_PARAMS,
+        )
+    }
+
+    fn snapshot(&self, id: i64) -> Result<Option<Snapshot>> {
+        let mut snapshots = query_snapshots(
+            &self.conn,
+            "SELECT s.id, v.label, s.started, s.finished FROM scans s JOIN volumes v ON v.id = s.volume_id
+             WHERE s.finished IS NOT NULL AND s.id = ?1;",
+            params![id],
+        )?;
 
-        let res = records.map(|r| r.unwrap()).collect::<Vec<IndexRecord>>();
-        Ok(res)
+        Ok(snapshots.pop())
     }
 
-    fn fetch_indexed(&self) -> Result<HashMap<String, IndexRecord>> {
-	let mut stmt = self.conn.prepare(
-            "SELECT i.id, i.checksum, i.name, i.path, i.modified
-             FROM index_records i
-             ORDER BY i.path, i.name;",
+    fn history(&self, volume: &str, path: &str, name: &str) -> Result<Vec<FileChange>> {
+        query_changes(
+            &self.conn,
+            &format!(
+                "SELECT {} FROM file_history h JOIN volumes v ON v.id = h.volume_id
+                 WHERE v.label = ?1
+                 AND ((h.path = ?2 AND h.name = ?3) OR (h.previous_path = ?2 AND h.previous_name = ?3))
+                 ORDER BY h.scan_id, h.id;",
+                CHANGE_COLUMNS,
+            ),
+            params![volume, path, name],
+        )
+    }
+
+    fn changes_between(&self, volume: &str, from: i64, to: i64) -> Result<Vec<FileChange>> {
+        query_changes(
+            &self.conn,
+            &format!(
+                "SELECT {} FROM file_history h JOIN volumes v ON v.id = h.volume_id
+                 WHERE v.label = ?1 AND h.scan_id > ?2 AND h.scan_id <= ?3
+                 ORDER BY h.scan_id, h.id;",
+                CHANGE_COLUMNS,
+            ),
+            params![volume, from, to],
+        )
+    }
+
+    fn directory_moves_between(&self, volume: &str, from: i64, to: i64) -> Result<Vec<DirectoryMove>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT d.scan_id, v.label, d.from_path, d.to_path
+             FROM directory_moves d JOIN volumes v ON v.id = d.volume_id
+             WHERE v.label = ?1 AND d.scan_id > ?2 AND d.scan_id <= ?3
+             ORDER BY d.scan_id, d.from_path;",
         )?;
 
-        let records = stmt.query_map(NO_PARAMS, |row| {
-	    let str_modifeid: String = row.get(4)?;
-	    Ok(IndexRecord {
-                id: row.get(0)?,
-                checksum: row.get(1)?,
-                name: row.get(2)?,
-                path: row.get(3)?,
-		modified: DateTime::parse_from_rfc3339(str_modifeid.as_str()).expect("Failed to parse date from db").into(),
-            })
+        let moves = stmt.query_map(params![volume, from, to], |row| {
+            Ok(DirectoryMove { snapshot: row.get(0)?, volume: row.get(1)?, from: row.get(2)?, to: row.get(3)? })
         })?;
+        Ok(moves.collect::<rusqlite::Result<Vec<DirectoryMove>>>()?)
+    }
+
+    fn record(&self, id: u32) -> Result<Option<IndexRecord>> {
+        let mut records = query_records(
+            &self.conn,
+            &format!(
+                "SELECT {} FROM index_records i JOIN volumes v ON v.id = i.volume_id WHERE i.id = ?1;",
+                RECORD_COLUMNS,
+            ),
+            params![id],
+        )?;
+
+        Ok(records.pop())
+    }
+
+    fn count_records(&self, volume: &str) -> Result<usize> {
+        let count: i64 = self.conn.query_row(
+            "SELECT count(*) FROM current_records i JOIN volumes v ON v.id = i.volume_id WHERE v.label = ?1;",
+            params![volume],
+            |row| row.get(0),
+        )?;
+
+        Ok(count as usize)
+    }
+
+    fn count_records_under(&self, volume: &str, path: &str) -> Result<usize> {
+        let count: i64 = self.conn.query_row(
+            "SELECT count(*) FROM current_records i JOIN volumes v ON v.id = i.volume_id
+             WHERE v.label = ?1 AND (?2 = '' OR i.path = ?2 OR substr(i.path, 1, length(?2) + 1) = ?2 || '/');",
+            params![volume, path],
+            |row| row.get(0),
+        )?;
+
+        Ok(count as usize)
+    }
 
-	let mut res = HashMap::new();
-	for _record in records {
-	    let clean_record = _record.unwrap();
-	    let path = clean_record.path.clone();
-	    
-	    match res.insert(path.clone(), clean_record) {
-		None => continue,
-		_ => println!("Repeated file in database: {}", path),
-	    }
-	}
-
-	return Ok(res);
+    fn least_recently_verified(&self, volume: &str, limit: usize) -> Result<Vec<IndexRecord>> {
+        query_records(
+            &self.conn,
+            &format!(
+                "SELECT {} FROM current_records i JOIN volumes v ON v.id = i.volume_id
+                 WHERE v.label = ?1
+                 ORDER BY i.verified IS NOT NULL, i.verified, i.id
+                 LIMIT ?2;",
+                RECORD_COLUMNS,
+            ),
+            params![volume, limit as i64],
+        )
+    }
+
+    fn mark_verified(&self, ids: &[u32]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let verified = now();
+            let mut stmt = tx.prepare_cached("UPDATE index_records SET verified = ?1 WHERE id = ?2")?;
+            for id in ids {
+                stmt.execute(params![verified, id])?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn graph_root(&self) -> Result<Option<StoredNode>> {
+        let mut nodes = query_nodes(
+            &self.conn,
+            &format!("SELECT {} FROM graph_nodes g WHERE g.parent_id IS NULL", NODE_COLUMNS),
+            NO_PARAMS,
+        )?;
+
+        Ok(nodes.pop())
+    }
+
+    fn graph_children(&self, parent: i64) -> Result<Vec<StoredNode>> {
+        query_nodes(
+            &self.conn,
+            &format!("SELECT {} FROM graph_nodes g WHERE g.parent_id = ?1 ORDER BY g.id", NODE_COLUMNS),
+            params![parent],
+        )
+    }
+
+    fn save_graph(&self, nodes: &[StoredNode]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute_batch("DELETE FROM graph_nodes;")?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO graph_nodes (id, parent_id, name, checksum, is_dir, record_id) values (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for node in nodes {
+                stmt.execute(params![node.id, node.parent, node.name, node.checksum, node.is_dir, node.record_id])?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(())
     }
 }
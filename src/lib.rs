@@ -0,0 +1,57 @@
+//! Indexes files by content checksum and finds duplicated files and directories.
+//!
+//! The crate is organised around a small pipeline:
+//!
+//! - [`file_handler`] scans directories (or lists of files) and hashes them;
+//! - [`index_db`] keeps the resulting [`IndexRecord`]s in a persistent index,
+//!   [`volumes`] finding the volumes of the index a command is about;
+//! - [`analyser`] builds a directory tree out of [`FileRecord`]s, giving every
+//!   directory a checksum of its contents, and finds duplicates in it;
+//! - [`history`] follows files across the snapshots taken by every scan;
+//! - [`report`] presents and exports the duplicates found, [`duplicates`]
+//!   defining the schema of their reports, and [`graph_export`] exports the
+//!   directory tree for Graphviz, Gephi or Cytoscape;
+//! - [`review`] browses the duplicates in the terminal, writing what to keep
+//!   and delete to a plan;
+//! - [`usage`] tells the size of every directory, and how much of it is
+//!   duplicated elsewhere;
+//! - [`verify`] rehashes indexed files to catch silent corruption;
+//! - [`manifest`] exchanges checksums with `md5sum` and `sha256sum` manifests.
+//!
+//! ```no_run
+//! use rusty_index::{analyser, file_handler, index_db, misc};
+//! use rusty_index::{GraphStorageInterface, IndexStorage, ProgressSender};
+//!
+//! let storage = index_db::initalise_db("index.db").unwrap();
+//! storage.create().unwrap();
+//! file_handler::stream_scan_directory(String::from("/data"), &storage, false, ProgressSender::none()).unwrap();
+//!
+//! let records = storage.fetch_sorted().unwrap().iter().map(misc::to_file_record).collect();
+//! let mut graph = analyser::initialise_graph();
+//! graph.bulk_insert(records).unwrap();
+//! let duplicates = graph.find_duplicates();
+//! ```
+
+pub mod analyser;
+pub mod compare;
+pub mod config;
+pub mod duplicates;
+pub mod error;
+pub mod file_handler;
+pub mod graph_export;
+pub mod history;
+pub mod index_db;
+pub mod manifest;
+pub mod misc;
+pub mod progress;
+pub mod report;
+pub mod review;
+pub mod usage;
+pub mod verify;
+pub mod volumes;
+
+pub use analyser::{FileRecord, GNode, GraphStorage, GraphStorageInterface};
+pub use error::{Error, Result};
+pub use file_handler::{hash_file, scan, stream_scan_directory, Delimiter, HashAlgorithm, ScanOptions, ScanSource};
+pub use index_db::{IndexRecord, IndexStorage, SQLite3};
+pub use progress::{ProgressEvent, ProgressFormat, ProgressSender};
@@ -1,172 +1,414 @@
+use std::env;
+use std::fs;
 use std::fs::File;
-use std::io::prelude::*;
-use std::collections::HashMap;
-
-extern crate petgraph;
-use petgraph::dot::Dot;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
+use std::process;
 
 extern crate clap;
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
 extern crate colored;
 use colored::*;
 
-extern crate serde_json;
-use serde_json::Result;
+extern crate ctrlc;
 
-mod index_db;
-use index_db::{IndexRecord, IndexStorage};
+extern crate rusty_index;
+use rusty_index::{compare, config, duplicates, file_handler, graph_export, history, index_db, manifest, progress, report, review, usage, verify, volumes};
+use rusty_index::{Error, Result};
+use rusty_index::file_handler::{Delimiter, HashAlgorithm, ScanOptions, ScanSource};
+use rusty_index::index_db::{IndexRecord, IndexStorage};
+use rusty_index::duplicates::ReportFormat;
+use rusty_index::graph_export::{GraphExportOptions, GraphFormat};
+use rusty_index::manifest::ManifestFormat;
+use rusty_index::progress::{ProgressFormat, ProgressSender};
+use rusty_index::report::{display_comparison, display_copies, display_diff, display_history, display_snapshots};
+use rusty_index::report::{display_usage, display_verification, display_volumes};
+use rusty_index::verify::VerifyOptions;
 
-mod analyser;
-use analyser::{FileRecord, GraphStorageInterface};
 
-mod file_handler;
-use file_handler::load_and_process_files;
+fn main() {
+    let config = App::new("rusty-index")
+        .arg(Arg::with_name("db").long("db").takes_value(true).global(true)
+             .help("Index database to use, instead of the profile's or $RUSTY_INDEX_DB"))
+        .arg(Arg::with_name("profile").long("profile").short("p").takes_value(true).global(true)
+             .help("Named profile from the configuration file"))
+        .arg(Arg::with_name("config").long("config").takes_value(true).global(true)
+             .help("Configuration file, defaults to ~/.config/rusty-index/config.toml"))
+        .arg(Arg::with_name("dot-out").long("dot-out").takes_value(true).global(true)
+             .default_value(report::DEFAULT_DOT_OUT)
+             .help("Where to write the dot export of the graph"))
+        .arg(Arg::with_name("json-out").long("json-out").takes_value(true).global(true)
+             .default_value(report::DEFAULT_JSON_OUT)
+             .help("Where to write the JSON export of the duplicates"))
+        .arg(Arg::with_name("json-format").long("json-format").takes_value(true).global(true)
+             .possible_values(&["json", "ndjson"]).default_value("json")
+             .help("A single JSON document, or one line per duplicate group for streaming"))
+        .arg(Arg::with_name("csv-out").long("csv-out").takes_value(true).global(true)
+             .help("Also write the duplicates as CSV, one row per copy"))
+        .arg(Arg::with_name("html-out").long("html-out").takes_value(true).global(true)
+             .help("Also write the duplicates as an HTML page to review them and select copies"))
+        .subcommand(SubCommand::with_name("generate")
+                    .about("Reports the duplicates of the whole index, and exports its directory graph")
+                    .arg(Arg::with_name("graph-format").long("graph-format").takes_value(true)
+                         .possible_values(&["dot", "graphml", "cytoscape"]).default_value("dot")
+                         .help("Graphviz dot, GraphML for Gephi, or Cytoscape JSON"))
+                    .arg(Arg::with_name("max-depth").long("max-depth").takes_value(true)
+                         .help("Only export this many levels below the root"))
+                    .arg(Arg::with_name("duplicates-only").long("duplicates-only")
+                         .help("Leave out the branches without duplicates"))
+                    .arg(Arg::with_name("root").long("root").takes_value(true)
+                         .help("Export the tree from this directory, as volume or volume:path/to/dir")))
+        .subcommand(SubCommand::with_name("review")
+                    .about("Browses the duplicate groups in the terminal, marking copies to keep or delete")
+                    .arg(Arg::with_name("plan").long("plan").takes_value(true).default_value("plan.txt")
+                         .help("Where to write the marks, for a separate step to apply. Nothing is deleted")))
+        .subcommand(SubCommand::with_name("tree")
+                    .about("Shows the size of every directory, and how much of it is duplicated elsewhere")
+                    .arg(Arg::with_name("depth").long("depth").takes_value(true).default_value("2")
+                         .help("Levels of directories to show below the root"))
+                    .arg(Arg::with_name("root").long("root").takes_value(true)
+                         .help("Directory to start from, as volume or volume:path/to/dir")))
+        .subcommand(SubCommand::with_name("volumes")
+                    .about("Lists the indexed volumes, and whether they are online"))
+        .subcommand(SubCommand::with_name("where")
+                    .about("Lists every indexed copy of a file, including those on offline volumes")
+                    .arg(Arg::with_name("file").takes_value(true).required(true)))
+        .subcommand(SubCommand::with_name("snapshots")
+                    .about("Lists the snapshots taken by every completed scan of a volume"))
+        .subcommand(SubCommand::with_name("history")
+                    .about("Shows when the contents of a file changed, across snapshots")
+                    .arg(Arg::with_name("path").takes_value(true).required(true)
+                         .help("File on disk, or volume:path/to/file")))
+        .subcommand(SubCommand::with_name("diff-snapshots")
+                    .about("Lists the files added, removed, modified and moved between two snapshots of a volume")
+                    .arg(Arg::with_name("from").takes_value(true).required(true))
+                    .arg(Arg::with_name("to").takes_value(true).required(true)))
+        .subcommand(SubCommand::with_name("verify")
+                    .about("Rehashes indexed files, flagging content that changed while its modification time did not")
+                    .arg(Arg::with_name("sample").long("sample").takes_value(true).default_value("100")
+                         .help("Percentage of the files to verify, the ones verified the longest ago first"))
+                    .arg(Arg::with_name("volume").long("volume").takes_value(true)
+                         .help("Only verify the files of this volume")))
+        .subcommand(SubCommand::with_name("compare")
+                    .about("Compares two indexes by content, such as a primary and its backup")
+                    .arg(Arg::with_name("left").long("left").takes_value(true).required(true))
+                    .arg(Arg::with_name("right").long("right").takes_value(true).required(true))
+                    .arg(Arg::with_name("left-volume").long("left-volume").takes_value(true)
+                         .help("Only compare this volume of the left index"))
+                    .arg(Arg::with_name("right-volume").long("right-volume").takes_value(true)
+                         .help("Only compare this volume of the right index"))
+                    .arg(Arg::with_name("copy-list").long("copy-list").takes_value(true)
+                         .help("Write the files only on the left there, relative to their volume root, for rsync --files-from"))
+                    .arg(Arg::with_name("null").long("null").short("0")
+                         .help("NUL separate the copy list, for rsync --from0")))
+        .subcommand(SubCommand::with_name("export-manifest")
+                    .about("Writes the checksums of a volume as a manifest, to check with md5sum -c or sha256sum -c")
+                    .arg(Arg::with_name("volume").long("volume").takes_value(true)
+                         .help("Volume to export, required when the index has several"))
+                    .arg(Arg::with_name("format").long("format").takes_value(true)
+                         .possible_values(&["gnu", "bsd"]).default_value("gnu")
+                         .help("Lines as printed by md5sum and sha256sum, or by their --tag BSD style"))
+                    .arg(Arg::with_name("output").long("output").short("o").takes_value(true).required(true)
+                         .help("File to write the manifest to")))
+        .subcommand(SubCommand::with_name("import-manifest")
+                    .about("Records the checksums of a manifest as a virtual volume, without reading the files")
+                    .arg(Arg::with_name("manifest").takes_value(true).required(true)
+                         .help("md5sum or sha256sum output, in either format"))
+                    .arg(Arg::with_name("volume").long("volume").takes_value(true).required(true)
+                         .help("Label of the virtual volume")))
+        .subcommand(SubCommand::with_name("scan")
+                    .about("Hashes files into the index, and optionally reports duplicates among them")
+                    .arg(Arg::with_name("path").takes_value(true).multiple(true)
+                         .help("Directories or files to scan, defaults to the profile roots or the current directory"))
+                    .arg(Arg::with_name("from-stdin").long("from-stdin").conflicts_with("path")
+                         .help("Read the files to scan from stdin, one path per line"))
+                    .arg(Arg::with_name("files-from").long("files-from").takes_value(true)
+                         .conflicts_with_all(&["path", "from-stdin"])
+                         .help("Read the files to scan from the given file, one path per line"))
+                    .arg(Arg::with_name("volume").long("volume").takes_value(true).multiple(true).number_of_values(1)
+                         .help("Volume label of each path in order, or of the listed files, then rooted at the current directory"))
+                    .arg(Arg::with_name("uuid").long("uuid").takes_value(true).multiple(true).number_of_values(1)
+                         .help("Filesystem UUID of each labelled volume in order"))
+                    .arg(Arg::with_name("hash").long("hash").takes_value(true).possible_values(&["md5", "sha256"])
+                         .help("Hash function of new volumes, defaults to md5; known volumes keep theirs"))
+                    .arg(Arg::with_name("null").long("null").short("0")
+                         .help("File lists are NUL separated, as printed by find -print0"))
+                    .arg(Arg::with_name("store").long("store").overrides_with("no-store")
+                         .help("Save the records into the index (default)"))
+                    .arg(Arg::with_name("no-store").long("no-store").overrides_with("store")
+                         .help("Only hash the files, leaving the index untouched"))
+                    .arg(Arg::with_name("report").long("report")
+                         .help("Report duplicates among the scanned files once done"))
+                    .arg(Arg::with_name("resume").long("resume")
+                         .help("Continue the last interrupted scan of the same path"))
+                    .arg(Arg::with_name("progress").long("progress").takes_value(true)
+                         .possible_values(&["human", "json", "none"]).default_value("human")
+                         .help("How to report progress while scanning, on stderr; json gives one object per line")))
+        .get_matches();
 
-mod graph_storage;
-use graph_storage::mock_process_base_dir;
+    if let Err(e) = run(&config) {
+        eprintln!("{} {}", "Error:".red().bold(), e);
+        process::exit(e.exit_code());
+    }
+}
 
-mod misc;
-use misc::to_file_record;
-use misc::to_index_record;
 
-fn export_graph(graph: &analyser::GraphStorage) {
-    let mut f = File::create("example1.dot").unwrap();
-    let output = format!("{:?}", Dot::new(&graph.graph));
+fn run(config: &ArgMatches) -> Result<()> {
+    let settings = match config.value_of("config") {
+        Some(path) => config::load_config(Path::new(path))?,
+        None => match config::default_config_path() {
+            Some(path) => config::load_config(&path)?,
+            None => config::Config::default(),
+        },
+    };
+    let profile = settings.profile(config.value_of("profile"))?;
 
-    println!("Writing dot file with final results.");
-    match f.write_all(&output.as_bytes()) {
-        Ok(_) => println!("All done. Have a nice day in the world."),
-        Err(e) => println!("Error writing to file {:?}", e),
+    if let Some(_matches) = config.subcommand_matches("compare") {
+        return compare_indexes(_matches);
     }
-}
 
+    let file_name = config::resolve_db_path(
+        config.value_of("db"), &settings, config.value_of("profile"), env::var(config::DB_ENV_VAR).ok(),
+    )?;
+    if !Path::new(&file_name).exists() {
+        eprintln!("{} {} does not exist, a new empty index will be created.", "Warning:".yellow().bold(), file_name);
+    }
+    let data_source = index_db::initalise_db(&file_name)?;
+
+    let dot_out = config.value_of("dot-out").unwrap_or(report::DEFAULT_DOT_OUT);
+    let json_out = config.value_of("json-out").unwrap_or(report::DEFAULT_JSON_OUT);
+    let json_format = ReportFormat::parse(config.value_of("json-format").unwrap_or("json"))?;
+    let mut report_outputs = vec![(json_out, json_format)];
+    if let Some(csv_out) = config.value_of("csv-out") {
+        report_outputs.push((csv_out, ReportFormat::Csv));
+    }
+    if let Some(html_out) = config.value_of("html-out") {
+        report_outputs.push((html_out, ReportFormat::Html));
+    }
 
-fn display_result(duplicates: &HashMap<String, Vec<String>>) {
-    println!("{}", "Here are he results :".green().bold());
-    println!("");
-    
-    for (hash, paths) in duplicates {
-	println!("{} {}", "Hash :".red(), hash.magenta());
+    data_source.create()?;
+    eprintln!("Database initialised or verified");
 
-	for p in paths {
-	    if p.ends_with("/") {
-		println!("\t {} \t \u{1F4C2}", p.blue().bold());
-	    } else {
-		println!("\t{}", p.blue().bold());
-	    }
-	}
+    if let Some(_matches) = config.subcommand_matches("generate") {
+        let options = GraphExportOptions {
+            format: GraphFormat::parse(_matches.value_of("graph-format").unwrap_or("dot"))?,
+            max_depth: parse_depth(_matches.value_of("max-depth"))?,
+            duplicates_only: _matches.is_present("duplicates-only"),
+            root: _matches.value_of("root").map(graph_export::parse_root).unwrap_or_default(),
+        };
 
-	println!("");
-    }
-    
-}
+        let index = duplicates::load_index(&data_source)?;
+        eprintln!("Processing {} nodes from the database.", index.graph.graph.node_count());
+        report::report_graph(&index.graph, dot_out, &options, &index.records, index.roots, &report_outputs)?;
+
+    } else if let Some(_matches) = config.subcommand_matches("review") {
+        let index = duplicates::load_index(&data_source)?;
+        let report = duplicates::build_report(&index.graph, &index.records, index.roots);
+
+        let plan = _matches.value_of("plan").unwrap_or("plan.txt");
+        review::run(&report, &index.graph, &index.records, plan)?;
+
+    } else if let Some(_matches) = config.subcommand_matches("tree") {
+        let index = duplicates::load_index(&data_source)?;
+        let root = _matches.value_of("root").map(graph_export::parse_root).unwrap_or_default();
+        let usage = usage::directory_usage(&index.graph, &index.records, &root, parse_depth(_matches.value_of("depth"))?)?;
+        display_usage(&usage, &index.roots);
+
+    } else if config.subcommand_matches("volumes").is_some() {
+        display_volumes(&data_source.volumes()?);
+
+    } else if let Some(_matches) = config.subcommand_matches("where") {
+        let file = _matches.value_of("file").unwrap_or_default();
+        let copies = volumes::copies_of(&data_source, &file)?;
+        if copies.is_empty() {
+            println!("No copy of {} in the index.", file);
+        } else {
+            println!("{} copies of {}:", copies.len(), file);
+            display_copies(&copies, &data_source.volumes()?);
+        }
+
+    } else if config.subcommand_matches("snapshots").is_some() {
+        display_snapshots(&data_source.snapshots()?);
+
+    } else if let Some(_matches) = config.subcommand_matches("history") {
+        let path = _matches.value_of("path").unwrap_or_default();
+        let changes = history::file_history(&data_source, path, &file_handler::get_current_dir()?)?;
+
+        if changes.is_empty() {
+            println!("No history of {} in the index.", path);
+        } else {
+            display_history(&changes);
+        }
+
+    } else if let Some(_matches) = config.subcommand_matches("diff-snapshots") {
+        let from = parse_snapshot(_matches.value_of("from"))?;
+        let to = parse_snapshot(_matches.value_of("to"))?;
+        display_diff(&history::diff_snapshots(&data_source, from, to)?);
+
+    } else if let Some(_matches) = config.subcommand_matches("verify") {
+        let sample = _matches.value_of("sample").unwrap_or_default();
+        let options = VerifyOptions {
+            percent: sample.parse().map_err(|_| Error::Parse(format!("'{}' is not a percentage", sample)))?,
+            volume: _matches.value_of("volume").map(String::from),
+        };
 
+        let report = verify::verify(&data_source, &options)?;
+        display_verification(&report);
+        let damaged = report.damaged();
+        if damaged > 0 {
+            return Err(Error::Damaged(damaged));
+        }
+
+    } else if let Some(_matches) = config.subcommand_matches("export-manifest") {
+        let volume = volumes::pick_volume(&data_source, _matches.value_of("volume"))?;
+        let format = ManifestFormat::parse(_matches.value_of("format").unwrap_or("gnu"))?;
+
+        let output = _matches.value_of("output").unwrap_or_default();
+        let count = manifest::export_manifest(&data_source, &volume, format, io::BufWriter::new(File::create(output)?))?;
+        println!("{} {} checksums of {} written to {}.", count, volume.algorithm.as_str(), volume.label, output);
+
+    } else if let Some(_matches) = config.subcommand_matches("import-manifest") {
+        let path = _matches.value_of("manifest").unwrap_or_default();
+        let label = _matches.value_of("volume").unwrap_or_default();
+        let count = manifest::import_manifest_file(&data_source, path, label)?;
+        println!("{} checksums imported into virtual volume {}.", count, label);
+
+    } else if let Some(_matches) = config.subcommand_matches("scan") {
+        let store = !_matches.is_present("no-store");
+        let delimiter = if _matches.is_present("null") { Delimiter::Nul } else { Delimiter::Newline };
+        let mut labels = _matches.values_of("volume").into_iter().flatten().map(String::from);
+        let mut uuids = _matches.values_of("uuid").into_iter().flatten().map(String::from);
+        let sources: Vec<(ScanSource, Option<String>, Option<String>)> = if let Some(list) = _matches.value_of("files-from") {
+            let source = ScanSource::List(Box::new(BufReader::new(File::open(list)?)), delimiter);
+            vec![(source, labels.next(), uuids.next())]
+        } else if _matches.is_present("from-stdin") {
+            let source = ScanSource::List(Box::new(BufReader::new(io::stdin())), delimiter);
+            vec![(source, labels.next(), uuids.next())]
+        } else {
+            match (_matches.values_of("path"), profile) {
+                (Some(paths), _) => paths
+                    .map(|path| (ScanSource::Directory(String::from(path)), labels.next(), uuids.next()))
+                    .collect(),
+                (None, Some(p)) if !p.roots.is_empty() || !p.volumes.is_empty() => p.sources().into_iter()
+                    .map(|(root, label, uuid)| (ScanSource::Directory(root), label, uuid))
+                    .collect(),
+                _ => vec![(ScanSource::Directory(file_handler::get_current_dir()?), labels.next(), uuids.next())],
+            }
+        };
 
-fn export_result(duplicates: &HashMap<String, Vec<String>>) {
-    let file_name = "duplicate-results.json";
-    println!("{}{}", "Exporting the results to  :".green().bold(), file_name.blue().bold());
-    println!("");
+        ctrlc::set_handler(|| {
+            if file_handler::is_interrupted() {
+                eprintln!("Aborting, dropping what is not saved yet.");
+                file_handler::abort();
+            } else {
+                eprintln!("Interrupted, saving what has been processed so far. Press Ctrl-C again to abort.");
+                file_handler::interrupt();
+            }
+        }).map_err(|e| Error::Io(io::Error::other(e)))?;
+
+        let mut scanned = Vec::<IndexRecord>::new();
+        for (source, volume, uuid) in sources {
+            if let ScanSource::Directory(path) = &source {
+                if let Some(offline) = volumes::offline_volume(&data_source, path, volume.as_deref(), &file_handler::get_current_dir()?)? {
+                    eprintln!("Volume {} is {}, keeping its records.", offline.label, report::volume_state(&offline));
+                    continue;
+                }
+            }
+
+            let (progress, reporter) = match _matches.value_of("progress") {
+                Some("json") => {
+                    let (tx, handle) = progress::spawn_reporter(ProgressFormat::Json);
+                    (tx, Some(handle))
+                },
+                Some("human") => {
+                    let (tx, handle) = progress::spawn_reporter(ProgressFormat::Human);
+                    (tx, Some(handle))
+                },
+                _ => (ProgressSender::none(), None),
+            };
+
+            match (&source, store) {
+                (ScanSource::Directory(path), true) => eprintln!("Scanning {} and saving into {}.", path, file_name),
+                (ScanSource::Directory(path), false) => eprintln!("Scanning {}.", path),
+                (ScanSource::List(..), true) => eprintln!("Scanning listed files and saving into {}.", file_name),
+                (ScanSource::List(..), false) => eprintln!("Scanning listed files."),
+            }
+
+            let options = ScanOptions {
+                store,
+                resume: _matches.is_present("resume"),
+                volume,
+                uuid,
+                algorithm: _matches.value_of("hash").map(HashAlgorithm::parse).transpose()?,
+                progress,
+            };
+            let keep = _matches.is_present("report");
+            let result = file_handler::scan(source, &data_source, options, |record| {
+                if keep {
+                    scanned.push(record.clone());
+                }
+            });
+            if let Some(handle) = reporter {
+                // a reporter that died only loses progress lines, not records.
+                let _ = handle.join();
+            }
+
+            let total = result?;
+            if store {
+                eprintln!("{} records successfully inserted", total);
+            } else {
+                eprintln!("{} files scanned", total);
+            }
+
+            if file_handler::is_interrupted() {
+                if store {
+                    eprintln!("Scan interrupted, run it again with --resume to continue.");
+                }
+                return Err(Error::Interrupted);
+            }
+        }
+
+        if _matches.is_present("report") {
+            let roots = duplicates::scan_roots(&data_source, &scanned)?;
+            report::report_duplicates(&scanned, roots, dot_out, &report_outputs)?;
+        }
 
-    let json_data = serde_json::to_string(duplicates).expect("Error serialising duplicate_results to JSON");
-    let mut file = std::fs::File::create(file_name).expect("Couldn't not open file to save result.");
-    file.write(json_data.as_bytes()).expect("Could not write result into file");
+    } else {
+        println!("You need to either scan or generate, otherwise there is nothing to do.");
+    }
+
+    Ok(())
 }
 
 
-fn main() {
-    let config = App::new("rusty-index")
-        .subcommand(SubCommand::with_name("parse"))
-        .subcommand(SubCommand::with_name("generate"))
-        .subcommand(SubCommand::with_name("virtual"))
-        .subcommand(SubCommand::with_name("baby-steps").arg(Arg::with_name("path").takes_value(true).index(1)))
-        .subcommand(SubCommand::with_name("baby-steps-mem").arg(Arg::with_name("path").takes_value(true).index(1)))
-        .get_matches();
+fn compare_indexes(matches: &ArgMatches) -> Result<()> {
+    let left = index_db::open_existing(matches.value_of("left").unwrap_or_default())?;
+    let right = index_db::open_existing(matches.value_of("right").unwrap_or_default())?;
 
-    let file_name = String::from("index.db");
-    let data_source = index_db::initalise_db(&file_name).unwrap();
+    let comparison = compare::compare(&left, matches.value_of("left-volume"), &right, matches.value_of("right-volume"))?;
+    display_comparison(&comparison);
 
-    match data_source.create() {
-        Ok(_) => println!("Database initialised or verified"),
-        Err(e) => println!("Error initialising database: {:?}", e),
-    };
+    if let Some(list) = matches.value_of("copy-list") {
+        let separator = if matches.is_present("null") { 0 } else { b'\n' };
+        fs::write(list, compare::left_only_copy_list(&comparison, separator)?)?;
+        println!("Copy list of {} files written to {}.", comparison.left_only.len(), list);
+    }
 
-    if let Some(_matches) = config.subcommand_matches("parse") {
-        let records = load_and_process_files();
-        let storage_records: Vec<IndexRecord> =
-            records.into_iter().map(|x| to_index_record(&x)).collect();
+    Ok(())
+}
 
-        println!("Saving {} into the database.", storage_records.len());
-        match data_source.insert(&storage_records) {
-            Ok(_) => println!("Records successfully inserted"),
-            Err(e) => println!("Error inserting records: {:?}", e),
-        };
 
-    } else if let Some(_matches) = config.subcommand_matches("generate") {
-        let res = data_source.fetch_sorted().unwrap();
-
-        let file_records_res: Vec<FileRecord> =
-            res.into_iter().map(|x| to_file_record(&x)).collect();
-        println!("Processing {} from the database.", file_records_res.len());
-
-	let mut graph = analyser::initialise_graph();
-        graph.bulk_insert(file_records_res);
-        export_graph(&graph);
-
-        // let graph_ref = analyser::create_shared_graph();
-        // let local_ref = graph_ref.clone();
-        // let mut root = local_ref.lock().unwrap().root;
-
-        // let first_ref = graph_ref.clone();
-        // analyser::parallel_bulk_insert(first_ref, &mut root, file_records_res);
-        // let graph = local_ref.lock().unwrap();
-
-        export_graph(&graph);
-	
-        let final_res = graph.find_duplicates();
-	display_result(&final_res);
-	export_result(&final_res);
-
-    } else if let Some(_matches) = config.subcommand_matches("virtual") {
-        let records = load_and_process_files();
-        println!("Dropped, now saving.");
-
-        // let mut graph = analyser::initialise_graph();
-        // let mut root = graph.root;
-        // graph.bulk_insert(&mut root, records);
-
-        let graph_ref = analyser::create_shared_graph();
-        let local_ref = graph_ref.clone();
-        let mut root = local_ref.lock().unwrap().root;
-
-        let first_ref = graph_ref.clone();
-        analyser::parallel_bulk_insert(first_ref, &mut root, records);
-        let graph = local_ref.lock().unwrap();
-
-        export_graph(&graph);
-
-        let final_res = graph.find_duplicates();
-        println!("The final result: {:#?}", final_res);
-
-    } else if let Some(_matches) = config.subcommand_matches("baby-steps") {
-	let path = String::from(
-	    _matches.value_of("path").unwrap_or(file_handler::get_current_dir().as_str()));
-	mock_process_base_dir(path);
-    } else if let Some(_matches) = config.subcommand_matches("baby-steps-mem") {
-    	let path = String::from(
-    	    _matches.value_of("path").unwrap_or(file_handler::get_current_dir().as_str()));
-    	let records = file_handler::simple_scan_directory(path);
-	
-	println!("Processing {} files.", records.len());
-	let strage_records = file_handler::path_to_file_record(records);
-
-        println!("Saving {} into the database.", strage_records.len());
-        match data_source.insert(&strage_records) {
-            Ok(_) => println!("Records successfully inserted"),
-            Err(e) => println!("Error inserting records: {:?}", e),
-        };
-	
-    } else {
-        println!("You need to either parse or generate, otherwise there is nothing to do.");
+fn parse_depth(value: Option<&str>) -> Result<Option<usize>> {
+    match value {
+        Some(depth) => Ok(Some(depth.parse().map_err(|_| Error::Parse(format!("'{}' is not a depth", depth)))?)),
+        None => Ok(None),
     }
 }
+
+
+fn parse_snapshot(value: Option<&str>) -> Result<i64> {
+    let value = value.unwrap_or_default();
+    value.parse().map_err(|_| Error::Parse(format!("'{}' is not a snapshot number", value)))
+}
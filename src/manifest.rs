@@ -0,0 +1,343 @@
+//! Checksum manifests, as written and checked by `md5sum`, `sha256sum` and
+//! their BSD style `--tag` counterparts.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+
+extern crate chrono;
+use chrono::{DateTime, Utc};
+
+use crate::error::{Error, Result};
+use crate::file_handler::HashAlgorithm;
+use crate::index_db::{IndexRecord, IndexStorage, Volume};
+
+
+/// Layout of the lines of a manifest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ManifestFormat {
+    /// `<checksum>  <path>`, as printed by `md5sum` and `sha256sum`.
+    Gnu,
+    /// `SHA256 (<path>) = <checksum>`, as printed by BSD `md5` and `shasum --tag`.
+    Bsd,
+}
+
+
+impl ManifestFormat {
+    pub fn parse(name: &str) -> Result<ManifestFormat> {
+        match name {
+            "gnu" => Ok(ManifestFormat::Gnu),
+            "bsd" => Ok(ManifestFormat::Bsd),
+            _ => Err(Error::Parse(format!("unknown manifest format '{}'", name))),
+        }
+    }
+}
+
+
+/// A single file of a manifest, its path relative to where the manifest is checked from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub checksum: String,
+    pub algorithm: HashAlgorithm,
+    pub path: String,
+}
+
+
+fn bsd_tag(algorithm: HashAlgorithm) -> &'static str {
+    match algorithm {
+        HashAlgorithm::Md5 => "MD5",
+        HashAlgorithm::Sha256 => "SHA256",
+    }
+}
+
+
+/// Names with a backslash or a line break are escaped, the line then
+/// starting with a backslash, as coreutils does.
+fn escape(path: &str) -> Option<String> {
+    if !path.contains(['\\', '\n', '\r']) {
+        return None;
+    }
+
+    Some(path.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r"))
+}
+
+
+fn unescape(path: &str) -> Result<String> {
+    let mut res = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('\\') => res.push('\\'),
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            _ => return Err(Error::Parse(format!("invalid escape sequence in '{}'", path))),
+        }
+    }
+
+    Ok(res)
+}
+
+
+/// Formats `entry` as a manifest line, without the line break.
+pub fn format_entry(entry: &ManifestEntry, format: ManifestFormat) -> String {
+    let (prefix, path) = match escape(&entry.path) {
+        Some(escaped) => ("\\", escaped),
+        None => ("", entry.path.clone()),
+    };
+
+    match format {
+        ManifestFormat::Gnu => format!("{}{}  {}", prefix, entry.checksum, path),
+        ManifestFormat::Bsd => format!("{}{} ({}) = {}", prefix, bsd_tag(entry.algorithm), path, entry.checksum),
+    }
+}
+
+
+/// Parses a manifest line of either format, telling the algorithm from the
+/// BSD tag or the length of the checksum.
+pub fn parse_line(line: &str) -> Result<ManifestEntry> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let invalid = || Error::Parse(format!("'{}' is not a manifest line", line));
+
+    let (checksum, algorithm, path) = match line.split_once(' ') {
+        Some((checksum, rest)) if HashAlgorithm::of_digest(checksum).is_some() => {
+            // a space for text mode, a star for binary mode.
+            let path = rest.strip_prefix([' ', '*']).ok_or_else(invalid)?;
+            (checksum, HashAlgorithm::of_digest(checksum).ok_or_else(invalid)?, path)
+        },
+        Some((tag, rest)) => {
+            let (path, checksum) = rest.strip_prefix('(')
+                .and_then(|rest| rest.rsplit_once(") = "))
+                .ok_or_else(invalid)?;
+            let algorithm = HashAlgorithm::of_digest(checksum).ok_or_else(invalid)?;
+            if !tag.eq_ignore_ascii_case(bsd_tag(algorithm)) {
+                return Err(Error::Parse(format!("'{}' is not a {} checksum", checksum, tag)));
+            }
+            (checksum, algorithm, path)
+        },
+        None => return Err(invalid()),
+    };
+
+    Ok(ManifestEntry {
+        checksum: checksum.to_lowercase(),
+        algorithm,
+        path: if escaped { unescape(path)? } else { String::from(path) },
+    })
+}
+
+
+/// Reads every entry of a manifest. Blank lines and `#` comments are skipped.
+pub fn read_manifest<R: BufRead>(mut input: R) -> Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+    let mut line = Vec::new();
+    let mut number = 0;
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        number += 1;
+
+        let text = std::str::from_utf8(&line)
+            .map_err(|_| Error::Parse(format!("line {} of the manifest is not valid UTF-8", number)))?;
+        let text = text.trim_end_matches('\n').trim_end_matches('\r');
+        if text.trim().is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        let entry = parse_line(text).map_err(|e| Error::Parse(format!("line {}: {}", number, e)))?;
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+
+/// Writes the records of `volume` as a manifest, with paths relative to its
+/// root so it can be checked from there. Returns the number of entries.
+pub fn export_manifest<T: IndexStorage, W: Write>(
+    storage: &T,
+    volume: &Volume,
+    format: ManifestFormat,
+    mut out: W,
+) -> Result<usize> {
+    let mut count = 0;
+    for record in storage.fetch_sorted()?.into_iter().filter(|r| r.volume == volume.label) {
+        let path = match record.path.as_str() {
+            "" => record.name,
+            dir => format!("{}/{}", dir, record.name),
+        };
+        let entry = ManifestEntry { checksum: record.checksum, algorithm: volume.algorithm, path };
+        writeln!(out, "{}", format_entry(&entry, format))?;
+        count += 1;
+    }
+    out.flush()?;
+
+    Ok(count)
+}
+
+
+/// Splits a manifest path into the directory and name of a record.
+fn split_path(path: &str) -> Result<(String, String)> {
+    let mut path = path.trim_start_matches('/');
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest.trim_start_matches('/');
+    }
+
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Error::Parse(format!("'{}' does not name a file", path)));
+    }
+
+    Ok((String::from(dir.trim_end_matches('/')), String::from(name)))
+}
+
+
+/// Records `entries` as the virtual volume `label`, taking their checksums as
+/// they are. The import is a snapshot of the volume like any scan: entries gone
+/// from the manifest are dropped, and the changes kept in the history.
+///
+/// Manifests know nothing of sizes, so the records have none, and they are
+/// dated `modified`, when the manifest was written.
+pub fn import_manifest<T: IndexStorage>(
+    storage: &T,
+    entries: Vec<ManifestEntry>,
+    label: &str,
+    source: &str,
+    modified: DateTime<Utc>,
+) -> Result<usize> {
+    let algorithm = match entries.first() {
+        Some(entry) => entry.algorithm,
+        None => return Err(Error::Parse(format!("{} lists no files", source))),
+    };
+    if entries.iter().any(|e| e.algorithm != algorithm) {
+        return Err(Error::Parse(format!("{} mixes hash algorithms", source)));
+    }
+    if let Some(volume) = storage.volume(label)? {
+        if !volume.is_virtual() {
+            return Err(Error::Parse(format!("volume '{}' is scanned from {}, not imported", label, volume.root)));
+        }
+    }
+
+    let volume = storage.register_volume(label, None, "", Some(algorithm))?;
+    let mut records = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let (path, name) = split_path(&entry.path)?;
+        records.push(IndexRecord {
+            id: 0,
+            checksum: entry.checksum,
+            name,
+            volume: String::from(label),
+            path,
+            modified,
+            size: 0,
+            inode: None,
+        });
+    }
+
+    let scan_id = storage.start_scan(volume.id, source)?;
+    storage.checkpoint(scan_id, &[], &records)?;
+    storage.finish_scan(scan_id)?;
+
+    Ok(records.len())
+}
+
+
+/// Imports the manifest at `path` as the virtual volume `label`, see
+/// [`import_manifest`], dating its records when the manifest was last written.
+pub fn import_manifest_file<T: IndexStorage>(storage: &T, path: &str, label: &str) -> Result<usize> {
+    let file = File::open(path)?;
+    let modified = file.metadata()?.modified()?.into();
+
+    let entries = read_manifest(BufReader::new(file))?;
+    import_manifest(storage, entries, label, path, modified)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use crate::compare;
+    use crate::index_db;
+
+    const SHA_A: &str = "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb";
+    const SHA_B: &str = "3e23e8160039594a33894f6564e1b1348bbd7a0088d42c4acb73eeaed59c009d";
+
+    #[test]
+    fn test_parse_formats() {
+        let gnu = parse_line(&format!("{}  photos/a.jpg", SHA_A)).unwrap();
+        let binary = parse_line(&format!("{} *photos/a.jpg", SHA_A)).unwrap();
+        let bsd = parse_line(&format!("SHA256 (photos/a.jpg) = {}", SHA_A)).unwrap();
+        assert_eq!(gnu, binary);
+        assert_eq!(gnu, bsd);
+        assert_eq!(gnu.algorithm, HashAlgorithm::Sha256);
+
+        let md5 = parse_line("MD5 (with (parens)) = d41d8cd98f00b204e9800998ecf8427e").unwrap();
+        assert_eq!((md5.algorithm, md5.path.as_str()), (HashAlgorithm::Md5, "with (parens)"));
+        assert!(parse_line(&format!("MD5 (a) = {}", SHA_A)).is_err());
+        assert!(parse_line("not a checksum  file").is_err());
+
+        // odd names survive a round trip.
+        let odd = ManifestEntry { checksum: String::from(SHA_B), algorithm: HashAlgorithm::Sha256, path: String::from("a\\b\nc") };
+        for format in &[ManifestFormat::Gnu, ManifestFormat::Bsd] {
+            let line = format_entry(&odd, *format);
+            assert!(line.starts_with('\\') && !line.contains('\n'));
+            assert_eq!(parse_line(&line).unwrap(), odd);
+        }
+    }
+
+    #[test]
+    fn test_import_and_export() {
+        let manifest = format!("# backup of 2020\n{}  ./photos/a.jpg\n\n{}  b.txt\n{}  photos/copy.jpg\n", SHA_A, SHA_B, SHA_A);
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+
+        let entries = read_manifest(Cursor::new(manifest)).unwrap();
+        assert_eq!(import_manifest(&storage, entries, "backup", "SHA256SUMS", Utc::now()).unwrap(), 3);
+        assert_eq!(storage.copies(SHA_A).unwrap().len(), 2);
+
+        let volume = storage.volume("backup").unwrap().unwrap();
+        assert!(volume.is_virtual());
+        assert_eq!(volume.algorithm, HashAlgorithm::Sha256);
+
+        let mut out = Vec::new();
+        export_manifest(&storage, &volume, ManifestFormat::Gnu, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}  b.txt\n{}  photos/a.jpg\n{}  photos/copy.jpg\n", SHA_B, SHA_A, SHA_A));
+
+        // a newer manifest replaces the records, as a rescan would.
+        let entries = read_manifest(Cursor::new(format!("{}  photos/a.jpg\n", SHA_A))).unwrap();
+        import_manifest(&storage, entries, "backup", "SHA256SUMS", Utc::now()).unwrap();
+        let other = index_db::initalise_db(":memory:").unwrap();
+        other.create().unwrap();
+        let entries = read_manifest(Cursor::new(format!("{}  a.jpg\n{}  b.txt\n", SHA_A, SHA_B))).unwrap();
+        import_manifest(&other, entries, "primary", "sums", Utc::now()).unwrap();
+
+        let comparison = compare::compare(&other, None, &storage, None).unwrap();
+        assert_eq!(comparison.left_only.iter().map(|r| r.location()).collect::<Vec<String>>(), vec!["primary:b.txt"]);
+        assert_eq!(storage.snapshots().unwrap().len(), 2);
+
+        // md5 records can't go into a sha256 volume.
+        let entries = read_manifest(Cursor::new("d41d8cd98f00b204e9800998ecf8427e  empty\n")).unwrap();
+        assert!(import_manifest(&storage, entries, "backup", "MD5SUMS", Utc::now()).is_err());
+        // nor be compared with it.
+        let entries = read_manifest(Cursor::new("d41d8cd98f00b204e9800998ecf8427e  empty\n")).unwrap();
+        import_manifest(&other, entries, "md5", "MD5SUMS", Utc::now()).unwrap();
+        assert!(compare::compare(&other, Some("md5"), &storage, None).is_err());
+
+        let path = std::env::temp_dir().join("rusty-index-test-SHA256SUMS");
+        std::fs::write(&path, format!("{}  b.txt\n", SHA_B)).unwrap();
+        assert_eq!(import_manifest_file(&storage, path.to_str().unwrap(), "backup").unwrap(), 1);
+        assert_eq!(storage.fetch_sorted().unwrap().iter().filter(|r| r.volume == "backup").count(), 1);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(ManifestFormat::parse("bsd").unwrap(), ManifestFormat::Bsd);
+    }
+}
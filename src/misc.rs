@@ -1,4 +1,8 @@
-use std::env;
+//! Conversions between storage records, tree records and paths.
+
+use std::path::Path;
+
+use crate::error::{Error, Result};
 
 use crate::index_db;
 use index_db::IndexRecord;
@@ -7,67 +11,84 @@ use crate::analyser;
 use analyser::FileRecord;
 
 
-pub fn path_to_components(path: &String) -> Vec<String> {
+pub fn path_to_components(path: &str) -> Vec<String> {
     path.split('/')
-        .filter(|x| *x != "")
-        .map(|x| String::from(x))
+        .filter(|x| !x.is_empty())
+        .map(String::from)
         .collect()
 }
 
 
-pub fn components_to_path(components: &Vec<String>) -> String {
+/// Size in bytes for people to read, such as `38.0 GB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+
+pub fn components_to_path(components: &[String]) -> String {
     format!("/{}/", components.join("/"))
 }
 
 
+/// The first component of a tree record's path is the label of its volume.
 pub fn to_index_record(file_record: &FileRecord) -> IndexRecord {
+    let (volume, path) = match file_record.path.split_first() {
+        Some((volume, path)) => (volume.clone(), path.join("/")),
+        None => (String::new(), String::new()),
+    };
+
     IndexRecord {
-        id: 0,
+        id: file_record.id,
         checksum: file_record.checksum.clone(),
         name: file_record.name.clone(),
-        path: components_to_path(&file_record.path),
-	modified: file_record.modified.clone(),
+        volume,
+        path,
+	modified: file_record.modified,
+        size: 0,
+        inode: None,
     }
 }
 
 
+/// Places the record under its volume, so one tree can hold several volumes.
 pub fn to_file_record(index_record: &IndexRecord) -> FileRecord {
+    let mut path = vec![index_record.volume.clone()];
+    path.extend(path_to_components(&index_record.path));
+
     FileRecord {
+        id: index_record.id,
         checksum: index_record.checksum.clone(),
         name: index_record.name.clone(),
-        path: path_to_components(&index_record.path),
-	modified: index_record.modified.clone(),
+        path,
+	modified: index_record.modified,
     }
 }
 
 
-pub fn get_name_and_split_path(file_path: &String) -> (Vec<String>, String) {
-    let parts: Vec<String> = file_path.rsplitn(2, '/').map(|x| String::from(x)).collect();
-    let path_components = path_to_components(&parts[1]);
-    return (path_components, parts[0].clone());
+pub fn path_to_str(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| Error::PathEncoding(path.to_path_buf()))
 }
 
 
-pub fn process_file_paths(raw_file_list: Vec::<String>) -> Vec::<String> {
-    let current_dir = String::from(
-        env::current_dir().unwrap().into_os_string().into_string().unwrap()
-    );
-    
-    raw_file_list.into_iter().map(|file_name| {
-        let mut relevant_file_name: String = file_name.clone();
-
-        if file_name.starts_with("./") {
-            let (_, new_file_name) = file_name.split_at(2);
-            relevant_file_name = String::from(new_file_name);
-        }
-
-        let mut real_path = format!("{}/{}", current_dir.clone(), relevant_file_name);
-        if file_name.starts_with("/") {
-            real_path = relevant_file_name;
-        }
+/// Turns a path given relative to `current_dir` into an absolute one.
+pub fn absolute_path(file_name: &str, current_dir: &str) -> String {
+    if file_name.starts_with('/') {
+        return String::from(file_name);
+    }
 
-        real_path
-    }).collect()
+    let relevant_file_name = file_name.strip_prefix("./").unwrap_or(file_name);
+    format!("{}/{}", current_dir.trim_end_matches('/'), relevant_file_name)
 }
 
 
@@ -81,7 +102,7 @@ mod test {
 	let d = NaiveDate::from_ymd(2015, 6, 3);
 	let t = NaiveTime::from_hms_milli(12, 34, 56, 789);
 
-	return DateTime::<Utc>::from_utc(NaiveDateTime::new(d, t), Utc);
+	DateTime::<Utc>::from_utc(NaiveDateTime::new(d, t), Utc)
     }
     
     #[test]
@@ -90,8 +111,11 @@ mod test {
             id: 1,
             checksum: String::from("aaaaa"),
             name: String::from("aaaaa.txt"),
-            path: String::from("/some/"),
+            volume: String::from("nas"),
+            path: String::from("some/where"),
 	    modified: mock_date_time(),
+            size: 5,
+            inode: None,
         };
 
         let res = to_file_record(&example);
@@ -99,15 +123,17 @@ mod test {
 
         assert_eq!(res.checksum, String::from("aaaaa"));
         assert_eq!(res.name, String::from("aaaaa.txt"));
-        assert_eq!(res.path, vec!["some"]);
+        assert_eq!(res.path, vec!["nas", "some", "where"]);
+        assert_eq!(res.id, 1);
     }
 
     #[test]
     fn test_to_index_record() {
         let example = FileRecord {
+            id: 7,
             checksum: String::from("aaaaa"),
             name: String::from("aaaaa.txt"),
-            path: vec![String::from("some")],
+            path: vec![String::from("nas"), String::from("some"), String::from("where")],
 	    modified: mock_date_time(),
         };
 
@@ -116,7 +142,16 @@ mod test {
 
         assert_eq!(res.checksum, String::from("aaaaa"));
         assert_eq!(res.name, String::from("aaaaa.txt"));
-        assert_eq!(res.path, String::from("/some/"));
-        assert_eq!(res.id, 0);
+        assert_eq!(res.volume, String::from("nas"));
+        assert_eq!(res.path, String::from("some/where"));
+        assert_eq!(res.id, 7);
+    }
+
+    #[test]
+    fn test_absolute_path() {
+        assert_eq!(absolute_path("./some/file", "/base"), "/base/some/file");
+        assert_eq!(absolute_path("some/file", "/base/"), "/base/some/file");
+        assert_eq!(absolute_path("/other/file", "/base"), "/other/file");
+        assert_eq!(absolute_path(".", "/base"), "/base/.");
     }
 }
@@ -0,0 +1,235 @@
+//! Progress reporting for long running scans.
+
+use std::io::{stderr, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+extern crate serde_json;
+use serde_json::json;
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+const BYTES_PER_MB: f64 = 1024.0 * 1024.0;
+const QUEUE_SIZE: usize = 1024;
+
+
+/// What the scanning pipeline reports while it runs.
+#[derive(Debug)]
+pub enum ProgressEvent {
+    /// A file of the given size was queued for hashing.
+    Queued { bytes: u64 },
+    /// Every file has been queued, so the totals are final.
+    EnumerationDone,
+    /// A file under `dir` went through the hashing pool.
+    Processed { bytes: u64, dir: PathBuf },
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressFormat {
+    Human,
+    Json,
+}
+
+
+/// Handle given to the pipeline threads. Reporting is best effort, a missing or
+/// finished reporter never stops a scan, while a busy one holds it back rather
+/// than letting events pile up.
+#[derive(Clone, Default)]
+pub struct ProgressSender {
+    tx: Option<SyncSender<ProgressEvent>>,
+}
+
+
+impl ProgressSender {
+    pub fn none() -> ProgressSender {
+        ProgressSender { tx: None }
+    }
+
+    pub fn send(&self, event: ProgressEvent) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(event);
+        }
+    }
+}
+
+
+#[derive(Debug, Default)]
+pub struct ProgressState {
+    pub files_queued: u64,
+    pub bytes_queued: u64,
+    pub files_processed: u64,
+    pub bytes_processed: u64,
+    pub enumeration_done: bool,
+    pub current_dir: PathBuf,
+}
+
+
+impl ProgressState {
+    pub fn update(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Queued { bytes } => {
+                self.files_queued += 1;
+                self.bytes_queued += bytes;
+            },
+            ProgressEvent::EnumerationDone => self.enumeration_done = true,
+            ProgressEvent::Processed { bytes, dir } => {
+                self.files_processed += 1;
+                self.bytes_processed += bytes;
+                self.current_dir = dir;
+            },
+        }
+    }
+
+    /// Hashing throughput in MB/s.
+    pub fn throughput(&self, elapsed: Duration) -> f64 {
+        let secs = elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+
+        self.bytes_processed as f64 / BYTES_PER_MB / secs
+    }
+
+    /// Estimated time left, only known once enumeration is done.
+    pub fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        let rate = self.bytes_processed as f64 / elapsed.as_secs_f64();
+        if !self.enumeration_done || !rate.is_finite() || rate <= 0.0 {
+            return None;
+        }
+
+        let remaining = self.bytes_queued.saturating_sub(self.bytes_processed) as f64;
+        Some(Duration::from_secs_f64(remaining / rate))
+    }
+
+    pub fn to_json(&self, elapsed: Duration) -> String {
+        json!({
+            "files_processed": self.files_processed,
+            "files_total": self.files_queued,
+            "bytes_processed": self.bytes_processed,
+            "bytes_total": self.bytes_queued,
+            "enumeration_done": self.enumeration_done,
+            "current_dir": self.current_dir.to_string_lossy(),
+            "elapsed_secs": elapsed.as_secs(),
+            "mb_per_sec": self.throughput(elapsed),
+            "eta_secs": self.eta(elapsed).map(|eta| eta.as_secs()),
+        }).to_string()
+    }
+
+    pub fn to_line(&self, elapsed: Duration) -> String {
+        let total = if self.enumeration_done {
+            format!("{}", self.files_queued)
+        } else {
+            format!("{}+", self.files_queued)
+        };
+        let eta = match self.eta(elapsed) {
+            Some(eta) => format_duration(eta),
+            None => String::from("--:--:--"),
+        };
+
+        format!(
+            "{}/{} files, {:.1}/{:.1} MB, {:.1} MB/s, ETA {} - {}",
+            self.files_processed,
+            total,
+            self.bytes_processed as f64 / BYTES_PER_MB,
+            self.bytes_queued as f64 / BYTES_PER_MB,
+            self.throughput(elapsed),
+            eta,
+            self.current_dir.to_string_lossy(),
+        )
+    }
+}
+
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+
+fn print_state(state: &ProgressState, format: ProgressFormat, elapsed: Duration) {
+    match format {
+        // stdout is left to the command's own output, so the events stay parsable.
+        ProgressFormat::Json => eprintln!("{}", state.to_json(elapsed)),
+        ProgressFormat::Human => {
+            // stays on one line, clearing whatever was left by the previous refresh.
+            eprint!("\r\x1b[2K{}", state.to_line(elapsed));
+            let _ = stderr().flush();
+        },
+    }
+}
+
+
+fn report(rx: Receiver<ProgressEvent>, format: ProgressFormat) {
+    let started = Instant::now();
+    let mut last_print = started;
+    let mut state = ProgressState::default();
+
+    loop {
+        match rx.recv_timeout(REFRESH_INTERVAL) {
+            Ok(event) => state.update(event),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if last_print.elapsed() >= REFRESH_INTERVAL {
+            print_state(&state, format, started.elapsed());
+            last_print = Instant::now();
+        }
+    }
+
+    print_state(&state, format, started.elapsed());
+    if format == ProgressFormat::Human {
+        eprintln!();
+    }
+}
+
+
+/// Starts a thread printing the progress of a scan until every sender is dropped.
+pub fn spawn_reporter(format: ProgressFormat) -> (ProgressSender, JoinHandle<()>) {
+    let (tx, rx) = sync_channel(QUEUE_SIZE);
+    let handle = thread::spawn(move || report(rx, format));
+
+    (ProgressSender { tx: Some(tx) }, handle)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mock_state() -> ProgressState {
+        let mut state = ProgressState::default();
+        state.update(ProgressEvent::Queued { bytes: 3 * 1024 * 1024 });
+        state.update(ProgressEvent::Queued { bytes: 1024 * 1024 });
+        state.update(ProgressEvent::Processed { bytes: 3 * 1024 * 1024, dir: PathBuf::from("/some") });
+
+        state
+    }
+
+    #[test]
+    fn test_eta_needs_enumeration() {
+        let mut state = mock_state();
+        let elapsed = Duration::from_secs(3);
+
+        assert_eq!(state.eta(elapsed), None);
+        assert!((state.throughput(elapsed) - 1.0).abs() < 1e-9);
+
+        state.update(ProgressEvent::EnumerationDone);
+        assert_eq!(state.eta(elapsed), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut state = mock_state();
+        state.update(ProgressEvent::EnumerationDone);
+
+        let res: serde_json::Value = serde_json::from_str(&state.to_json(Duration::from_secs(3))).unwrap();
+
+        assert_eq!(res["files_processed"], 1);
+        assert_eq!(res["files_total"], 2);
+        assert_eq!(res["current_dir"], "/some");
+        assert_eq!(res["eta_secs"], 1);
+    }
+}
@@ -0,0 +1,277 @@
+//! Presentation and export of the duplicates found by the analyser.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::collections::HashMap;
+
+extern crate colored;
+use colored::*;
+
+extern crate num_cpus;
+
+use crate::analyser;
+use crate::analyser::GraphStorageInterface;
+use crate::duplicates;
+use crate::duplicates::{DuplicateReport, ReportFormat, ScanRoot};
+use crate::graph_export;
+use crate::graph_export::GraphExportOptions;
+use crate::error::Result;
+use crate::compare::Comparison;
+use crate::history::SnapshotDiff;
+use crate::verify::{Finding, VerifyReport};
+use crate::index_db::{ChangeKind, FileChange, IndexRecord, Snapshot, Volume};
+use crate::misc::{human_size, to_file_record};
+use crate::usage::DirectoryUsage;
+
+pub const DEFAULT_DOT_OUT: &str = "example1.dot";
+pub const DEFAULT_JSON_OUT: &str = "duplicate-results.json";
+
+
+/// Writes the directory graph, or the part of it picked by `options`. Files
+/// carry the row of their record in the index as their `id`, such as `record-42`.
+pub fn export_graph(graph: &analyser::GraphStorage, file_name: &str, options: &GraphExportOptions) -> Result<()> {
+    let f = BufWriter::new(File::create(file_name)?);
+
+    println!("Writing {:?} file with final results to {}.", options.format, file_name);
+    let count = graph_export::write_graph(graph, options, f)?;
+    println!("{} nodes written. All done. Have a nice day in the world.", count);
+
+    Ok(())
+}
+
+
+/// Prints the duplicates, grouped by checksum, to the terminal.
+pub fn display_result(duplicates: &HashMap<String, Vec<String>>) {
+    println!("{}", "Here are he results :".green().bold());
+    println!();
+    
+    for (hash, paths) in duplicates {
+	println!("{} {}", "Hash :".red(), hash.magenta());
+
+	for p in paths {
+	    if p.ends_with("/") {
+		println!("\t {} \t \u{1F4C2}", p.blue().bold());
+	    } else {
+		println!("\t{}", p.blue().bold());
+	    }
+	}
+
+	println!();
+    }
+    
+}
+
+
+/// Saves the report of the duplicates, see [`duplicates`] for its schema.
+pub fn export_result(report: &DuplicateReport, file_name: &str, format: ReportFormat) -> Result<()> {
+    println!("{}{}", "Exporting the results to  :".green().bold(), file_name.blue().bold());
+    println!();
+
+    duplicates::write_report(report, format, BufWriter::new(File::create(file_name)?))?;
+
+    Ok(())
+}
+
+
+/// Exports the directory tree and reports the duplicates found in it, the
+/// files of the tree being described by `records`, to each of `outputs`.
+pub fn report_graph(
+    graph: &analyser::GraphStorage,
+    dot_out: &str,
+    options: &GraphExportOptions,
+    records: &[IndexRecord],
+    roots: Vec<ScanRoot>,
+    outputs: &[(&str, ReportFormat)],
+) -> Result<()> {
+    export_graph(graph, dot_out, options)?;
+
+    display_result(&graph.find_duplicates());
+    let report = duplicates::build_report(graph, records, roots);
+    for (file_name, format) in outputs {
+        export_result(&report, file_name, *format)?;
+    }
+
+    Ok(())
+}
+
+
+/// Builds the directory tree of `records`, exports it and reports the duplicates found in it.
+pub fn report_duplicates(records: &[IndexRecord], roots: Vec<ScanRoot>, dot_out: &str, outputs: &[(&str, ReportFormat)]) -> Result<()> {
+    let graph = analyser::parallel_bulk_insert(records.iter().map(to_file_record).collect(), num_cpus::get())?;
+    report_graph(&graph, dot_out, &GraphExportOptions::default(), records, roots, outputs)
+}
+
+
+/// Whether the volume is mounted, or when it was last seen.
+pub fn volume_state(volume: &Volume) -> String {
+    if volume.is_virtual() {
+        format!("virtual, imported at {}", volume.last_seen.format(TIME_FORMAT))
+    } else if volume.is_online() {
+        String::from("online")
+    } else {
+        format!("offline, last seen at {}", volume.last_seen.format(TIME_FORMAT))
+    }
+}
+
+
+/// Prints the known volumes and their state.
+pub fn display_volumes(volumes: &[Volume]) {
+    for volume in volumes {
+        let uuid = volume.uuid.as_deref().unwrap_or("-");
+        println!("{} \t{} \t{} \t{} \t{}", volume.label.blue().bold(), uuid, volume.root, volume.algorithm.as_str(),
+                 volume_state(volume));
+    }
+}
+
+
+/// Prints every copy of a file, with the state of the volume holding it.
+pub fn display_copies(copies: &[IndexRecord], volumes: &[Volume]) {
+    for copy in copies {
+        let state = match volumes.iter().find(|v| v.label == copy.volume) {
+            Some(volume) => volume_state(volume),
+            None => String::from("unknown volume"),
+        };
+        println!("\t{} ({})", copy.location().blue().bold(), state);
+    }
+}
+
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S UTC";
+
+
+/// Prints the snapshots taken so far, one per finished scan.
+pub fn display_snapshots(snapshots: &[Snapshot]) {
+    for snapshot in snapshots {
+        println!("{} \t{} \t{}", snapshot.id.to_string().magenta(), snapshot.volume.blue().bold(),
+                 snapshot.finished.format(TIME_FORMAT));
+    }
+}
+
+
+/// Prints when the contents of a file changed.
+pub fn display_history(changes: &[FileChange]) {
+    for change in changes {
+        let what = match change.kind {
+            ChangeKind::Added => format!("added as {}", change.checksum.as_deref().unwrap_or_default()),
+            ChangeKind::Modified => format!(
+                "changed from {} to {}",
+                change.previous_checksum.as_deref().unwrap_or_default(),
+                change.checksum.as_deref().unwrap_or_default(),
+            ),
+            ChangeKind::Removed => String::from("removed"),
+            ChangeKind::Moved => format!(
+                "moved from {} to {}",
+                change.previous_location().unwrap_or_default(),
+                change.location(),
+            ),
+        };
+        println!("{} {} \t{} \t(modified {})", "Snapshot".green(), change.snapshot.to_string().magenta(), what,
+                 change.modified.format(TIME_FORMAT));
+    }
+}
+
+
+/// Prints what differs between two snapshots.
+pub fn display_diff(diff: &SnapshotDiff) {
+    for change in &diff.added {
+        println!("{} {}", "+".green().bold(), change.location());
+    }
+    for change in &diff.removed {
+        println!("{} {}", "-".red().bold(), change.location());
+    }
+    for change in &diff.modified {
+        println!("{} {}", "M".yellow().bold(), change.location());
+    }
+    for moved in &diff.moved_directories {
+        println!("{} {}:{}/ -> {}:{}/", "R".blue().bold(), moved.volume, moved.from, moved.volume, moved.to);
+    }
+    for moved in &diff.moved {
+        println!("{} {} -> {}", "R".blue().bold(), moved.previous_location().unwrap_or_default(), moved.location());
+    }
+
+    println!();
+    println!("{} added, {} removed, {} modified, {} moved, {} directories moved.",
+             diff.added.len(), diff.removed.len(), diff.modified.len(), diff.moved.len(), diff.moved_directories.len());
+}
+
+
+/// Prints the problems found by a verification run.
+pub fn display_verification(report: &VerifyReport) {
+    for label in &report.offline {
+        println!("{} volume {} is offline, not verified", "Skipped:".yellow().bold(), label);
+    }
+
+    for (record, finding) in &report.issues {
+        let what = match finding {
+            Finding::Corrupted => "CORRUPTED".red().bold(),
+            Finding::Changed => "changed".yellow(),
+            Finding::Missing => "missing".red(),
+            Finding::Unreadable(e) => format!("unreadable: {}", e).red(),
+        };
+        println!("\t{} \t{}", what, record.location().blue().bold());
+    }
+
+    let damaged = report.damaged();
+    println!("{} files verified, {} damaged, {} changed since indexed.",
+             report.checked, damaged, report.issues.len() - damaged);
+}
+
+
+/// Prints what two indexes have that the other lacks, by content.
+pub fn display_comparison(comparison: &Comparison) {
+    println!("{}", "Only on the left:".green().bold());
+    for record in &comparison.left_only {
+        println!("\t{}", record.location().blue().bold());
+    }
+
+    println!("{}", "Only on the right:".green().bold());
+    for record in &comparison.right_only {
+        println!("\t{}", record.location().blue().bold());
+    }
+
+    println!("{}", "On both sides, under different paths:".green().bold());
+    for relocated in &comparison.relocated {
+        let left: Vec<String> = relocated.left.iter().map(|r| r.location()).collect();
+        let right: Vec<String> = relocated.right.iter().map(|r| r.location()).collect();
+        println!("\t{} -> {}", left.join(", ").blue().bold(), right.join(", "));
+    }
+
+    println!();
+    println!("{} files ({:.1} MB) only on the left, {} only on the right, {} placed differently.",
+             comparison.left_only.len(), comparison.left_only_bytes() as f64 / (1024.0 * 1024.0),
+             comparison.right_only.len(), comparison.relocated.len());
+}
+
+
+/// A directory as the user knows it: its absolute path when the root of its
+/// volume is known, `volume:path/` otherwise.
+fn place_name(path: &[String], roots: &[ScanRoot]) -> String {
+    let relative = path[1..].iter().map(|c| format!("{}/", c)).collect::<String>();
+    match roots.iter().find(|root| root.volume == path[0]).and_then(|root| root.root.as_deref()) {
+        Some(root) => format!("{}/{}", root.trim_end_matches('/'), relative),
+        None => format!("{}:{}", path[0], relative),
+    }
+}
+
+
+/// Prints the size of every directory as a tree, with how much of it is
+/// duplicated elsewhere and where most of the copies are.
+pub fn display_usage(usage: &[DirectoryUsage], roots: &[ScanRoot]) {
+    for directory in usage {
+        let name = match directory.depth {
+            0 => place_name(&directory.path, roots),
+            _ => format!("{}/", directory.path[directory.path.len() - 1]),
+        };
+        print!("{}{} {}", "  ".repeat(directory.depth), name.blue().bold(), human_size(directory.size));
+
+        if let Some((place, _)) = directory.places.first() {
+            let others = match directory.places.len() {
+                1 => String::new(),
+                2 => String::from(" and 1 other place"),
+                n => format!(" and {} other places", n - 1),
+            };
+            print!(", {}", format!("{} duplicated in {}{}", human_size(directory.duplicated), place_name(place, roots), others).yellow());
+        }
+        println!();
+    }
+}
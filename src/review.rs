@@ -0,0 +1,620 @@
+//! Interactive review of the duplicate groups in the terminal. Copies are
+//! marked to keep or to delete and the marks written to a plan file, for a
+//! separate step to apply: nothing is ever deleted from here.
+//!
+//! A plan has a mark per line, `keep` or `delete` followed by the
+//! `volume:path` location of the copy, lines starting with `#` being comments:
+//!
+//! ```text
+//! # rusty-index plan
+//! # group 1, 38.0 GB reclaimable
+//! keep nas:photos/
+//! delete nas:backup/old-photos/
+//! ```
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+
+extern crate crossterm;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, execute, queue, style, terminal};
+
+extern crate petgraph;
+use petgraph::graph::NodeIndex;
+
+use crate::analyser::{GNode, GraphStorage};
+use crate::duplicates::{node_totals, DuplicateGroup, DuplicateReport, GroupKind, Totals};
+use crate::error::{Error, Result};
+use crate::index_db::IndexRecord;
+use crate::misc::{human_size, path_to_components};
+
+
+/// Lines taken by the details of the highlighted copy or entry.
+const PREVIEW_LINES: usize = 7;
+const HELP: &str = "arrows move, enter opens, esc goes back, k keep, d delete, u unmark, w write plan, q quit";
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mark {
+    Keep,
+    Delete,
+}
+
+
+impl Mark {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mark::Keep => "keep",
+            Mark::Delete => "delete",
+        }
+    }
+}
+
+
+/// A key press, as far as the review is concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Open,
+    Back,
+    Keep,
+    Delete,
+    Unmark,
+    Write,
+    Quit,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum View {
+    Groups,
+    Copies { group: usize },
+    /// Entries of a directory of `group`, found at `location`.
+    Directory { group: usize, node: NodeIndex, location: String },
+}
+
+
+/// What to draw, line by line, the highlighted line in reverse video.
+#[derive(Debug)]
+pub struct Screen {
+    pub lines: Vec<String>,
+    pub highlight: Option<usize>,
+}
+
+
+pub struct Review<'a> {
+    report: &'a DuplicateReport,
+    graph: &'a GraphStorage,
+    roots: HashMap<&'a str, &'a str>,
+    totals: HashMap<NodeIndex, Totals>,
+    copies: HashMap<&'a str, usize>,
+    /// Views opened so far, the current one last, with the cursor of each.
+    views: Vec<(View, usize)>,
+    /// Marks by location of the copy.
+    marks: HashMap<String, Mark>,
+    plan: String,
+    saved: bool,
+    quitting: bool,
+    status: String,
+}
+
+
+impl<'a> Review<'a> {
+    /// Reviews `report`, built out of `graph` and `records`. Marks are written to `plan`.
+    pub fn new(report: &'a DuplicateReport, graph: &'a GraphStorage, records: &[IndexRecord], plan: &str) -> Review<'a> {
+        let by_location: HashMap<String, &IndexRecord> = records.iter().map(|r| (r.location(), r)).collect();
+        let mut copies = HashMap::<&str, usize>::new();
+        for node in graph.graph.node_indices() {
+            *copies.entry(graph.graph[node].checksum()).or_default() += 1;
+        }
+
+        Review {
+            report,
+            graph,
+            roots: report.header.roots.iter()
+                .filter_map(|root| root.root.as_deref().map(|path| (root.volume.as_str(), path)))
+                .collect(),
+            totals: node_totals(graph, &by_location),
+            copies,
+            views: vec![(View::Groups, 0)],
+            marks: HashMap::new(),
+            plan: String::from(plan),
+            saved: true,
+            quitting: false,
+            status: String::new(),
+        }
+    }
+
+    pub fn marks(&self) -> &HashMap<String, Mark> {
+        &self.marks
+    }
+
+    fn current(&self) -> &(View, usize) {
+        &self.views[self.views.len() - 1]
+    }
+
+    fn group(&self, group: usize) -> &'a DuplicateGroup {
+        &self.report.groups[group]
+    }
+
+    /// Entries of a directory, the directories first, by name.
+    fn children(&self, node: NodeIndex) -> Vec<NodeIndex> {
+        let mut res: Vec<NodeIndex> = self.graph.graph.neighbors(node).collect();
+        res.sort_by_key(|n| (matches!(self.graph.graph[*n], GNode::FileLeaf {..}), self.graph.graph[*n].name()));
+        res
+    }
+
+    fn entries(&self) -> usize {
+        match &self.current().0 {
+            View::Groups => self.report.groups.len(),
+            View::Copies { group } => self.group(*group).paths.len(),
+            View::Directory { node, .. } => self.graph.graph.neighbors(*node).count(),
+        }
+    }
+
+    /// Applies a key press, returning whether the review goes on.
+    pub fn handle(&mut self, key: Key) -> bool {
+        let quitting = std::mem::take(&mut self.quitting);
+        self.status.clear();
+
+        let entries = self.entries();
+        let last = entries.saturating_sub(1);
+        let cursor = self.current().1;
+        let moved = match key {
+            Key::Up => Some(cursor.saturating_sub(1)),
+            Key::Down => Some((cursor + 1).min(last)),
+            Key::PageUp => Some(cursor.saturating_sub(10)),
+            Key::PageDown => Some((cursor + 10).min(last)),
+            Key::Home => Some(0),
+            Key::End => Some(last),
+            _ => None,
+        };
+        if let Some(moved) = moved {
+            let index = self.views.len() - 1;
+            self.views[index].1 = moved;
+            return true;
+        }
+
+        match key {
+            Key::Open if entries > 0 => self.open(),
+            Key::Back if self.views.len() > 1 => {
+                self.views.pop();
+            },
+            Key::Keep => self.mark(Some(Mark::Keep)),
+            Key::Delete => self.mark(Some(Mark::Delete)),
+            Key::Unmark => self.mark(None),
+            Key::Write => {
+                self.status = match self.write_plan() {
+                    Ok(count) => format!("{} marks written to {}.", count, self.plan),
+                    Err(e) => format!("Could not write {}: {}", self.plan, e),
+                };
+            },
+            Key::Quit => {
+                if self.saved || quitting {
+                    return false;
+                }
+                self.quitting = true;
+                self.status = String::from("The marks are not written, press w to write the plan or q again to quit.");
+            },
+            _ => {},
+        }
+
+        true
+    }
+
+    fn open(&mut self) {
+        let (view, cursor) = self.current().clone();
+        let opened = match view {
+            View::Groups => View::Copies { group: cursor },
+            View::Copies { group } => {
+                let copy = &self.group(group).paths[cursor];
+                if self.group(group).kind == GroupKind::File {
+                    self.status = String::from("Files have no entries to show.");
+                    return;
+                }
+
+                let mut path = vec![copy.volume.clone()];
+                path.extend(path_to_components(&copy.path));
+                match self.graph.lookup(&path) {
+                    Some(node) => View::Directory { group, node, location: copy.location() },
+                    None => {
+                        self.status = format!("{} is not in the tree.", copy.location());
+                        return;
+                    },
+                }
+            },
+            View::Directory { group, node, location } => {
+                let child = self.children(node)[cursor];
+                match &self.graph.graph[child] {
+                    GNode::DirNode {name, ..} => View::Directory { group, node: child, location: format!("{}{}/", location, name) },
+                    GNode::FileLeaf {..} => {
+                        self.status = String::from("Files have no entries to show.");
+                        return;
+                    },
+                }
+            },
+        };
+
+        self.views.push((opened, 0));
+    }
+
+    fn mark(&mut self, mark: Option<Mark>) {
+        let (group, cursor) = match self.current() {
+            (View::Copies { group }, cursor) => (*group, *cursor),
+            _ => {
+                self.status = String::from("Copies are marked from the list of copies of a group.");
+                return;
+            },
+        };
+        let paths = &self.group(group).paths;
+        let location = paths[cursor].location();
+
+        if mark == Some(Mark::Delete) {
+            let others_deleted = paths.iter()
+                .filter(|copy| copy.location() != location)
+                .all(|copy| self.marks.get(&copy.location()) == Some(&Mark::Delete));
+            if others_deleted {
+                self.status = String::from("Every other copy is marked for deletion, keep at least one.");
+                return;
+            }
+        }
+
+        match mark {
+            Some(mark) => self.marks.insert(location, mark),
+            None => self.marks.remove(&location),
+        };
+        self.saved = false;
+    }
+
+    /// Writes the marks to the plan file, returning how many there are.
+    pub fn write_plan(&mut self) -> Result<usize> {
+        let count = write_plan(self.report, &self.marks, BufWriter::new(File::create(&self.plan)?))?;
+        self.saved = true;
+
+        Ok(count)
+    }
+
+    /// Details of the copy or entry at `location`, whose node is `node`.
+    fn preview(&self, location: &str, node: Option<NodeIndex>, lines: &mut Vec<String>) {
+        let (volume, path) = location.split_once(':').unwrap_or((location, ""));
+        lines.push(format!("Location  {}", location));
+        lines.push(match self.roots.get(volume) {
+            Some(root) => format!("Path      {}/{}", root.trim_end_matches('/'), path),
+            None => String::from("Path      unknown, the volume is not in the index"),
+        });
+
+        let node = match node {
+            Some(node) => node,
+            None => return,
+        };
+        let totals = &self.totals[&node];
+        let checksum = self.graph.graph[node].checksum();
+        lines.push(match self.graph.graph[node] {
+            GNode::DirNode {..} => format!("Size      {}, {} files", human_size(totals.size), totals.files),
+            GNode::FileLeaf {..} => format!("Size      {}", human_size(totals.size)),
+        });
+        if let Some(modified) = totals.modified {
+            lines.push(format!("Modified  {}", modified.format("%Y-%m-%d %H:%M:%S UTC")));
+        }
+        lines.push(format!("Checksum  {}, {} copies in the index", checksum, self.copies.get(checksum).unwrap_or(&1)));
+        if let Some(record) = self.graph.graph[node].record_id() {
+            lines.push(format!("Record    {}", record));
+        }
+    }
+
+    /// Lays out the current view on a `width` by `height` screen.
+    pub fn screen(&self, width: usize, height: usize) -> Screen {
+        let (view, cursor) = self.current();
+        let mut items = Vec::new();
+        let mut preview = Vec::new();
+
+        let title = match view {
+            View::Groups => {
+                for group in &self.report.groups {
+                    let marked: Vec<Mark> = group.paths.iter().filter_map(|c| self.marks.get(&c.location()).copied()).collect();
+                    let marks = match marked.len() {
+                        0 => String::new(),
+                        _ => format!("  [{} keep, {} delete]",
+                                     marked.iter().filter(|m| **m == Mark::Keep).count(),
+                                     marked.iter().filter(|m| **m == Mark::Delete).count()),
+                    };
+                    items.push(format!("{:>10}  {:>3} copies  {:<9}  {}{}", human_size(group.reclaimable), group.paths.len(),
+                                       kind_name(group.kind), group.paths[0].location(), marks));
+                }
+
+                let reclaimable: u64 = self.report.groups.iter().map(|g| g.reclaimable).sum();
+                format!("{} groups of duplicates, {} to reclaim, {} copies marked",
+                        self.report.groups.len(), human_size(reclaimable), self.marks.len())
+            },
+            View::Copies { group } => {
+                let info = self.group(*group);
+                for copy in &info.paths {
+                    let mark = match self.marks.get(&copy.location()) {
+                        Some(Mark::Keep) => 'K',
+                        Some(Mark::Delete) => 'D',
+                        None => ' ',
+                    };
+                    items.push(format!("[{}] {}", mark, copy.location()));
+                }
+
+                if let Some(copy) = info.paths.get(*cursor) {
+                    let mut path = vec![copy.volume.clone()];
+                    path.extend(path_to_components(&copy.path));
+                    self.preview(&copy.location(), self.graph.lookup(&path), &mut preview);
+                }
+                format!("Group {} of {}: {} copies of a {}, {} to reclaim", group + 1, self.report.groups.len(),
+                        info.paths.len(), kind_name(info.kind), human_size(info.reclaimable))
+            },
+            View::Directory { node, location, .. } => {
+                let children = self.children(*node);
+                for child in &children {
+                    let entry = &self.graph.graph[*child];
+                    let suffix = if matches!(entry, GNode::DirNode {..}) { "/" } else { "" };
+                    let duplicated = if self.copies.get(entry.checksum()).is_some_and(|n| *n > 1) { "  (duplicated)" } else { "" };
+                    items.push(format!("{:>10}  {}{}{}", human_size(self.totals[child].size), entry.name(), suffix, duplicated));
+                }
+
+                if let Some(child) = children.get(*cursor) {
+                    let entry = &self.graph.graph[*child];
+                    let suffix = if matches!(entry, GNode::DirNode {..}) { "/" } else { "" };
+                    self.preview(&format!("{}{}{}", location, entry.name(), suffix), Some(*child), &mut preview);
+                }
+                location.clone()
+            },
+        };
+
+        let preview_height = if matches!(view, View::Groups) { 0 } else { PREVIEW_LINES + 1 };
+        let list_height = height.saturating_sub(4 + preview_height).max(1);
+        let offset = cursor / list_height * list_height;
+
+        let mut lines = vec![title, "-".repeat(width)];
+        lines.extend(items.iter().skip(offset).take(list_height).cloned());
+        let highlight = if items.is_empty() { None } else { Some(2 + cursor - offset) };
+        lines.resize(2 + list_height, String::new());
+        if preview_height > 0 {
+            lines.push("-".repeat(width));
+            preview.resize(PREVIEW_LINES, String::new());
+            lines.extend(preview);
+        }
+        lines.push(self.status.clone());
+        lines.push(String::from(HELP));
+
+        let lines = lines.into_iter().take(height).map(|line| {
+            let mut line: String = line.chars().take(width).collect();
+            let padding = width.saturating_sub(line.chars().count());
+            line.extend(std::iter::repeat_n(' ', padding));
+            line
+        }).collect();
+
+        Screen { lines, highlight }
+    }
+}
+
+
+fn kind_name(kind: GroupKind) -> &'static str {
+    match kind {
+        GroupKind::File => "file",
+        GroupKind::Directory => "directory",
+    }
+}
+
+
+/// Writes the marks of the copies of `report`, group by group. Returns the
+/// number of marks written.
+pub fn write_plan<W: Write>(report: &DuplicateReport, marks: &HashMap<String, Mark>, mut out: W) -> Result<usize> {
+    let mut count = 0;
+    writeln!(out, "# rusty-index plan")?;
+    for (i, group) in report.groups.iter().enumerate() {
+        let marked: Vec<(String, Mark)> = group.paths.iter()
+            .filter_map(|copy| marks.get(&copy.location()).map(|mark| (copy.location(), *mark)))
+            .collect();
+        if marked.is_empty() {
+            continue;
+        }
+
+        writeln!(out, "# group {}, {} reclaimable", i + 1, human_size(group.reclaimable))?;
+        for (location, mark) in marked {
+            writeln!(out, "{} {}", mark.as_str(), location)?;
+            count += 1;
+        }
+    }
+    out.flush()?;
+
+    Ok(count)
+}
+
+
+/// Reads the marks of a plan, in order.
+pub fn read_plan<R: BufRead>(input: R) -> Result<Vec<(Mark, String)>> {
+    let mut res = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mark = match line.split_once(' ') {
+            Some(("keep", location)) => (Mark::Keep, location),
+            Some(("delete", location)) => (Mark::Delete, location),
+            _ => return Err(Error::Parse(format!("line {} of the plan is not a keep or delete mark", number + 1))),
+        };
+        res.push((mark.0, String::from(mark.1)));
+    }
+
+    Ok(res)
+}
+
+
+fn to_key(event: KeyEvent) -> Option<Key> {
+    if event.kind != KeyEventKind::Press {
+        return None;
+    }
+
+    match event.code {
+        KeyCode::Up => Some(Key::Up),
+        KeyCode::Down => Some(Key::Down),
+        KeyCode::PageUp => Some(Key::PageUp),
+        KeyCode::PageDown => Some(Key::PageDown),
+        KeyCode::Home => Some(Key::Home),
+        KeyCode::End => Some(Key::End),
+        KeyCode::Enter | KeyCode::Right => Some(Key::Open),
+        KeyCode::Esc | KeyCode::Left | KeyCode::Backspace => Some(Key::Back),
+        KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => Some(Key::Quit),
+        KeyCode::Char('k') => Some(Key::Keep),
+        KeyCode::Char('d') => Some(Key::Delete),
+        KeyCode::Char('u') => Some(Key::Unmark),
+        KeyCode::Char('w') => Some(Key::Write),
+        KeyCode::Char('q') => Some(Key::Quit),
+        _ => None,
+    }
+}
+
+
+fn draw<W: Write>(screen: &Screen, out: &mut W) -> Result<()> {
+    queue!(out, terminal::Clear(terminal::ClearType::All))?;
+    for (i, line) in screen.lines.iter().enumerate() {
+        queue!(out, cursor::MoveTo(0, i as u16))?;
+        if screen.highlight == Some(i) {
+            queue!(out, style::SetAttribute(style::Attribute::Reverse), style::Print(line),
+                   style::SetAttribute(style::Attribute::Reset))?;
+        } else {
+            queue!(out, style::Print(line))?;
+        }
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+
+fn event_loop<W: Write>(review: &mut Review, out: &mut W) -> Result<()> {
+    loop {
+        let (width, height) = terminal::size()?;
+        draw(&review.screen(width as usize, height as usize), out)?;
+
+        if let Event::Key(event) = event::read()? {
+            if let Some(key) = to_key(event) {
+                if !review.handle(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+
+/// Keeps the terminal in raw mode on the alternate screen while alive, giving
+/// it back when dropped, be it on return, error or panic.
+struct TerminalGuard;
+
+
+impl TerminalGuard {
+    fn enter<W: Write>(out: &mut W) -> Result<TerminalGuard> {
+        terminal::enable_raw_mode()?;
+        let guard = TerminalGuard;
+        execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+        Ok(guard)
+    }
+}
+
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        // nothing better to do if the terminal can't be restored, and the other steps still have to run.
+        let _ = execute!(std::io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+
+/// Runs the review of `report` in the terminal until it is quit.
+pub fn run(report: &DuplicateReport, graph: &GraphStorage, records: &[IndexRecord], plan: &str) -> Result<()> {
+    let mut review = Review::new(report, graph, records, plan);
+    let mut out = std::io::stdout();
+
+    let _guard = TerminalGuard::enter(&mut out)?;
+    event_loop(&mut review, &mut out)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Utc;
+
+    use crate::analyser::{initialise_graph, GraphStorageInterface};
+    use crate::duplicates::{build_report, ScanRoot};
+    use crate::misc;
+
+    fn record(id: u32, path: &str, name: &str, checksum: &str, size: u64) -> IndexRecord {
+        IndexRecord {
+            id,
+            checksum: String::from(checksum),
+            name: String::from(name),
+            volume: String::from("nas"),
+            path: String::from(path),
+            modified: Utc::now(),
+            size,
+            inode: None,
+        }
+    }
+
+    #[test]
+    fn test_review() {
+        let records = vec![
+            record(1, "backup/photos", "a.jpg", "1", 2048),
+            record(2, "backup/photos/2020", "b.jpg", "2", 1024),
+            record(3, "photos", "a.jpg", "1", 2048),
+            record(4, "photos/2020", "b.jpg", "2", 1024),
+        ];
+        let mut graph = initialise_graph();
+        graph.bulk_insert(records.iter().map(misc::to_file_record).collect()).unwrap();
+        let roots = vec![ScanRoot { volume: String::from("nas"), root: Some(String::from("/mnt/nas")), algorithm: String::from("md5") }];
+        let report = build_report(&graph, &records, roots);
+        let mut review = Review::new(&report, &graph, &records, "unused");
+
+        let screen = review.screen(80, 20);
+        assert_eq!(screen.lines.len(), 20);
+        assert!(screen.lines[2].contains("3.0 KB") && screen.lines[2].contains("nas:backup/photos/"));
+        assert_eq!(screen.highlight, Some(2));
+
+        // the copies of the group, then the entries of the second one.
+        review.handle(Key::Open);
+        review.handle(Key::Down);
+        let screen = review.screen(80, 20);
+        assert!(screen.lines.iter().any(|l| l.starts_with("Path      /mnt/nas/photos/")));
+        assert!(screen.lines.iter().any(|l| l.starts_with("Size      3.0 KB, 2 files")));
+        review.handle(Key::Open);
+        let screen = review.screen(80, 20);
+        assert!(screen.lines[0].starts_with("nas:photos/"));
+        assert!(screen.lines[2].contains("2020/  (duplicated)") && screen.lines[3].contains("a.jpg  (duplicated)"));
+        review.handle(Key::Open);
+        assert!(review.screen(80, 20).lines[2].contains("b.jpg"));
+        review.handle(Key::Back);
+        review.handle(Key::Back);
+
+        // one copy has to stay.
+        review.handle(Key::Delete);
+        review.handle(Key::Up);
+        review.handle(Key::Delete);
+        assert_eq!(review.marks().len(), 1);
+        review.handle(Key::Keep);
+        assert!(review.handle(Key::Quit));
+        assert!(!review.handle(Key::Quit));
+
+        let mut out = Vec::new();
+        assert_eq!(write_plan(&report, review.marks(), &mut out).unwrap(), 2);
+        let plan = read_plan(out.as_slice()).unwrap();
+        assert_eq!(plan, vec![(Mark::Keep, String::from("nas:backup/photos/")), (Mark::Delete, String::from("nas:photos/"))]);
+        assert!(read_plan("remove nas:photos/\n".as_bytes()).is_err());
+    }
+}
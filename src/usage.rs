@@ -0,0 +1,273 @@
+//! Disk usage of the directory tree, like `du`, telling for every directory how
+//! much of it is duplicated elsewhere and where.
+
+use std::collections::{HashMap, HashSet};
+
+extern crate petgraph;
+use petgraph::graph::NodeIndex;
+
+use crate::analyser::{GNode, GraphStorage};
+use crate::duplicates::node_totals;
+use crate::error::{Error, Result};
+use crate::index_db::IndexRecord;
+
+
+/// A directory of the tree, with its size and duplication.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectoryUsage {
+    /// Path components, the volume label first.
+    pub path: Vec<String>,
+    /// Levels below the directory the usage was asked for.
+    pub depth: usize,
+    pub size: u64,
+    pub files: usize,
+    /// Bytes of the files with a copy outside of the directory.
+    pub duplicated: u64,
+    /// Where the copies are, the directories holding the most bytes first. A
+    /// copy is counted in the directory matching this one, `backup/photos/`
+    /// for `photos/2020/a.jpg` copied to `backup/photos/2020/a.jpg`.
+    pub places: Vec<(Vec<String>, u64)>,
+}
+
+
+#[derive(Default)]
+struct Duplication {
+    bytes: u64,
+    places: HashMap<Vec<String>, u64>,
+}
+
+
+/// The paths of every copy of a file, sorted, and the copies ending with each
+/// tail of their path, so that a copy is found without going through them all.
+struct Copies<'a> {
+    paths: Vec<&'a [String]>,
+    by_tail: HashMap<&'a [String], Vec<usize>>,
+}
+
+
+impl<'a> Copies<'a> {
+    fn new(paths: Vec<&'a [String]>) -> Copies<'a> {
+        let mut by_tail = HashMap::<&[String], Vec<usize>>::new();
+        for (i, path) in paths.iter().enumerate() {
+            for start in 1..path.len() {
+                by_tail.entry(&path[start..]).or_default().push(i);
+            }
+        }
+
+        Copies { paths, by_tail }
+    }
+
+    /// Where a copy stands for the directory `directory` holding the file at
+    /// `file`: the directory at the same relative place, `backup/photos/` for
+    /// `photos/2020/a.jpg` copied to `backup/photos/2020/a.jpg`, or else the one
+    /// holding the first copy outside of it.
+    fn counterpart(&self, directory: &[String], file: &[String]) -> Option<Vec<String>> {
+        let relative = &file[directory.len()..];
+        let same_place = self.by_tail.get(relative)
+            .and_then(|ids| ids.iter().map(|&i| self.paths[i]).find(|copy| !copy.starts_with(directory)));
+        if let Some(copy) = same_place {
+            return Some(copy[..copy.len() - relative.len()].to_vec());
+        }
+
+        // the copies under the directory are next to each other, as the paths are sorted.
+        let first = self.paths.partition_point(|path| *path < directory);
+        let copy = match first {
+            0 => self.paths.get(self.paths.partition_point(|path| path.starts_with(directory) || *path < directory))?,
+            _ => &self.paths[0],
+        };
+        Some(copy[..copy.len() - 1].to_vec())
+    }
+}
+
+
+/// The usage of the directory at `root` and of the ones below it, down to
+/// `max_depth` levels, the largest first within each directory. The whole
+/// tree when `root` is empty, starting with the volumes.
+pub fn directory_usage<S: AsRef<str>>(
+    graph: &GraphStorage,
+    records: &[IndexRecord],
+    root: &[S],
+    max_depth: Option<usize>,
+) -> Result<Vec<DirectoryUsage>> {
+    let root_path: Vec<String> = root.iter().map(|c| String::from(c.as_ref())).collect();
+    let root_node = graph.lookup(&root_path)
+        .ok_or_else(|| Error::Graph(format!("{} is not in the tree", root_path.join("/"))))?;
+    if let GNode::FileLeaf {..} = graph.graph[root_node] {
+        return Err(Error::Graph(format!("{} is a file", root_path.join("/"))));
+    }
+
+    let by_location: HashMap<String, &IndexRecord> = records.iter().map(|r| (r.location(), r)).collect();
+    let totals = node_totals(graph, &by_location);
+
+    // the directories to show, and every file with its path.
+    let mut shown = Vec::<(NodeIndex, Vec<String>, usize)>::new();
+    let mut files = Vec::<(Vec<String>, &str, u64)>::new();
+    let mut pending = vec![(graph.root, Vec::<String>::new())];
+    while let Some((node, path)) = pending.pop() {
+        for child in graph.graph.neighbors(node) {
+            let mut sub_path = path.clone();
+            sub_path.push(String::from(graph.graph[child].name()));
+
+            match &graph.graph[child] {
+                GNode::FileLeaf {checksum, ..} => files.push((sub_path, checksum, totals[&child].size)),
+                GNode::DirNode {..} => {
+                    if sub_path.starts_with(&root_path) {
+                        let depth = sub_path.len() - root_path.len() - usize::from(root_path.is_empty());
+                        if max_depth.is_none_or(|max| depth <= max) {
+                            shown.push((child, sub_path.clone(), depth));
+                        }
+                    }
+                    pending.push((child, sub_path));
+                },
+            }
+        }
+    }
+    files.sort();
+
+    let mut paths = HashMap::<&str, Vec<&[String]>>::new();
+    for (path, checksum, size) in &files {
+        // empty files weigh nothing, and can be countless.
+        if *size > 0 {
+            paths.entry(checksum).or_default().push(path);
+        }
+    }
+    let copies: HashMap<&str, Copies> = paths.into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(checksum, paths)| (checksum, Copies::new(paths)))
+        .collect();
+
+    let wanted: HashSet<&[String]> = shown.iter().map(|(_, path, _)| path.as_slice()).collect();
+    let mut duplication = HashMap::<Vec<String>, Duplication>::new();
+    for (path, checksum, size) in &files {
+        let copies = match copies.get(checksum) {
+            Some(copies) => copies,
+            None => continue,
+        };
+
+        for depth in 1..path.len() {
+            let directory = &path[..depth];
+            if !wanted.contains(directory) {
+                continue;
+            }
+
+            let place = match copies.counterpart(directory, path) {
+                Some(place) => place,
+                None => continue,
+            };
+            let entry = duplication.entry(directory.to_vec()).or_default();
+            entry.bytes += size;
+            *entry.places.entry(place).or_default() += size;
+        }
+    }
+
+    let mut res = Vec::new();
+    for (node, path, depth) in shown {
+        let (duplicated, mut places) = match duplication.remove(&path) {
+            Some(found) => (found.bytes, found.places.into_iter().collect::<Vec<(Vec<String>, u64)>>()),
+            None => (0, Vec::new()),
+        };
+        places.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        res.push(DirectoryUsage {
+            path,
+            depth,
+            size: totals[&node].size,
+            files: totals[&node].files,
+            duplicated,
+            places,
+        });
+    }
+
+    Ok(order_tree(res))
+}
+
+
+/// Sorts `usage` so every directory comes right before its entries, the
+/// largest entries first.
+fn order_tree(usage: Vec<DirectoryUsage>) -> Vec<DirectoryUsage> {
+    let mut children = HashMap::<Vec<String>, Vec<DirectoryUsage>>::new();
+    let mut tops = Vec::new();
+    let paths: HashSet<Vec<String>> = usage.iter().map(|u| u.path.clone()).collect();
+    for entry in usage {
+        let parent = entry.path[..entry.path.len() - 1].to_vec();
+        if paths.contains(&parent) {
+            children.entry(parent).or_default().push(entry);
+        } else {
+            tops.push(entry);
+        }
+    }
+
+    let mut res = Vec::new();
+    tops.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    let mut pending: Vec<DirectoryUsage> = tops.into_iter().rev().collect();
+    while let Some(entry) = pending.pop() {
+        let mut entries = children.remove(&entry.path).unwrap_or_default();
+        entries.sort_by(|a, b| a.size.cmp(&b.size).then_with(|| b.path.cmp(&a.path)));
+        pending.extend(entries);
+        res.push(entry);
+    }
+
+    res
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Utc;
+
+    use crate::analyser::{initialise_graph, GraphStorageInterface};
+    use crate::misc;
+
+    fn record(path: &str, name: &str, checksum: &str, size: u64) -> IndexRecord {
+        IndexRecord {
+            id: 0,
+            checksum: String::from(checksum),
+            name: String::from(name),
+            volume: String::from("nas"),
+            path: String::from(path),
+            modified: Utc::now(),
+            size,
+            inode: None,
+        }
+    }
+
+    #[test]
+    fn test_directory_usage() {
+        let records = vec![
+            record("backup/old-photos/2019", "a.jpg", "1", 100),
+            record("backup/old-photos/2019", "b.jpg", "2", 50),
+            record("docs", "a.jpg", "1", 100),
+            record("photos", "c.jpg", "3", 400),
+            record("photos/2019", "a.jpg", "1", 100),
+            record("photos/2019", "b.jpg", "2", 50),
+            record("photos/2020", "d.jpg", "4", 300),
+            record("photos/2020", "empty", "5", 0),
+            record("photos/2020", "empty copy", "5", 0),
+        ];
+        let mut graph = initialise_graph();
+        graph.bulk_insert(records.iter().map(misc::to_file_record).collect()).unwrap();
+
+        let usage = directory_usage(&graph, &records, &["nas"], Some(1)).unwrap();
+        let summary: Vec<(String, usize, u64, u64)> = usage.iter()
+            .map(|u| (u.path.join("/"), u.depth, u.size, u.duplicated))
+            .collect();
+        assert_eq!(summary, vec![
+            (String::from("nas"), 0, 1100, 0),
+            (String::from("nas/photos"), 1, 850, 150),
+            (String::from("nas/backup"), 1, 150, 150),
+            (String::from("nas/docs"), 1, 100, 100),
+        ]);
+
+        let photos = &usage[1];
+        let place = |path: &str| path.split('/').map(String::from).collect::<Vec<String>>();
+        assert_eq!(photos.places, vec![(place("nas/backup/old-photos"), 150)]);
+        // the photos' copy comes first, the one in docs not matching its layout.
+        assert_eq!(usage[3].places, vec![(place("nas/backup/old-photos/2019"), 100)]);
+
+        let whole = directory_usage(&graph, &records, &Vec::<String>::new(), Some(0)).unwrap();
+        assert_eq!(whole.iter().map(|u| u.path.join("/")).collect::<Vec<String>>(), vec!["nas"]);
+        assert!(directory_usage(&graph, &records, &["nas", "docs", "a.jpg"], None).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+//! Integrity checks of indexed files against their stored checksums.
+
+use std::fs::metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+
+extern crate chrono;
+use chrono::{DateTime, Utc};
+
+extern crate num_cpus;
+
+extern crate threadpool;
+use threadpool::ThreadPool;
+
+use crate::error::{Error, Result};
+use crate::file_handler::{hash_file_with, HashAlgorithm};
+use crate::index_db::{IndexRecord, IndexStorage};
+
+
+/// What is wrong with an indexed file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    /// The contents changed while the modification time did not, a strong
+    /// sign of silent corruption.
+    Corrupted,
+    /// The contents changed along with the modification time, the index is
+    /// only out of date.
+    Changed,
+    Missing,
+    Unreadable(String),
+}
+
+
+impl Finding {
+    /// Whether the finding points at damage rather than an outdated index.
+    pub fn is_damage(&self) -> bool {
+        !matches!(self, Finding::Changed)
+    }
+}
+
+
+#[derive(Debug)]
+pub struct VerifyOptions {
+    /// Share of the files of every volume checked by this run. Each run picks
+    /// the files verified the longest ago, so a full pass takes `100 / percent` runs.
+    pub percent: u8,
+    /// Only check this volume.
+    pub volume: Option<String>,
+}
+
+
+impl Default for VerifyOptions {
+    fn default() -> VerifyOptions {
+        VerifyOptions { percent: 100, volume: None }
+    }
+}
+
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub issues: Vec<(IndexRecord, Finding)>,
+    /// Volumes left alone because they are not mounted.
+    pub offline: Vec<String>,
+}
+
+
+impl VerifyReport {
+    /// Number of files found damaged, missing or unreadable.
+    pub fn damaged(&self) -> usize {
+        self.issues.iter().filter(|(_, finding)| finding.is_damage()).count()
+    }
+}
+
+
+/// Rehashes the file `record` stands for, on the volume mounted at `root`.
+pub fn check_record(record: &IndexRecord, root: &Path, algorithm: HashAlgorithm) -> Option<Finding> {
+    let file: PathBuf = [root, Path::new(&record.path), Path::new(&record.name)].iter().collect();
+
+    let checksum = match hash_file_with(&file, algorithm) {
+        Ok(checksum) => checksum,
+        Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => return Some(Finding::Missing),
+        Err(e) => return Some(Finding::Unreadable(e.to_string())),
+    };
+    if checksum == record.checksum {
+        return None;
+    }
+
+    // the index keeps modification times to the millisecond.
+    let modified = metadata(&file).and_then(|m| m.modified()).map(DateTime::<Utc>::from);
+    match modified {
+        Ok(time) if time.timestamp_millis() == record.modified.timestamp_millis() => Some(Finding::Corrupted),
+        Ok(_) => Some(Finding::Changed),
+        Err(e) => Some(Finding::Unreadable(e.to_string())),
+    }
+}
+
+
+/// Checks a share of the indexed files of every online volume, see [`VerifyOptions`].
+pub fn verify<T: IndexStorage>(storage: &T, options: &VerifyOptions) -> Result<VerifyReport> {
+    if options.percent == 0 || options.percent > 100 {
+        return Err(Error::Parse(format!("cannot verify {}% of the files", options.percent)));
+    }
+
+    let mut report = VerifyReport::default();
+    let pool = ThreadPool::new(num_cpus::get());
+
+    for volume in storage.volumes()? {
+        if options.volume.as_ref().is_some_and(|label| *label != volume.label) {
+            continue;
+        }
+        if volume.is_virtual() {
+            // imported from a manifest, there is nothing on disk to check.
+            continue;
+        }
+        if !volume.is_online() {
+            report.offline.push(volume.label);
+            continue;
+        }
+
+        let total = storage.count_records(&volume.label)?;
+        let limit = (total * options.percent as usize).div_ceil(100);
+        let records = storage.least_recently_verified(&volume.label, limit)?;
+
+        let (tx, rx) = channel();
+        for record in records {
+            let tx = tx.clone();
+            let root = PathBuf::from(&volume.root);
+            let algorithm = volume.algorithm;
+            pool.execute(move || {
+                let finding = check_record(&record, &root, algorithm);
+                let _ = tx.send((record, finding));
+            });
+        }
+        drop(tx);
+
+        let mut verified = Vec::new();
+        for (record, finding) in rx.iter() {
+            verified.push(record.id);
+            if let Some(finding) = finding {
+                report.issues.push((record, finding));
+            }
+        }
+
+        storage.mark_verified(&verified)?;
+        report.checked += verified.len();
+    }
+
+    report.issues.sort_by_key(|(record, _)| record.location());
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    use crate::file_handler::{scan, ScanOptions, ScanSource};
+    use crate::index_db;
+
+    #[test]
+    fn test_verify() {
+        let dir = env::temp_dir().join(format!("rusty-index-verify-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["rotten", "edited", "gone", "fine"] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        let options = ScanOptions { volume: Some(String::from("disk")), ..ScanOptions::default() };
+        scan(ScanSource::Directory(String::from(dir.to_str().unwrap())), &storage, options, |_| {}).unwrap();
+
+        // same size and modification time, different contents.
+        let rotten = fs::File::options().write(true).open(dir.join("rotten")).unwrap();
+        let modified = rotten.metadata().unwrap().modified().unwrap();
+        io::Write::write_all(&mut &rotten, b"ROTTEN").unwrap();
+        rotten.set_modified(modified).unwrap();
+        drop(rotten);
+
+        fs::write(dir.join("edited"), "edited again").unwrap();
+        let edited = fs::File::options().write(true).open(dir.join("edited")).unwrap();
+        edited.set_modified(modified + std::time::Duration::from_secs(60)).unwrap();
+        fs::remove_file(dir.join("gone")).unwrap();
+
+        // half of the files per run, then the other half.
+        let half = VerifyOptions { percent: 50, volume: None };
+        let first = verify(&storage, &half).unwrap();
+        let second = verify(&storage, &half).unwrap();
+        assert_eq!((first.checked, second.checked), (2, 2));
+
+        let mut issues: Vec<(String, Finding)> = first.issues.into_iter().chain(second.issues)
+            .map(|(record, finding)| (record.name, finding))
+            .collect();
+        issues.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(issues, vec![
+            (String::from("edited"), Finding::Changed),
+            (String::from("gone"), Finding::Missing),
+            (String::from("rotten"), Finding::Corrupted),
+        ]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
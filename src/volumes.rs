@@ -0,0 +1,169 @@
+//! Finding the volumes of the index a command is about, and the copies held
+//! across all of them.
+
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::file_handler::{self, HashAlgorithm};
+use crate::index_db::{self, IndexRecord, IndexStorage, Volume};
+use crate::misc;
+use crate::misc::path_to_str;
+
+
+/// The known volume that should be at `path` but is not mounted, so that scanning
+/// an empty mount point doesn't drop the records of the drive. Volumes are looked
+/// up by `label`, or by their root when there is none.
+pub fn offline_volume<T: IndexStorage>(storage: &T, path: &str, label: Option<&str>, current_dir: &str) -> Result<Option<Volume>> {
+    let root = misc::absolute_path(path, current_dir);
+    let root = match root.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    };
+
+    match storage.volume(label.unwrap_or(root))? {
+        Some(volume) if !index_db::is_mounted(root) => Ok(Some(volume)),
+        _ => Ok(None),
+    }
+}
+
+
+/// Refuses to index the directory `root` as the volume `label` when another
+/// volume already indexes files under it, or is the same drive: every file
+/// would be in the index twice, and reported as a duplicate of itself.
+pub fn check_overlap<T: IndexStorage>(storage: &T, label: &str, uuid: Option<&str>, root: &Path) -> Result<()> {
+    for volume in storage.volumes()? {
+        if volume.label == label || volume.is_virtual() {
+            continue;
+        }
+
+        if uuid.is_some() && volume.uuid.as_deref() == uuid {
+            return Err(Error::Parse(format!(
+                "{} is on the drive of volume '{}', already indexed from {}", root.display(), volume.label, volume.root,
+            )));
+        }
+
+        let known = Path::new(&volume.root);
+        let inside = match root.strip_prefix(known) {
+            Ok(relative) => storage.count_records_under(&volume.label, path_to_str(relative)?)? > 0,
+            Err(_) => false,
+        };
+        if inside {
+            return Err(Error::Parse(format!(
+                "{} is inside volume '{}', which already indexes it from {}; scan {} instead",
+                root.display(), volume.label, volume.root, volume.root,
+            )));
+        }
+        if known.starts_with(root) && storage.count_records(&volume.label)? > 0 {
+            return Err(Error::Parse(format!(
+                "{} holds volume '{}', already indexed from {}", root.display(), volume.label, volume.root,
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+
+/// The volume labelled `label`, or the only volume of the index when no label
+/// is given.
+pub fn pick_volume<T: IndexStorage>(storage: &T, label: Option<&str>) -> Result<Volume> {
+    if let Some(label) = label {
+        return storage.volume(label)?
+            .ok_or_else(|| Error::Parse(format!("no volume labelled '{}'", label)));
+    }
+
+    let mut volumes = storage.volumes()?;
+    if volumes.len() != 1 {
+        let labels: Vec<String> = volumes.into_iter().map(|v| v.label).collect();
+        return Err(Error::Parse(format!("pick a volume among {}", labels.join(", "))));
+    }
+
+    Ok(volumes.remove(0))
+}
+
+
+/// Every indexed copy of `file`, hashing it once with each hash function used
+/// by the volumes.
+pub fn copies_of<T: IndexStorage, P: AsRef<Path>>(storage: &T, file: &P) -> Result<Vec<IndexRecord>> {
+    let mut algorithms: Vec<HashAlgorithm> = storage.volumes()?.iter().map(|v| v.algorithm).collect();
+    algorithms.sort_by_key(|a| a.as_str());
+    algorithms.dedup();
+
+    let mut copies = Vec::new();
+    for algorithm in algorithms {
+        copies.extend(storage.copies(&file_handler::hash_file_with(file, algorithm)?)?);
+    }
+
+    Ok(copies)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Utc;
+
+    fn record(volume: &str, checksum: &str) -> IndexRecord {
+        IndexRecord {
+            id: 0,
+            checksum: String::from(checksum),
+            name: String::from("fb"),
+            volume: String::from(volume),
+            path: String::new(),
+            modified: Utc::now(),
+            size: 0,
+            inode: None,
+        }
+    }
+
+    #[test]
+    fn test_volumes() {
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        let current_dir = file_handler::get_current_dir().unwrap();
+
+        assert!(pick_volume(&storage, None).is_err());
+        storage.register_volume("nas", None, "/no/such/mount", None).unwrap();
+        assert_eq!(pick_volume(&storage, None).unwrap().label, "nas");
+        storage.register_volume("usb", None, "/no/such/usb", Some(HashAlgorithm::Sha256)).unwrap();
+        assert!(pick_volume(&storage, None).is_err());
+        assert_eq!(pick_volume(&storage, Some("usb")).unwrap().label, "usb");
+        assert!(pick_volume(&storage, Some("other")).is_err());
+
+        let offline = offline_volume(&storage, "/no/such/mount/", Some("nas"), &current_dir).unwrap();
+        assert_eq!(offline.map(|v| v.label), Some(String::from("nas")));
+        assert!(offline_volume(&storage, "test_data", Some("nas"), &current_dir).unwrap().is_none());
+        assert!(offline_volume(&storage, "/no/such/mount", None, &current_dir).unwrap().is_none());
+
+        let md5 = file_handler::hash_file_with(&"test_data/fb", HashAlgorithm::Md5).unwrap();
+        let sha256 = file_handler::hash_file_with(&"test_data/fb", HashAlgorithm::Sha256).unwrap();
+        let mut other = record("usb", "other");
+        other.name = String::from("other");
+        storage.insert(&[record("nas", &md5), record("usb", &sha256), other]).unwrap();
+
+        let mut volumes: Vec<String> = copies_of(&storage, &"test_data/fb").unwrap().into_iter().map(|r| r.volume).collect();
+        volumes.sort();
+        assert_eq!(volumes, vec!["nas", "usb"]);
+    }
+
+    #[test]
+    fn test_check_overlap() {
+        let storage = index_db::initalise_db(":memory:").unwrap();
+        storage.create().unwrap();
+        storage.register_volume("nas", Some("1234"), "/mnt/nas", None).unwrap();
+        let mut photo = record("nas", "1");
+        photo.path = String::from("photos/2020");
+        storage.insert(&[photo]).unwrap();
+
+        assert!(check_overlap(&storage, "nas", None, Path::new("/mnt/nas/photos")).is_ok());
+        assert!(check_overlap(&storage, "usb", None, Path::new("/mnt/nas/photos")).is_err());
+        assert!(check_overlap(&storage, "usb", None, Path::new("/mnt/nas")).is_err());
+        assert!(check_overlap(&storage, "usb", None, Path::new("/mnt")).is_err());
+        assert!(check_overlap(&storage, "usb", Some("1234"), Path::new("/media/usb")).is_err());
+        // nothing of the volume is indexed there, nor is it a prefix of its names.
+        assert!(check_overlap(&storage, "usb", None, Path::new("/mnt/nas/docs")).is_ok());
+        assert!(check_overlap(&storage, "usb", None, Path::new("/mnt/nas/photo")).is_ok());
+        assert!(check_overlap(&storage, "usb", Some("5678"), Path::new("/media/usb")).is_ok());
+    }
+}
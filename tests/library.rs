@@ -0,0 +1,52 @@
+extern crate rusty_index;
+
+use rusty_index::{analyser, index_db, misc};
+use rusty_index::{stream_scan_directory, Error, FileRecord, GraphStorageInterface, IndexStorage, ProgressSender};
+
+
+fn scan_test_data() -> index_db::SQLite3 {
+    let storage = index_db::initalise_db(":memory:").unwrap();
+    storage.create().unwrap();
+    stream_scan_directory(String::from("test_data"), &storage, false, ProgressSender::none()).unwrap();
+
+    storage
+}
+
+
+#[test]
+fn test_scan_and_find_duplicates() {
+    let storage = scan_test_data();
+
+    let records: Vec<FileRecord> = storage.fetch_sorted().unwrap().iter().map(misc::to_file_record).collect();
+    assert_eq!(records.len(), 6);
+
+    let mut graph = analyser::initialise_graph();
+    graph.bulk_insert(records).unwrap();
+    let res = graph.find_duplicates();
+
+    // b/ and c/ hold the same files, so only one of them is looked into.
+    assert_eq!(res.len(), 3);
+    let dirs: Vec<&Vec<String>> = res.values().filter(|paths| paths[0].ends_with('/')).collect();
+    assert_eq!(dirs.len(), 1);
+    assert_eq!(dirs[0].len(), 2);
+    assert!(res.values().all(|paths| paths.len() == 2));
+}
+
+
+#[test]
+fn test_hash_file() {
+    let res = rusty_index::hash_file(&"test_data/fb").unwrap();
+    assert_eq!(res, "3c83a94f3920b82039874e370fca3e5a");
+}
+
+
+#[test]
+fn test_bad_rows_are_errors() {
+    let storage = scan_test_data();
+    storage.conn.execute("UPDATE index_records SET modified = 'yesterday'", rusqlite::NO_PARAMS).unwrap();
+
+    match storage.fetch_sorted() {
+        Err(e @ Error::Parse(_)) => assert_eq!(e.exit_code(), 5),
+        other => panic!("expected a parse error, got {:?}", other),
+    }
+}